@@ -0,0 +1,93 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use color_eyre::eyre::{bail, Context, Result};
+use llm::{InferenceError, InferenceParameters, InferenceSession, Model};
+use serde::Deserialize;
+
+use crate::cli_args::ScoreNormalization;
+
+/// A multiple-choice item, as found in HellaSwag, ARC or PIQA-style datasets.
+#[derive(Deserialize, Debug)]
+pub struct Item {
+    pub context: String,
+    pub choices: Vec<String>,
+    /// The index of the correct choice.
+    pub answer: usize,
+}
+
+/// Reads the items of a JSONL dataset, skipping blank lines.
+pub fn read_items(path: &Path) -> Result<Vec<Item>> {
+    let file = File::open(path).wrap_err_with(|| format!("Failed to open {path:?}"))?;
+
+    let mut items = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.wrap_err_with(|| format!("Failed to read {path:?}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let item: Item = serde_json::from_str(&line)
+            .wrap_err_with(|| format!("Invalid item on line {} of {path:?}", index + 1))?;
+        if item.answer >= item.choices.len() {
+            bail!(
+                "The answer on line {} of {path:?} is not one of its {} choices",
+                index + 1,
+                item.choices.len()
+            );
+        }
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// The half-width of the 95% Wilson score interval of an accuracy of `correct`
+/// out of `evaluated` items.
+pub fn confidence_margin(correct: usize, evaluated: usize) -> f64 {
+    const Z: f64 = 1.96;
+
+    let n = evaluated as f64;
+    let p = correct as f64 / n;
+    Z / (1.0 + Z * Z / n) * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt()
+}
+
+/// Scores each of the choices of `item`, and returns the index of the most
+/// likely one. The context is only evaluated once, and shared between the choices.
+pub fn predict(
+    model: &dyn Model,
+    params: &InferenceParameters,
+    session: &mut InferenceSession,
+    normalization: ScoreNormalization,
+    item: &Item,
+) -> Result<usize, InferenceError> {
+    session.reset();
+
+    let mut best = (0, f64::NEG_INFINITY);
+    for (index, choice) in item.choices.iter().enumerate() {
+        // The context only needs to be fed for the first choice; the session
+        // is rewound to the end of it after each choice is scored.
+        let context = if index == 0 {
+            item.context.as_str()
+        } else {
+            ""
+        };
+        let score = session.score(model, params, context, choice)?;
+
+        let length = match normalization {
+            ScoreNormalization::None => 1,
+            ScoreNormalization::Tokens => score.token_log_probabilities.len(),
+            ScoreNormalization::Bytes => choice.len(),
+        };
+        let normalized = score.log_probability / length.max(1) as f64;
+
+        if normalized > best.1 {
+            best = (index, normalized);
+        }
+    }
+
+    Ok(best.0)
+}
@@ -3,8 +3,8 @@ use std::{fmt::Debug, path::PathBuf};
 use clap::{Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{Result, WrapErr};
 use llm::{
-    ElementType, InferenceParameters, InferenceSessionConfig, InvalidTokenBias, LoadProgress,
-    Model, ModelKVMemoryType, ModelParameters, TokenBias,
+    ChatTemplate, ElementType, InferenceParameters, InferenceSessionConfig, InvalidTokenBias,
+    LoadProgress, Model, ModelKVMemoryType, ModelParameters, TokenBias, Vocabulary,
 };
 use rand::SeedableRng;
 
@@ -38,6 +38,9 @@ pub enum Args {
         #[command(subcommand)]
         args: BaseArgs,
     },
+    /// Use a model of any supported architecture, detected from the model file,
+    /// to infer the next tokens in a sequence, and exit.
+    Infer(Box<Infer>),
 }
 
 #[derive(Subcommand, Debug)]
@@ -46,6 +49,18 @@ pub enum BaseArgs {
     /// Use a model to infer the next tokens in a sequence, and exit.
     Infer(Box<Infer>),
 
+    #[command()]
+    /// Measure a model's perplexity for a given prompt.
+    Perplexity(Box<Perplexity>),
+
+    #[command()]
+    /// Compute an embedding of a prompt, and write it to the console or a file.
+    Embed(Box<Embed>),
+
+    #[command()]
+    /// Evaluate a model's accuracy on a multiple-choice dataset.
+    BenchEval(Box<BenchEval>),
+
     #[command()]
     /// Get information about a GGML model.
     Info(Box<Info>),
@@ -63,10 +78,12 @@ pub enum BaseArgs {
     #[command()]
     /// Use a model to interactively generate tokens, and chat with it.
     ///
-    /// Note that most, if not all, existing models are not trained for this
-    /// and do not support a long enough context window to be able to
-    /// have an extended conversation.
-    Chat(Box<Repl>),
+    /// The conversation is written with a chat template, which should match the
+    /// format the model was fine-tuned on. Base models are not trained for this,
+    /// and may not support a long enough context window to be able to have an
+    /// extended conversation. The prompt file, if given, is used as the system
+    /// message.
+    Chat(Box<Chat>),
 
     /// Quantize a GGML model to 4-bit.
     Quantize(Box<Quantize>),
@@ -106,6 +123,145 @@ pub struct Infer {
     pub persist_session: Option<PathBuf>,
 }
 
+#[derive(Parser, Debug)]
+pub struct Perplexity {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub prompt_file: PromptFile,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    /// The text to measure the perplexity of. The text is split into windows
+    /// the size of the context, which are evaluated independently.
+    ///
+    /// If used with `--prompt-file`/`-f`, the prompt from the file will be used
+    /// and `{{PROMPT}}` will be replaced with the value of `--prompt`/`-p`.
+    #[arg(long, short = 'p', default_value = None)]
+    pub prompt: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Embed {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub prompt_file: PromptFile,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    /// The text to embed.
+    ///
+    /// If used with `--prompt-file`/`-f`, the prompt from the file will be used
+    /// and `{{PROMPT}}` will be replaced with the value of `--prompt`/`-p`.
+    #[arg(long, short = 'p', default_value = None)]
+    pub prompt: Option<String>,
+
+    /// How the hidden states of each token are combined into one embedding.
+    #[arg(long, value_enum, default_value_t = Pooling::Mean)]
+    pub pooling: Pooling,
+
+    /// Scale the embedding to unit length.
+    #[arg(long, default_value_t = false)]
+    pub normalize: bool,
+
+    /// The format to write the embedding in.
+    #[arg(long, value_enum, default_value_t = EmbeddingFormat::Json)]
+    pub format: EmbeddingFormat,
+
+    /// Writes the embedding to the given path instead of the console.
+    #[arg(long, short = 'o')]
+    pub output: Option<PathBuf>,
+}
+impl Embed {
+    pub fn embedding_parameters(&self) -> llm::EmbeddingParameters {
+        llm::EmbeddingParameters {
+            pooling: self.pooling.into(),
+            normalize: self.normalize,
+        }
+    }
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+#[clap(rename_all = "snake_case")]
+pub enum Pooling {
+    /// The mean of the hidden states of all tokens.
+    Mean,
+    /// The hidden state of the last token.
+    LastToken,
+    /// The hidden state of the first token.
+    Cls,
+}
+impl From<Pooling> for llm::Pooling {
+    fn from(p: Pooling) -> Self {
+        match p {
+            Pooling::Mean => llm::Pooling::Mean,
+            Pooling::LastToken => llm::Pooling::LastToken,
+            Pooling::Cls => llm::Pooling::Cls,
+        }
+    }
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum EmbeddingFormat {
+    /// A JSON array of numbers.
+    Json,
+    /// Little-endian 32-bit floats.
+    Binary,
+}
+
+#[derive(Parser, Debug)]
+pub struct BenchEval {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    /// Path to a JSONL file of items to evaluate. Each line should be an object of
+    /// the form `{"context": "...", "choices": ["...", ...], "answer": 0}`, where
+    /// `answer` is the index of the correct choice.
+    ///
+    /// Choices are scored as a continuation of the context, so they should
+    /// include any leading whitespace.
+    #[arg(long, short = 'd')]
+    pub dataset: PathBuf,
+
+    /// Stop after evaluating this many items.
+    #[arg(long)]
+    pub limit: Option<usize>,
+
+    /// Stop early once the accuracy is known well enough: when the 95% confidence
+    /// interval of the accuracy so far is narrower than this many percentage points
+    /// on either side. At least `--min-items` items are evaluated first.
+    #[arg(long)]
+    pub confidence_margin: Option<f64>,
+
+    /// The number of items to evaluate before stopping early with
+    /// `--confidence-margin`.
+    #[arg(long, default_value_t = 100)]
+    pub min_items: usize,
+
+    /// How the log-likelihood of each choice is normalized before the choices
+    /// are compared.
+    #[arg(long, value_enum, default_value_t = ScoreNormalization::Tokens)]
+    pub normalization: ScoreNormalization,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum ScoreNormalization {
+    /// Compare the total log-likelihood of each choice.
+    None,
+    /// Divide the log-likelihood of each choice by its number of tokens.
+    Tokens,
+    /// Divide the log-likelihood of each choice by its length in bytes.
+    Bytes,
+}
+
 #[derive(Parser, Debug)]
 pub struct Info {
     /// The model to inspect
@@ -115,6 +271,44 @@ pub struct Info {
     /// Whether or not to dump the entire vocabulary
     #[arg(long, short = 'v')]
     pub dump_vocabulary: bool,
+
+    /// The context size (in tokens) to use when estimating memory requirements.
+    #[arg(long, default_value_t = 2048)]
+    pub num_ctx_tokens: usize,
+
+    /// Estimate memory requirements for loading the model without mmap.
+    #[arg(long)]
+    pub no_mmap: bool,
+
+    /// Estimate memory requirements for sessions that use 16-bit floats for
+    /// model memory key and value.
+    #[arg(long, default_value_t = false)]
+    pub float16: bool,
+
+    /// The number of sessions to estimate memory requirements for.
+    #[arg(long, default_value_t = 1)]
+    pub num_sessions: usize,
+}
+impl Info {
+    pub fn model_parameters(&self) -> ModelParameters {
+        ModelParameters {
+            prefer_mmap: !self.no_mmap,
+            n_context_tokens: self.num_ctx_tokens,
+            ..Default::default()
+        }
+    }
+
+    pub fn inference_session_config(&self) -> InferenceSessionConfig {
+        let mem_typ = if self.float16 {
+            ModelKVMemoryType::Float16
+        } else {
+            ModelKVMemoryType::Float32
+        };
+        InferenceSessionConfig {
+            memory_k_type: mem_typ,
+            memory_v_type: mem_typ,
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -145,6 +339,57 @@ pub struct Repl {
     pub generate: Generate,
 }
 
+#[derive(Parser, Debug)]
+pub struct Chat {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    // The prompt file is used as the system message.
+    #[command(flatten)]
+    pub prompt_file: PromptFile,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    /// The chat template to write the conversation with: one of `alpaca`,
+    /// `vicuna`, `chatml` or `llama2`, or the path to a JSON template.
+    #[arg(long, default_value = "alpaca")]
+    pub template: String,
+
+    /// How to keep the conversation inside the context window once it grows
+    /// too long for it.
+    #[arg(long, value_enum, default_value_t = ContextStrategy::DropOldest)]
+    pub context_strategy: ContextStrategy,
+}
+impl Chat {
+    pub fn template(&self) -> Result<ChatTemplate> {
+        match ChatTemplate::builtin(&self.template) {
+            Some(template) => Ok(template),
+            None => ChatTemplate::load(self.template.as_ref())
+                .wrap_err_with(|| format!("Failed to load chat template {:?}", self.template)),
+        }
+    }
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum ContextStrategy {
+    /// Stop the conversation.
+    None,
+    /// Drop the oldest turns, keeping the system message.
+    DropOldest,
+    /// Have the model summarize the oldest turns, and keep the summary.
+    Summarize,
+}
+impl From<ContextStrategy> for llm::ContextStrategy {
+    fn from(s: ContextStrategy) -> Self {
+        match s {
+            ContextStrategy::None => llm::ContextStrategy::None,
+            ContextStrategy::DropOldest => llm::ContextStrategy::DropOldest,
+            ContextStrategy::Summarize => llm::ContextStrategy::Summarize,
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct Generate {
     /// Sets the number of threads to use
@@ -214,6 +459,19 @@ pub struct Generate {
     /// option will override this if specified.
     #[arg(long, default_value_t = false)]
     pub ignore_eos: bool,
+
+    /// Recognise the text of special tokens in the prompt, such as `</s>` or
+    /// `<|endoftext|>`, as those tokens rather than as text.
+    #[arg(long, default_value_t = false)]
+    pub parse_special_tokens: bool,
+
+    /// Heal the given number of tokens at the end of the prompt: remove them,
+    /// and constrain the first generated tokens to start with their text. This
+    /// helps with prompts that end partway through a word or with a space.
+    ///
+    /// Only supported by `infer`.
+    #[arg(long, default_value_t = 0)]
+    pub token_healing: usize,
 }
 impl Generate {
     #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
@@ -273,6 +531,7 @@ impl Generate {
                 }
             }),
             repetition_penalty_last_n: self.repeat_last_n,
+            parse_special_tokens: self.parse_special_tokens,
         }
     }
 }
@@ -282,7 +541,8 @@ fn parse_bias(s: &str) -> Result<TokenBias, InvalidTokenBias> {
 
 #[derive(Parser, Debug)]
 pub struct ModelLoad {
-    /// Where to load the model from
+    /// Where to load the model from: a GGML file, or the directory of a Hugging
+    /// Face checkpoint with safetensors weights
     #[arg(long, short = 'm')]
     pub model_path: PathBuf,
 
@@ -303,14 +563,39 @@ pub struct ModelLoad {
     /// Don't use mmap to load the model.
     #[arg(long)]
     pub no_mmap: bool,
+
+    /// A `merges.txt` file to use with models that are tokenized with byte-level
+    /// BPE (GPT-2, GPT-J and GPT-NeoX). GGML files do not include the merges, so
+    /// tokenization will only approximate the original tokenizer without it.
+    #[arg(long)]
+    pub bpe_merges: Option<PathBuf>,
+
+    /// A Hugging Face `tokenizer.json` to use instead of the vocabulary embedded
+    /// in the model.
+    #[arg(long)]
+    pub tokenizer: Option<PathBuf>,
 }
 impl ModelLoad {
-    pub fn load<M: llm::KnownModel + 'static>(&self) -> Result<Box<dyn Model>> {
-        let params = ModelParameters {
+    fn model_parameters(&self) -> ModelParameters {
+        ModelParameters {
             prefer_mmap: !self.no_mmap,
             n_context_tokens: self.num_ctx_tokens,
+            bpe_merges_path: self.bpe_merges.clone(),
+            tokenizer_path: self.tokenizer.clone(),
             ..Default::default()
-        };
+        }
+    }
+
+    /// Loads only the vocabulary of the model, which is much faster than loading
+    /// the whole model.
+    pub fn load_vocabulary<M: llm::KnownModel + 'static>(&self) -> Result<Vocabulary> {
+        let (_, vocabulary) = llm::load_vocabulary::<M>(&self.model_path, &self.model_parameters())
+            .wrap_err("Could not load vocabulary")?;
+        Ok(vocabulary)
+    }
+
+    pub fn load<M: llm::KnownModel + 'static>(&self) -> Result<Box<dyn Model>> {
+        let params = self.model_parameters();
 
         let mut sp = Some(spinoff::Spinner::new(
             spinoff::spinners::Dots2,
@@ -320,7 +605,7 @@ impl ModelLoad {
         let now = std::time::Instant::now();
         let mut prev_load_time = now;
 
-        let model = llm::load::<M>(&self.model_path, params, move |progress| match progress {
+        let load_progress_callback = move |progress| match progress {
             LoadProgress::HyperparametersLoaded => {
                 if let Some(sp) = sp.as_mut() {
                     sp.update_text("Loaded hyperparameters")
@@ -361,7 +646,13 @@ impl ModelLoad {
                     ));
                 };
             }
-        })
+        };
+
+        let model = if self.model_path.is_dir() {
+            llm::load_hugging_face::<M>(&self.model_path, params, load_progress_callback)
+        } else {
+            llm::load::<M>(&self.model_path, params, load_progress_callback)
+        }
         .wrap_err("Could not load model")?;
 
         Ok(Box::new(model))
@@ -447,6 +738,26 @@ pub struct Quantize {
 
     /// The format to convert to
     pub target: QuantizationTarget,
+
+    /// The container format to save the quantized model in
+    #[arg(long, value_enum, default_value_t = SaveContainerType::Ggjt)]
+    pub container_type: SaveContainerType,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum SaveContainerType {
+    /// GGJT, which stores the hyperparameters in a fixed order.
+    Ggjt,
+    /// GGKV, which stores the hyperparameters and other details as key/value metadata.
+    Ggkv,
+}
+impl From<SaveContainerType> for llm::ContainerType {
+    fn from(value: SaveContainerType) -> Self {
+        match value {
+            SaveContainerType::Ggjt => llm::ContainerType::Ggjt(llm::GGJT_VERSION),
+            SaveContainerType::Ggkv => llm::ContainerType::Ggkv(llm::GGKV_VERSION),
+        }
+    }
 }
 
 #[derive(Parser, Debug, ValueEnum, Clone, Copy)]
@@ -6,13 +6,14 @@ use std::{
 
 use clap::Parser;
 use cli_args::{Args, BaseArgs};
-use color_eyre::eyre::{Context, Result};
-use llm::InferenceError;
+use color_eyre::eyre::{bail, Context, Result};
+use llm::{Conversation, FinishReason, InferenceError, InferenceStats, Role};
 use rustyline::error::ReadlineError;
 use rustyline::validate::{ValidationContext, ValidationResult, Validator};
 use rustyline::{history::DefaultHistory, Cmd, Event, EventHandler, KeyCode, KeyEvent, Modifiers};
 use rustyline::{Completer, Helper, Highlighter, Hinter};
 
+mod bench_eval;
 mod cli_args;
 mod snapshot;
 
@@ -30,16 +31,36 @@ fn main() -> Result<()> {
         Args::Gpt2 { args } => handle_args::<llm::models::Gpt2>(args),
         Args::GptJ { args } => handle_args::<llm::models::GptJ>(args),
         Args::NeoX { args } => handle_args::<llm::models::NeoX>(args),
+        Args::Infer(args) => infer_auto(args),
+    }
+}
+
+fn infer_auto(args: &cli_args::Infer) -> Result<()> {
+    use llm::ModelArchitecture::*;
+
+    let architecture = llm::detect_architecture(&args.model_load.model_path)
+        .wrap_err("Could not detect the model architecture")?;
+    log::info!("Detected a {architecture} model");
+
+    match architecture {
+        Bloom => infer::<llm::models::Bloom>(args),
+        Gpt2 => infer::<llm::models::Gpt2>(args),
+        GptJ => infer::<llm::models::GptJ>(args),
+        Llama => infer::<llm::models::Llama>(args),
+        NeoX => infer::<llm::models::NeoX>(args),
     }
 }
 
 fn handle_args<M: llm::KnownModel + 'static>(args: &cli_args::BaseArgs) -> Result<()> {
     match args {
         BaseArgs::Infer(args) => infer::<M>(args),
+        BaseArgs::Perplexity(args) => perplexity::<M>(args),
+        BaseArgs::Embed(args) => embed::<M>(args),
+        BaseArgs::BenchEval(args) => bench_eval::<M>(args),
         BaseArgs::Info(args) => info::<M>(args),
         BaseArgs::PromptTokens(args) => prompt_tokens::<M>(args),
-        BaseArgs::Repl(args) => interactive::<M>(args, false),
-        BaseArgs::Chat(args) => interactive::<M>(args, true),
+        BaseArgs::Repl(args) => repl::<M>(args),
+        BaseArgs::Chat(args) => chat::<M>(args),
         BaseArgs::Quantize(args) => quantize::<M>(args),
     }
 }
@@ -65,6 +86,8 @@ fn infer<M: llm::KnownModel + 'static>(args: &cli_args::Infer) -> Result<()> {
             parameters: Some(&inference_params),
             play_back_previous_tokens: session_loaded,
             maximum_token_count: args.generate.num_predict,
+            token_healing: args.generate.token_healing,
+            ..Default::default()
         },
         // OutputRequest
         &mut Default::default(),
@@ -78,14 +101,22 @@ fn infer<M: llm::KnownModel + 'static>(args: &cli_args::Infer) -> Result<()> {
     println!();
 
     match res {
-        Ok(_) => (),
+        Ok(stats) => {
+            if stats.finish_reason == FinishReason::ContextFull {
+                log::warn!("Context window full, stopping inference.")
+            }
+            log::debug!("Inference stats:\n{stats}");
+        }
         Err(InferenceError::ContextFull) => {
             log::warn!("Context window full, stopping inference.")
         }
         Err(InferenceError::TokenizationFailed) => {
             log::error!("Failed to tokenize initial prompt.");
         }
-        Err(InferenceError::UserCallback(_)) | Err(InferenceError::EndOfText) => {
+        Err(InferenceError::UserCallback(_))
+        | Err(InferenceError::EndOfText)
+        | Err(InferenceError::Cancelled)
+        | Err(InferenceError::DeadlineExceeded) => {
             unreachable!("cannot fail")
         }
     }
@@ -98,6 +129,157 @@ fn infer<M: llm::KnownModel + 'static>(args: &cli_args::Infer) -> Result<()> {
     Ok(())
 }
 
+fn perplexity<M: llm::KnownModel + 'static>(args: &cli_args::Perplexity) -> Result<()> {
+    let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref());
+    let inference_session_config = args.generate.inference_session_config();
+    let model = args.model_load.load::<M>()?;
+    let inference_params = args.generate.inference_parameters(model.eot_token_id());
+
+    let res = llm::perplexity(
+        model.as_ref(),
+        &inference_params,
+        inference_session_config,
+        &prompt,
+        |window| {
+            log::info!(
+                "Window {}: perplexity {:.4} ({} tokens)",
+                window.index,
+                window.perplexity,
+                window.predicted_tokens
+            )
+        },
+    );
+
+    match res {
+        Ok(result) => log::info!("Perplexity: {:.4}", result.perplexity),
+        Err(InferenceError::TokenizationFailed) => {
+            log::error!("Failed to tokenize prompt.");
+        }
+        Err(err) => log::error!("Failed to compute perplexity: {err}"),
+    }
+
+    Ok(())
+}
+
+fn embed<M: llm::KnownModel + 'static>(args: &cli_args::Embed) -> Result<()> {
+    let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref());
+    let inference_session_config = args.generate.inference_session_config();
+    let model = args.model_load.load::<M>()?;
+    let inference_params = args.generate.inference_parameters(model.eot_token_id());
+
+    let embedding = match llm::embed(
+        model.as_ref(),
+        &inference_params,
+        inference_session_config,
+        &args.embedding_parameters(),
+        &prompt,
+    ) {
+        Ok(embedding) => embedding,
+        Err(InferenceError::TokenizationFailed) => {
+            log::error!("Failed to tokenize prompt.");
+            return Ok(());
+        }
+        Err(err) => {
+            log::error!("Failed to compute embedding: {err}");
+            return Ok(());
+        }
+    };
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).wrap_err_with(|| format!("Failed to create {path:?}"))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match args.format {
+        cli_args::EmbeddingFormat::Json => {
+            serde_json::to_writer(&mut writer, &embedding)?;
+            writeln!(writer)?;
+        }
+        cli_args::EmbeddingFormat::Binary => {
+            for value in &embedding {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+
+    if let Some(path) = &args.output {
+        log::info!(
+            "Wrote {}-dimensional embedding to {path:?}",
+            embedding.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn bench_eval<M: llm::KnownModel + 'static>(args: &cli_args::BenchEval) -> Result<()> {
+    let mut items = bench_eval::read_items(&args.dataset)?;
+    if let Some(limit) = args.limit {
+        items.truncate(limit);
+    }
+
+    let inference_session_config = args.generate.inference_session_config();
+    let model = args.model_load.load::<M>()?;
+    let inference_params = args.generate.inference_parameters(model.eot_token_id());
+    let mut session = model.start_session(inference_session_config);
+
+    let mut correct = 0;
+    let mut evaluated = 0;
+    for (index, item) in items.iter().enumerate() {
+        let prediction = match bench_eval::predict(
+            model.as_ref(),
+            &inference_params,
+            &mut session,
+            args.normalization,
+            item,
+        ) {
+            Ok(prediction) => prediction,
+            Err(err) => {
+                log::warn!("Skipping item {index}: {err}");
+                continue;
+            }
+        };
+
+        evaluated += 1;
+        if prediction == item.answer {
+            correct += 1;
+        }
+        log::debug!(
+            "Item {index}: predicted {prediction}, answer {}",
+            item.answer
+        );
+        if evaluated % 100 == 0 {
+            log::info!(
+                "{evaluated}/{} items: accuracy {:.2}%",
+                items.len(),
+                100.0 * correct as f64 / evaluated as f64
+            );
+        }
+
+        if let Some(target) = args.confidence_margin {
+            let margin = 100.0 * bench_eval::confidence_margin(correct, evaluated);
+            if evaluated >= args.min_items && margin < target {
+                log::info!("Stopping early: the accuracy is known to within ±{margin:.2}%");
+                break;
+            }
+        }
+    }
+
+    if evaluated == 0 {
+        log::error!("No items were evaluated.");
+    } else {
+        log::info!(
+            "Accuracy: {:.2}% ±{:.2}% ({correct}/{evaluated} items)",
+            100.0 * correct as f64 / evaluated as f64,
+            100.0 * bench_eval::confidence_margin(correct, evaluated)
+        );
+    }
+
+    Ok(())
+}
+
 fn info<M: llm::KnownModel + 'static>(args: &cli_args::Info) -> Result<()> {
     let file = File::open(&args.model_path)?;
     let mut reader = BufReader::new(&file);
@@ -107,8 +289,16 @@ fn info<M: llm::KnownModel + 'static>(args: &cli_args::Info) -> Result<()> {
 
     llm::ggml_format::load(&mut reader, &mut loader)?;
 
-    log::info!("Container type: {:?}", loader.container_type);
+    log::info!("Container type: {}", loader.container_type);
     log::info!("Hyperparameters: {:?}", loader.hyperparameters);
+    log::info!(
+        "Metadata: {:?}",
+        loader
+            .metadata
+            .iter()
+            .map(|(key, value)| format!("{key} = {value}"))
+            .collect::<Vec<_>>()
+    );
     log::info!(
         "Tensors: {:?}",
         loader
@@ -119,6 +309,35 @@ fn info<M: llm::KnownModel + 'static>(args: &cli_args::Info) -> Result<()> {
     );
     log::info!("Vocabulary size: {}", loader.vocabulary.id_to_token.len());
 
+    let memory = llm::MemoryRequirements::new(
+        loader.container_type,
+        &loader.hyperparameters,
+        &loader.tensors,
+        &args.model_parameters(),
+        args.inference_session_config(),
+    );
+    let size = |bytes: usize| bytesize::to_string(bytes as u64, false);
+    log::info!("Estimated memory requirements:");
+    log::info!("  Weights (mmap): {}", size(memory.weights_mmap));
+    log::info!("  Weights (owned): {}", size(memory.weights_owned));
+    log::info!(
+        "  KV cache per session: {}",
+        size(memory.kv_cache_per_session)
+    );
+    log::info!(
+        "  Scratch buffers per session: {}",
+        size(memory.scratch_per_session)
+    );
+    log::info!(
+        "  Evaluation context per session: {}",
+        size(memory.eval_context_per_session)
+    );
+    log::info!(
+        "  Total for {} session(s): {}",
+        args.num_sessions,
+        size(memory.total(args.num_sessions))
+    );
+
     if args.dump_vocabulary {
         log::info!("Dumping vocabulary:");
         for (tid, token) in loader.vocabulary.id_to_token.iter().enumerate() {
@@ -137,8 +356,8 @@ fn info<M: llm::KnownModel + 'static>(args: &cli_args::Info) -> Result<()> {
 
 fn prompt_tokens<M: llm::KnownModel + 'static>(args: &cli_args::PromptTokens) -> Result<()> {
     let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref());
-    let model = args.model_load.load::<M>()?;
-    let toks = match model.vocabulary().tokenize(&prompt, false) {
+    let vocabulary = args.model_load.load_vocabulary::<M>()?;
+    let toks = match vocabulary.tokenize(&prompt, false) {
         Ok(toks) => toks,
         Err(e) => {
             log::error!("Could not tokenize prompt: {e}");
@@ -156,7 +375,10 @@ fn prompt_tokens<M: llm::KnownModel + 'static>(args: &cli_args::PromptTokens) ->
     log::info!(
         "{}",
         toks.iter()
-            .map(|(s, tid)| format!("{s:?}:{tid}"))
+            .map(|(_, tid)| {
+                let text = String::from_utf8_lossy(&vocabulary.decode_token(*tid)).into_owned();
+                format!("{text:?}:{tid}")
+            })
             .collect::<Vec<_>>()
             .join(", ")
     );
@@ -164,12 +386,11 @@ fn prompt_tokens<M: llm::KnownModel + 'static>(args: &cli_args::PromptTokens) ->
     Ok(())
 }
 
-fn interactive<M: llm::KnownModel + 'static>(
-    args: &cli_args::Repl,
-    // If set to false, the session will be cloned after each inference
-    // to ensure that previous state is not carried over.
-    chat_mode: bool,
-) -> Result<()> {
+fn repl<M: llm::KnownModel + 'static>(args: &cli_args::Repl) -> Result<()> {
+    // The prompt is fed before inference starts, so there is nothing left to heal.
+    if args.generate.token_healing > 0 {
+        bail!("--token-healing is only supported by `infer`, not `repl`");
+    }
     let prompt_file = args.prompt_file.contents();
     let inference_session_config = args.generate.inference_session_config();
     let model = args.model_load.load::<M>()?;
@@ -182,22 +403,15 @@ fn interactive<M: llm::KnownModel + 'static>(
     let inference_params = args.generate.inference_parameters(model.eot_token_id());
 
     let mut rng = args.generate.rng();
-    let mut rl = rustyline::Editor::<LineContinuationValidator, DefaultHistory>::new()?;
-    rl.set_helper(Some(LineContinuationValidator));
-    rl.bind_sequence(
-        Event::KeySeq(vec![KeyEvent(KeyCode::Enter, Modifiers::SHIFT)]),
-        EventHandler::Simple(Cmd::Newline),
-    );
+    let mut rl = line_editor()?;
 
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(raw_line) => {
-                let session_backup = if chat_mode {
-                    None
-                } else {
-                    Some(session.clone())
-                };
+                // The session is restored after each inference to ensure that
+                // previous state is not carried over.
+                let session_backup = session.clone();
                 let line = raw_line.replace("\\\n", "\n");
 
                 let prompt = prompt_file
@@ -226,6 +440,7 @@ fn interactive<M: llm::KnownModel + 'static>(
                         parameters: Some(&inference_params),
                         play_back_previous_tokens: session_loaded,
                         maximum_token_count: args.generate.num_predict,
+                        ..Default::default()
                     },
                     // EvaluateOuputRequest
                     &mut Default::default(),
@@ -236,14 +451,85 @@ fn interactive<M: llm::KnownModel + 'static>(
                     },
                 );
                 println!();
+                log_reply_errors(res);
 
-                if let Err(InferenceError::ContextFull) = res {
-                    log::error!("Reply exceeds context window length");
+                session = session_backup;
+            }
+            Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
+                break;
+            }
+            Err(err) => {
+                log::error!("{err}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn chat<M: llm::KnownModel + 'static>(args: &cli_args::Chat) -> Result<()> {
+    // The prompt is fed before inference starts, so there is nothing left to heal.
+    if args.generate.token_healing > 0 {
+        bail!("--token-healing is only supported by `infer`, not `chat`");
+    }
+    let template = args.template()?;
+    let inference_session_config = args.generate.inference_session_config();
+    let model = args.model_load.load::<M>()?;
+    let (mut session, _) = snapshot::read_or_create_session(
+        model.as_ref(),
+        None,
+        args.generate.load_session.as_deref(),
+        inference_session_config,
+    );
+    let inference_params = args.generate.inference_parameters(model.eot_token_id());
+
+    let mut conversation =
+        Conversation::new(template).with_context_strategy(args.context_strategy.into());
+    if let Some(system) = args.prompt_file.contents() {
+        conversation.push(Role::System, system);
+    }
+
+    let mut rng = args.generate.rng();
+    let mut rl = line_editor()?;
+
+    loop {
+        let readline = rl.readline(">> ");
+        match readline {
+            Ok(raw_line) => {
+                conversation.push(Role::User, raw_line.replace("\\\n", "\n"));
+
+                // The spinner is shown until the prompt has been fed and the
+                // first token of the reply is generated.
+                let mut sp = Some(spinoff::Spinner::new(
+                    spinoff::spinners::Dots2,
+                    "".to_string(),
+                    None,
+                ));
+                let res = conversation.reply::<Infallible>(
+                    &mut session,
+                    model.as_ref(),
+                    &inference_params,
+                    &mut rng,
+                    args.generate.num_predict,
+                    |tk| {
+                        if let Some(sp) = sp.take() {
+                            sp.clear();
+                        }
+                        print!("{tk}");
+                        std::io::stdout().flush().unwrap();
+                        Ok(())
+                    },
+                );
+                if let Some(sp) = sp.take() {
+                    sp.clear();
                 }
+                println!();
 
-                if let Some(session_backup) = session_backup {
-                    session = session_backup;
+                if let Err(InferenceError::ContextFull) = res {
+                    log::error!("The conversation no longer fits in the context window.");
+                    break;
                 }
+                log_reply_errors(res);
             }
             Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
                 break;
@@ -257,6 +543,28 @@ fn interactive<M: llm::KnownModel + 'static>(
     Ok(())
 }
 
+fn line_editor() -> Result<rustyline::Editor<LineContinuationValidator, DefaultHistory>> {
+    let mut rl = rustyline::Editor::<LineContinuationValidator, DefaultHistory>::new()?;
+    rl.set_helper(Some(LineContinuationValidator));
+    rl.bind_sequence(
+        Event::KeySeq(vec![KeyEvent(KeyCode::Enter, Modifiers::SHIFT)]),
+        EventHandler::Simple(Cmd::Newline),
+    );
+    Ok(rl)
+}
+
+fn log_reply_errors(res: Result<InferenceStats, InferenceError>) {
+    match res {
+        Ok(stats) if stats.finish_reason == FinishReason::ContextFull => {
+            log::error!("Reply exceeds context window length");
+        }
+        Err(InferenceError::ContextFull) => {
+            log::error!("Reply exceeds context window length");
+        }
+        _ => {}
+    }
+}
+
 fn quantize<M: llm::KnownModel + 'static>(args: &cli_args::Quantize) -> Result<()> {
     use llm::QuantizeProgress;
 
@@ -266,6 +574,7 @@ fn quantize<M: llm::KnownModel + 'static>(args: &cli_args::Quantize) -> Result<(
     llm::quantize::<M, _, _>(
         &mut source,
         &mut destination,
+        args.container_type.into(),
         args.target.into(),
         |progress| match progress {
             QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
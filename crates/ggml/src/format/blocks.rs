@@ -0,0 +1,118 @@
+//! The layouts of quantized blocks in the different GGJT versions, and the
+//! conversion of blocks from newer versions to the layouts used by `ggml`.
+//!
+//! GGJT v2 stores the values of 4- and 5-bit quantized blocks with the first half
+//! of the block in the low nibbles and the second half in the high nibbles, instead
+//! of interleaving them. GGJT v3 also stores the scales of `Q4_0`, `Q4_1` and `Q8_0`
+//! blocks as `f16` instead of `f32`.
+
+use crate::ElementType;
+
+/// The number of values in a quantized block.
+const QK: usize = 32;
+
+/// Returns the number of values in a block of `element_type`, and the number of bytes
+/// that block occupies, in the layouts of GGJT `version`.
+///
+/// Returns `None` if `element_type` cannot be stored in that version.
+pub fn block_layout(element_type: ElementType, version: u32) -> Option<(usize, usize)> {
+    use crate::Type::*;
+
+    let f16_scales = version >= 3;
+    Some(match element_type {
+        F32 | I32 => (1, 4),
+        F16 => (1, 2),
+        Q4_0 => (QK, if f16_scales { 18 } else { 20 }),
+        Q4_1 => (QK, if f16_scales { 20 } else { 24 }),
+        Q4_2 if version == 1 => (16, 10),
+        Q5_0 => (QK, 22),
+        Q5_1 => (QK, 24),
+        Q8_0 => (QK, if f16_scales { 34 } else { 36 }),
+        // `Q8_1` is only used for intermediate results, and `Q4_2` was removed in v2.
+        Q4_2 | Q8_1 => return None,
+    })
+}
+
+/// Converts `data`, made of blocks of `element_type` in the layouts of GGJT `version`,
+/// to the layouts used by `ggml`.
+pub fn convert_blocks(element_type: ElementType, version: u32, data: &[u8]) -> Vec<u8> {
+    use crate::Type::*;
+
+    let mut out = Vec::with_capacity(data.len());
+    match element_type {
+        Q4_0 | Q4_1 if version >= 2 => {
+            let n_scales = if element_type == Q4_0 { 1 } else { 2 };
+            let scale_size = if version >= 3 { 2 } else { 4 };
+            for block in data.chunks_exact(n_scales * scale_size + QK / 2) {
+                let (scales, qs) = block.split_at(n_scales * scale_size);
+                for scale in scales.chunks_exact(scale_size) {
+                    out.extend_from_slice(&read_scale(scale).to_le_bytes());
+                }
+                interleave_nibbles(qs, &mut out);
+            }
+        }
+        Q5_0 | Q5_1 if version >= 2 => {
+            // The `f16` scales and the high bits are laid out the same way in all versions.
+            let header_size = if element_type == Q5_0 {
+                2 + 4
+            } else {
+                2 + 2 + 4
+            };
+            for block in data.chunks_exact(header_size + QK / 2) {
+                let (header, qs) = block.split_at(header_size);
+                out.extend_from_slice(header);
+                interleave_nibbles(qs, &mut out);
+            }
+        }
+        Q8_0 if version >= 3 => {
+            for block in data.chunks_exact(2 + QK) {
+                let (scale, qs) = block.split_at(2);
+                out.extend_from_slice(&read_scale(scale).to_le_bytes());
+                out.extend_from_slice(qs);
+            }
+        }
+        _ => out.extend_from_slice(data),
+    }
+    out
+}
+
+/// Reads a little-endian scale that is stored as either an `f16` or an `f32`.
+fn read_scale(bytes: &[u8]) -> f32 {
+    match *bytes {
+        [a, b] => f16_to_f32(u16::from_le_bytes([a, b])),
+        [a, b, c, d] => f32::from_le_bytes([a, b, c, d]),
+        _ => unreachable!("scales are either 2 or 4 bytes"),
+    }
+}
+
+/// Packs the values of a block, stored as the first half in the low nibbles of `qs`
+/// and the second half in the high nibbles, as consecutive pairs of nibbles.
+fn interleave_nibbles(qs: &[u8], out: &mut Vec<u8>) {
+    let value = |i: usize| {
+        if i < QK / 2 {
+            qs[i] & 0x0F
+        } else {
+            qs[i - QK / 2] >> 4
+        }
+    };
+    out.extend((0..QK / 2).map(|j| value(2 * j) | value(2 * j + 1) << 4));
+}
+
+/// Converts the bits of an IEEE 754 half-precision float to an `f32`.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = u32::from(bits >> 15) << 31;
+    let exponent = u32::from(bits >> 10) & 0x1F;
+    let mantissa = u32::from(bits) & 0x3FF;
+
+    let magnitude = match exponent {
+        // Zero and subnormals, which are normal as an `f32`.
+        0 => {
+            let value = mantissa as f32 * 2f32.powi(-24);
+            return f32::from_bits(sign | value.to_bits());
+        }
+        // Infinities and NaNs.
+        0x1F => 0x7F80_0000 | mantissa << 13,
+        _ => (exponent + 127 - 15) << 23 | mantissa << 13,
+    };
+    f32::from_bits(sign | magnitude)
+}
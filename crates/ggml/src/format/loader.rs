@@ -10,7 +10,12 @@ use std::{
 };
 
 use crate::{
-    util::{has_data_left, read_bytes_with_len, read_f32, read_i32, read_u32},
+    format::{
+        block_layout, convert_blocks,
+        metadata::{read_metadata, read_string},
+        Metadata, MetadataError,
+    },
+    util::{has_data_left, read_bytes_with_len, read_f32, read_i32, read_u32, read_u64},
     ContainerType, ElementType,
 };
 
@@ -46,6 +51,9 @@ pub enum LoadError<E: Error> {
     #[error("invariant broken: {0}")]
     /// An invariant was broken.
     InvariantBroken(String),
+    #[error("invalid metadata")]
+    /// The metadata of a GGKV file could not be read.
+    InvalidMetadata(#[from] MetadataError),
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +71,8 @@ pub struct TensorLoadInfo {
     pub element_type: ElementType,
     /// start of tensor - start of file
     pub start_offset: u64,
+    /// The container the tensor was read from, which determines the layout of its data.
+    pub container_type: ContainerType,
 }
 impl TensorLoadInfo {
     /// Get the dimensions of the tensor.
@@ -75,17 +85,37 @@ impl TensorLoadInfo {
         data_size(self.element_type, self.dims().iter().product())
     }
 
-    /// Reads the tensor's data from the given reader in an owned fashion.
+    /// Calculate the size of the tensor's values in bytes in the file it was read from.
+    ///
+    /// This differs from [Self::calc_size] when the quantized block layouts of the
+    /// file differ from those used by `ggml`.
+    pub fn calc_file_size(&self) -> usize {
+        let version = self.container_type.block_layout_version();
+        match block_layout(self.element_type, version) {
+            Some((block_size, block_bytes)) if version != crate::FORMAT_VERSION => {
+                self.dims().iter().product::<usize>() / block_size * block_bytes
+            }
+            _ => self.calc_size(),
+        }
+    }
+
+    /// Reads the tensor's data from the given reader in an owned fashion, converting
+    /// it to the block layouts used by `ggml` if necessary.
     ///
     /// The behaviour is undefined if the reader does not correspond to this info.
     ///
     /// Do not use this if loading with `mmap`.
     pub fn read_data<R: BufRead + Seek>(&self, reader: &mut R) -> std::io::Result<Vec<u8>> {
-        let n_bytes = self.n_elements * crate::type_size(self.element_type);
-        let mut data = vec![0; n_bytes];
+        let mut data = vec![0; self.calc_file_size()];
         reader.seek(SeekFrom::Start(self.start_offset))?;
         reader.read_exact(&mut data)?;
-        Ok(data)
+
+        let version = self.container_type.block_layout_version();
+        if version == crate::FORMAT_VERSION {
+            Ok(data)
+        } else {
+            Ok(convert_blocks(self.element_type, version, &data))
+        }
     }
 }
 
@@ -113,6 +143,9 @@ pub trait LoadHandler<E: Error> {
         &mut self,
         reader: &mut dyn BufRead,
     ) -> Result<PartialHyperparameters, E>;
+    /// Called when the [Metadata] of a [ContainerType::Ggkv] model has been read, instead of
+    /// [LoadHandler::read_hyperparameters]. The hyperparameters are stored within it.
+    fn read_metadata(&mut self, metadata: Metadata) -> Result<PartialHyperparameters, E>;
     /// Called when a new [crate::Tensor] is read for the model.
     fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), E>;
 }
@@ -122,10 +155,55 @@ pub fn load<E: Error, R: BufRead + Seek>(
     reader: &mut R,
     handler: &mut impl LoadHandler<E>,
 ) -> Result<(), LoadError<E>> {
-    // Verify magic
+    let container_type = load_vocabulary(reader, handler)?;
+
+    // Load tensor data
+    match container_type {
+        ContainerType::Ggkv(_) => load_tensor_table(reader, handler, container_type),
+        _ => load_weights(reader, handler, container_type),
+    }
+}
+
+/// Load the hyperparameters and vocabulary of a GGML model from a `reader` with the
+/// [LoadHandler], stopping before the tensors. The tensor callbacks of the handler
+/// are not called.
+///
+/// Returns the [ContainerType] of the model.
+pub fn load_vocabulary<E: Error, R: BufRead>(
+    reader: &mut R,
+    handler: &mut impl LoadHandler<E>,
+) -> Result<ContainerType, LoadError<E>> {
+    // Verify magic and load format version
     let container_type: ContainerType = match read_u32(reader)? {
-        crate::FILE_MAGIC_GGMF => ContainerType::Ggmf,
-        crate::FILE_MAGIC_GGJT => ContainerType::Ggjt,
+        crate::FILE_MAGIC_GGMF => match read_u32(reader)? {
+            1 => ContainerType::Ggmf(1),
+            version => {
+                return Err(LoadError::InvalidFormatVersion(
+                    ContainerType::Ggmf(version),
+                    version,
+                ))
+            }
+        },
+        crate::FILE_MAGIC_GGJT => match read_u32(reader)? {
+            version if crate::SUPPORTED_GGJT_VERSIONS.contains(&version) => {
+                ContainerType::Ggjt(version)
+            }
+            version => {
+                return Err(LoadError::InvalidFormatVersion(
+                    ContainerType::Ggjt(version),
+                    version,
+                ))
+            }
+        },
+        crate::FILE_MAGIC_GGKV => match read_u32(reader)? {
+            crate::GGKV_VERSION => ContainerType::Ggkv(crate::GGKV_VERSION),
+            version => {
+                return Err(LoadError::InvalidFormatVersion(
+                    ContainerType::Ggkv(version),
+                    version,
+                ))
+            }
+        },
         crate::FILE_MAGIC_UNVERSIONED => ContainerType::Ggml,
         magic => return Err(LoadError::InvalidMagic(magic)),
     };
@@ -133,21 +211,15 @@ pub fn load<E: Error, R: BufRead + Seek>(
         .container_type(container_type)
         .map_err(LoadError::ImplementationError)?;
 
-    // Load format version
-    match container_type {
-        ContainerType::Ggmf | ContainerType::Ggjt => {
-            let _version: u32 = match read_u32(reader)? {
-                crate::FORMAT_VERSION => crate::FORMAT_VERSION,
-                version => return Err(LoadError::InvalidFormatVersion(container_type, version)),
-            };
+    // Load hyper params
+    let hparams = match container_type {
+        ContainerType::Ggkv(_) => {
+            let metadata = read_metadata(reader)?;
+            handler.read_metadata(metadata)
         }
-        ContainerType::Ggml => {}
+        _ => handler.read_hyperparameters(reader),
     }
-
-    // Load hyper params
-    let hparams = handler
-        .read_hyperparameters(reader)
-        .map_err(LoadError::ImplementationError)?;
+    .map_err(LoadError::ImplementationError)?;
     let n_vocab = hparams.n_vocab;
 
     // Load vocabulary
@@ -155,7 +227,9 @@ pub fn load<E: Error, R: BufRead + Seek>(
         let len = read_u32(reader)?.try_into()?;
         let token = read_bytes_with_len(reader, len)?;
         let token_score = match container_type {
-            ContainerType::Ggmf | ContainerType::Ggjt => read_f32(reader)?,
+            ContainerType::Ggmf(_) | ContainerType::Ggjt(_) | ContainerType::Ggkv(_) => {
+                read_f32(reader)?
+            }
             ContainerType::Ggml => {
                 // Legacy model, set empty score
                 0.
@@ -166,22 +240,21 @@ pub fn load<E: Error, R: BufRead + Seek>(
             .map_err(LoadError::ImplementationError)?;
     }
 
-    // Load tensor data
-    match container_type {
-        ContainerType::Ggmf | ContainerType::Ggml => load_weights(reader, handler, false),
-        ContainerType::Ggjt => load_weights(reader, handler, true),
-    }
+    Ok(container_type)
 }
 
 /// # Params
 ///
-/// `align`
-/// align to 4 bytes before reading tensor weights
+/// `container_type`
+/// GGJT files are aligned to 32 bytes before each tensor's weights, and their
+/// version determines the layout of those weights
 fn load_weights<E: Error, R: BufRead + Seek>(
     reader: &mut R,
     handler: &mut impl LoadHandler<E>,
-    align: bool,
+    container_type: ContainerType,
 ) -> Result<(), LoadError<E>> {
+    let align = matches!(container_type, ContainerType::Ggjt(_));
+    let version = container_type.block_layout_version();
     while has_data_left(reader)? {
         // load tensor header
         let n_dims: usize = read_i32(reader)?.try_into()?;
@@ -204,11 +277,7 @@ fn load_weights<E: Error, R: BufRead + Seek>(
 
         // load tensor name
         let name = String::from_utf8(read_bytes_with_len(reader, name_len.try_into()?)?)?;
-        let ftype =
-            crate::Type::try_from(ftype).map_err(|_| LoadError::UnsupportedElementType {
-                tensor_name: name.clone(),
-                ftype,
-            })?;
+        let ftype = element_type(name.as_str(), ftype, version)?;
 
         // sanity check
         match ftype {
@@ -235,8 +304,9 @@ fn load_weights<E: Error, R: BufRead + Seek>(
             n_elements,
             element_type: ftype,
             start_offset: offset_aligned,
+            container_type,
         };
-        let n_bytes = tensor_info.calc_size();
+        let n_bytes = tensor_info.calc_file_size();
         handler
             .tensor_buffer(tensor_info)
             .map_err(LoadError::ImplementationError)?;
@@ -245,3 +315,71 @@ fn load_weights<E: Error, R: BufRead + Seek>(
 
     Ok(())
 }
+
+/// Loads the tensor table of a GGKV file, which lists every tensor before their data.
+///
+/// Each entry holds the tensor's name, its dimensions, its element type and the offset
+/// of its data from the start of the data section, which is aligned to 32 bytes.
+fn load_tensor_table<E: Error, R: BufRead + Seek>(
+    reader: &mut R,
+    handler: &mut impl LoadHandler<E>,
+    container_type: ContainerType,
+) -> Result<(), LoadError<E>> {
+    let n_tensors = read_u32(reader)?;
+    let mut tensors = Vec::with_capacity(n_tensors.try_into()?);
+    for _ in 0..n_tensors {
+        let name = read_string(reader)?;
+        let n_dims: usize = read_u32(reader)?.try_into()?;
+        let mut dims = [1usize, 1];
+        for dim in &mut dims {
+            *dim = read_u32(reader)?.try_into()?;
+        }
+        let ne_len = dims.len();
+        if n_dims > ne_len {
+            return Err(LoadError::InvariantBroken(format!("{n_dims} <= {ne_len}")));
+        }
+        let element_type = element_type(&name, read_u32(reader)?, crate::FORMAT_VERSION)?;
+        let offset = read_u64(reader)?;
+
+        tensors.push(TensorLoadInfo {
+            name,
+            dims,
+            n_dims,
+            n_elements: dims.iter().product(),
+            element_type,
+            start_offset: offset,
+            container_type,
+        });
+    }
+
+    let data_start = (reader.stream_position()? + 31) & !31;
+    for mut tensor_info in tensors {
+        tensor_info.start_offset += data_start;
+        handler
+            .tensor_buffer(tensor_info)
+            .map_err(LoadError::ImplementationError)?;
+    }
+
+    Ok(())
+}
+
+/// Converts the `ftype` of a tensor to an [ElementType], checking that it can be stored
+/// in the block layouts of GGJT `version`.
+fn element_type<E: Error>(
+    tensor_name: &str,
+    ftype: u32,
+    version: u32,
+) -> Result<ElementType, LoadError<E>> {
+    match crate::Type::try_from(ftype) {
+        Ok(element_type)
+            if version == crate::FORMAT_VERSION
+                || block_layout(element_type, version).is_some() =>
+        {
+            Ok(element_type)
+        }
+        _ => Err(LoadError::UnsupportedElementType {
+            tensor_name: tensor_name.to_owned(),
+            ftype,
+        }),
+    }
+}
@@ -0,0 +1,325 @@
+//! The typed key/value metadata stored in [GGKV](crate::ContainerType::Ggkv) files.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    format::LoadError,
+    util::{read_bytes_with_len, read_f32, read_i32, read_u32, write_f32, write_i32, write_u32},
+};
+
+#[derive(Debug, thiserror::Error)]
+/// Errors that can occur while reading values from [Metadata].
+pub enum MetadataError {
+    #[error("the metadata key {key:?} is missing")]
+    /// A required key was not present.
+    MissingKey {
+        /// The key that was missing.
+        key: String,
+    },
+    #[error("the metadata key {key:?} has type {actual}, but {expected} was expected")]
+    /// The value of a key did not have the expected type.
+    WrongType {
+        /// The key with the wrong type.
+        key: String,
+        /// The type that was expected.
+        expected: MetadataValueType,
+        /// The type that was found.
+        actual: MetadataValueType,
+    },
+    #[error("the metadata key {key:?} has type {actual}, but u32 or i32 was expected")]
+    /// The value of a key was requested as an integer of either type, but was not one.
+    NotAnInteger {
+        /// The key that was not an integer.
+        key: String,
+        /// The type that was found.
+        actual: MetadataValueType,
+    },
+    #[error("the value of the metadata key {key:?} is out of range")]
+    /// The value of a key could not be converted to the requested integer type.
+    OutOfRange {
+        /// The key whose value was out of range.
+        key: String,
+    },
+    #[error("unknown metadata value type {0}")]
+    /// A value in the file had an unknown type.
+    UnknownValueType(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The type of a [MetadataValue].
+pub enum MetadataValueType {
+    /// An unsigned 32-bit integer.
+    U32,
+    /// A signed 32-bit integer.
+    I32,
+    /// A 32-bit float.
+    F32,
+    /// A boolean.
+    Bool,
+    /// A UTF-8 string.
+    String,
+}
+impl From<MetadataValueType> for u32 {
+    fn from(value: MetadataValueType) -> Self {
+        match value {
+            MetadataValueType::U32 => 0,
+            MetadataValueType::I32 => 1,
+            MetadataValueType::F32 => 2,
+            MetadataValueType::Bool => 3,
+            MetadataValueType::String => 4,
+        }
+    }
+}
+impl TryFrom<u32> for MetadataValueType {
+    type Error = MetadataError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MetadataValueType::U32),
+            1 => Ok(MetadataValueType::I32),
+            2 => Ok(MetadataValueType::F32),
+            3 => Ok(MetadataValueType::Bool),
+            4 => Ok(MetadataValueType::String),
+            other => Err(MetadataError::UnknownValueType(other)),
+        }
+    }
+}
+impl fmt::Display for MetadataValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataValueType::U32 => write!(f, "u32"),
+            MetadataValueType::I32 => write!(f, "i32"),
+            MetadataValueType::F32 => write!(f, "f32"),
+            MetadataValueType::Bool => write!(f, "bool"),
+            MetadataValueType::String => write!(f, "string"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A value in [Metadata].
+pub enum MetadataValue {
+    /// An unsigned 32-bit integer.
+    U32(u32),
+    /// A signed 32-bit integer.
+    I32(i32),
+    /// A 32-bit float.
+    F32(f32),
+    /// A boolean.
+    Bool(bool),
+    /// A UTF-8 string.
+    String(String),
+}
+impl MetadataValue {
+    /// The type of this value.
+    pub fn value_type(&self) -> MetadataValueType {
+        match self {
+            MetadataValue::U32(_) => MetadataValueType::U32,
+            MetadataValue::I32(_) => MetadataValueType::I32,
+            MetadataValue::F32(_) => MetadataValueType::F32,
+            MetadataValue::Bool(_) => MetadataValueType::Bool,
+            MetadataValue::String(_) => MetadataValueType::String,
+        }
+    }
+}
+impl fmt::Display for MetadataValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataValue::U32(value) => write!(f, "{value}"),
+            MetadataValue::I32(value) => write!(f, "{value}"),
+            MetadataValue::F32(value) => write!(f, "{value}"),
+            MetadataValue::Bool(value) => write!(f, "{value}"),
+            MetadataValue::String(value) => write!(f, "{value:?}"),
+        }
+    }
+}
+impl From<u32> for MetadataValue {
+    fn from(value: u32) -> Self {
+        MetadataValue::U32(value)
+    }
+}
+impl From<i32> for MetadataValue {
+    fn from(value: i32) -> Self {
+        MetadataValue::I32(value)
+    }
+}
+impl From<f32> for MetadataValue {
+    fn from(value: f32) -> Self {
+        MetadataValue::F32(value)
+    }
+}
+impl From<bool> for MetadataValue {
+    fn from(value: bool) -> Self {
+        MetadataValue::Bool(value)
+    }
+}
+impl From<String> for MetadataValue {
+    fn from(value: String) -> Self {
+        MetadataValue::String(value)
+    }
+}
+impl From<&str> for MetadataValue {
+    fn from(value: &str) -> Self {
+        MetadataValue::String(value.to_owned())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Typed key/value metadata describing a model, such as its hyperparameters.
+pub struct Metadata(BTreeMap<String, MetadataValue>);
+impl Metadata {
+    /// Get the value of `key`, if present.
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.0.get(key)
+    }
+
+    /// Set the value of `key`, returning its previous value, if any.
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<MetadataValue>,
+    ) -> Option<MetadataValue> {
+        self.0.insert(key.into(), value.into())
+    }
+
+    /// Iterate over the keys and values, ordered by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MetadataValue)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value))
+    }
+
+    /// The number of keys.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the value of `key`, which must be present.
+    pub fn get_required(&self, key: &str) -> Result<&MetadataValue, MetadataError> {
+        self.get(key).ok_or_else(|| MetadataError::MissingKey {
+            key: key.to_owned(),
+        })
+    }
+
+    /// Get the value of `key` as a `u32`.
+    pub fn get_u32(&self, key: &str) -> Result<u32, MetadataError> {
+        match self.get_required(key)? {
+            MetadataValue::U32(value) => Ok(*value),
+            other => Err(wrong_type(key, MetadataValueType::U32, other)),
+        }
+    }
+
+    /// Get the value of `key` as an `i32`.
+    pub fn get_i32(&self, key: &str) -> Result<i32, MetadataError> {
+        match self.get_required(key)? {
+            MetadataValue::I32(value) => Ok(*value),
+            other => Err(wrong_type(key, MetadataValueType::I32, other)),
+        }
+    }
+
+    /// Get the value of `key` as a `f32`.
+    pub fn get_f32(&self, key: &str) -> Result<f32, MetadataError> {
+        match self.get_required(key)? {
+            MetadataValue::F32(value) => Ok(*value),
+            other => Err(wrong_type(key, MetadataValueType::F32, other)),
+        }
+    }
+
+    /// Get the value of `key` as a `bool`.
+    pub fn get_bool(&self, key: &str) -> Result<bool, MetadataError> {
+        match self.get_required(key)? {
+            MetadataValue::Bool(value) => Ok(*value),
+            other => Err(wrong_type(key, MetadataValueType::Bool, other)),
+        }
+    }
+
+    /// Get the value of `key` as a string.
+    pub fn get_str(&self, key: &str) -> Result<&str, MetadataError> {
+        match self.get_required(key)? {
+            MetadataValue::String(value) => Ok(value),
+            other => Err(wrong_type(key, MetadataValueType::String, other)),
+        }
+    }
+
+    /// Get the value of `key`, which may be either integer type, as a `usize`.
+    pub fn get_usize(&self, key: &str) -> Result<usize, MetadataError> {
+        let out_of_range = |_| MetadataError::OutOfRange {
+            key: key.to_owned(),
+        };
+        match self.get_required(key)? {
+            MetadataValue::U32(value) => usize::try_from(*value).map_err(out_of_range),
+            MetadataValue::I32(value) => usize::try_from(*value).map_err(out_of_range),
+            other => Err(MetadataError::NotAnInteger {
+                key: key.to_owned(),
+                actual: other.value_type(),
+            }),
+        }
+    }
+}
+
+fn wrong_type(key: &str, expected: MetadataValueType, actual: &MetadataValue) -> MetadataError {
+    MetadataError::WrongType {
+        key: key.to_owned(),
+        expected,
+        actual: actual.value_type(),
+    }
+}
+
+/// Reads a count-prefixed list of keys and their typed values.
+pub(crate) fn read_metadata<E: Error>(reader: &mut dyn BufRead) -> Result<Metadata, LoadError<E>> {
+    let mut metadata = Metadata::default();
+    let n_keys = read_u32(reader)?;
+    for _ in 0..n_keys {
+        let key = read_string(reader)?;
+        let value = match MetadataValueType::try_from(read_u32(reader)?)? {
+            MetadataValueType::U32 => MetadataValue::U32(read_u32(reader)?),
+            MetadataValueType::I32 => MetadataValue::I32(read_i32(reader)?),
+            MetadataValueType::F32 => MetadataValue::F32(read_f32(reader)?),
+            MetadataValueType::Bool => MetadataValue::Bool(read_u32(reader)? != 0),
+            MetadataValueType::String => MetadataValue::String(read_string(reader)?),
+        };
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+/// Writes the keys and typed values of `metadata`, prefixed by their count.
+pub(crate) fn write_metadata(writer: &mut dyn Write, metadata: &Metadata) -> std::io::Result<()> {
+    write_u32(writer, to_u32(metadata.len())?)?;
+    for (key, value) in metadata.iter() {
+        write_string(writer, key)?;
+        write_u32(writer, value.value_type().into())?;
+        match value {
+            MetadataValue::U32(value) => write_u32(writer, *value)?,
+            MetadataValue::I32(value) => write_i32(writer, *value)?,
+            MetadataValue::F32(value) => write_f32(writer, *value)?,
+            MetadataValue::Bool(value) => write_u32(writer, u32::from(*value))?,
+            MetadataValue::String(value) => write_string(writer, value)?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads a length-prefixed UTF-8 string.
+pub(crate) fn read_string<E: Error>(reader: &mut dyn BufRead) -> Result<String, LoadError<E>> {
+    let len = read_u32(reader)?.try_into()?;
+    Ok(String::from_utf8(read_bytes_with_len(reader, len)?)?)
+}
+
+/// Writes a length-prefixed UTF-8 string.
+pub(crate) fn write_string(writer: &mut dyn Write, value: &str) -> std::io::Result<()> {
+    write_u32(writer, to_u32(value.len())?)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn to_u32(value: usize) -> std::io::Result<u32> {
+    u32::try_from(value).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+}
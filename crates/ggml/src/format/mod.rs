@@ -1,7 +1,11 @@
 //! Loading and saving of [GGML](https://github.com/ggerganov/ggml) files.
 
+mod blocks;
 mod loader;
+pub(crate) mod metadata;
 mod saver;
 
+pub use blocks::*;
 pub use loader::*;
+pub use metadata::{Metadata, MetadataError, MetadataValue, MetadataValueType};
 pub use saver::*;
@@ -1,4 +1,4 @@
-//! The saver module implements a way to save a model to disk in the GGJT format.
+//! The saver module implements a way to save a model to disk in the GGJT or GGKV formats.
 //!
 //! To implement a saver for your model, implement [SaveHandler] for your model
 //! and provide data as appropriate, then call [save] with an instance of
@@ -6,10 +6,16 @@
 
 use std::{
     error::Error,
-    io::{Seek, Write},
+    io::{Seek, SeekFrom, Write},
 };
 
-use crate::{util, ElementType};
+use crate::{
+    format::{
+        metadata::{write_metadata, write_string},
+        Metadata,
+    },
+    util, ContainerType, ElementType,
+};
 
 #[derive(Debug, thiserror::Error)]
 /// Errors that can occur while writing a model.
@@ -26,13 +32,19 @@ pub enum SaveError<E: Error> {
     #[error("invariant broken: {0}")]
     /// An invariant was broken.
     InvariantBroken(String),
+    #[error("cannot save to {0}")]
+    /// Models cannot be saved to the given container type.
+    UnsupportedContainerType(ContainerType),
 }
 
 /// A handler for saving a GGML model.
 pub trait SaveHandler<E: Error> {
-    /// Called when the hyperparameters must be written.
+    /// Called when the hyperparameters must be written in a GGJT file.
     fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), E>;
 
+    /// Called when the [Metadata], including the hyperparameters, is needed for a GGKV file.
+    fn metadata(&mut self) -> Result<Metadata, E>;
+
     /// Called when information for a tensor is to be written.
     fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, E>;
 }
@@ -55,10 +67,29 @@ pub struct TensorSaveInfo {
     pub data: Vec<u8>,
 }
 
-/// Saves a model to the given writer.
+/// Saves a model to the given writer, in the given container.
 ///
-/// Only GGJT is supported.
+/// Only GGJT and GGKV are supported. The tensor data is expected to be in the block
+/// layouts used by `ggml`, so GGJT files are written as version [crate::FORMAT_VERSION].
 pub fn save<E: Error, W: Write + Seek>(
+    writer: &mut W,
+    handler: &mut dyn SaveHandler<E>,
+    container_type: ContainerType,
+    vocabulary: &[(Vec<u8>, f32)],
+    tensor_names: &[String],
+) -> Result<(), SaveError<E>> {
+    match container_type {
+        ContainerType::Ggjt(crate::FORMAT_VERSION) => {
+            save_ggjt(writer, handler, vocabulary, tensor_names)
+        }
+        ContainerType::Ggkv(crate::GGKV_VERSION) => {
+            save_ggkv(writer, handler, vocabulary, tensor_names)
+        }
+        _ => Err(SaveError::UnsupportedContainerType(container_type)),
+    }
+}
+
+fn save_ggjt<E: Error, W: Write + Seek>(
     writer: &mut W,
     handler: &mut dyn SaveHandler<E>,
     vocabulary: &[(Vec<u8>, f32)],
@@ -71,12 +102,7 @@ pub fn save<E: Error, W: Write + Seek>(
         .write_hyperparameters(writer)
         .map_err(SaveError::ImplementationError)?;
 
-    // Write vocabulary
-    for (token, score) in vocabulary {
-        util::write_u32(writer, token.len().try_into()?)?;
-        writer.write_all(token)?;
-        util::write_f32(writer, *score)?;
-    }
+    write_vocabulary(writer, vocabulary)?;
 
     // Write tensors
     for name in tensor_names {
@@ -88,15 +114,7 @@ pub fn save<E: Error, W: Write + Seek>(
         } = handler
             .tensor_data(name)
             .map_err(SaveError::ImplementationError)?;
-
-        match element_type {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
-            }
-            _ => {}
-        }
+        check_dims(element_type, dims)?;
 
         // Write tensor header
         util::write_i32(writer, n_dims.try_into()?)?;
@@ -109,15 +127,100 @@ pub fn save<E: Error, W: Write + Seek>(
         // Write tensor name
         writer.write_all(name.as_bytes())?;
 
-        // Align to nearest 32 bytes
-        let offset_curr = writer.stream_position()?;
-        let offset_aligned = (offset_curr + 31) & !31;
-        let padding = usize::try_from(offset_aligned - offset_curr)?;
-        writer.write_all(&vec![0; padding])?;
-
         // Write tensor data
+        write_aligned(writer)?;
+        writer.write_all(&data)?;
+    }
+
+    Ok(())
+}
+
+fn save_ggkv<E: Error, W: Write + Seek>(
+    writer: &mut W,
+    handler: &mut dyn SaveHandler<E>,
+    vocabulary: &[(Vec<u8>, f32)],
+    tensor_names: &[String],
+) -> Result<(), SaveError<E>> {
+    // Write header and metadata
+    util::write_u32(writer, crate::FILE_MAGIC_GGKV)?;
+    util::write_u32(writer, crate::GGKV_VERSION)?;
+    let metadata = handler.metadata().map_err(SaveError::ImplementationError)?;
+    write_metadata(writer, &metadata)?;
+
+    write_vocabulary(writer, vocabulary)?;
+
+    // Reserve the tensor table, which is filled in once the data has been written, as
+    // the tensors are only produced by the handler one at a time. Each entry has a
+    // fixed size besides its name.
+    util::write_u32(writer, tensor_names.len().try_into()?)?;
+    let table_start = writer.stream_position()?;
+    let table_size: usize = tensor_names.iter().map(|name| name.len() + 28).sum();
+    writer.write_all(&vec![0; table_size])?;
+    write_aligned(writer)?;
+    let data_start = writer.stream_position()?;
+
+    // Write tensor data
+    let mut table = Vec::with_capacity(table_size);
+    for name in tensor_names {
+        let TensorSaveInfo {
+            n_dims,
+            dims,
+            element_type,
+            data,
+        } = handler
+            .tensor_data(name)
+            .map_err(SaveError::ImplementationError)?;
+        check_dims(element_type, dims)?;
+
+        write_aligned(writer)?;
+        let offset = writer.stream_position()? - data_start;
         writer.write_all(&data)?;
+
+        write_string(&mut table, name)?;
+        util::write_u32(&mut table, n_dims.try_into()?)?;
+        for dim in dims {
+            util::write_u32(&mut table, dim.try_into()?)?;
+        }
+        util::write_u32(&mut table, element_type.into())?;
+        util::write_u64(&mut table, offset)?;
     }
 
+    // Write tensor table
+    let data_end = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(table_start))?;
+    writer.write_all(&table)?;
+    writer.seek(SeekFrom::Start(data_end))?;
+
     Ok(())
 }
+
+fn write_vocabulary<E: Error>(
+    writer: &mut dyn Write,
+    vocabulary: &[(Vec<u8>, f32)],
+) -> Result<(), SaveError<E>> {
+    for (token, score) in vocabulary {
+        util::write_u32(writer, token.len().try_into()?)?;
+        writer.write_all(token)?;
+        util::write_f32(writer, *score)?;
+    }
+    Ok(())
+}
+
+fn check_dims<E: Error>(element_type: ElementType, dims: [usize; 2]) -> Result<(), SaveError<E>> {
+    match element_type {
+        ElementType::Q4_0 | ElementType::Q4_1 => {
+            if dims[0] % 64 != 0 {
+                return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Pads the writer with zeroes to the nearest 32 bytes.
+fn write_aligned<W: Write + Seek>(writer: &mut W) -> std::io::Result<()> {
+    let offset_curr = writer.stream_position()?;
+    let offset_aligned = (offset_curr + 31) & !31;
+    writer.write_all(&vec![0; (offset_aligned - offset_curr) as usize])
+}
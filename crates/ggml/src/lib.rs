@@ -32,17 +32,49 @@ pub enum ContainerType {
     /// Legacy format, oldest ggml tensor file format
     Ggml,
     /// Legacy format. Introduces versioning. Newer than GGML, older than GGJT.
-    Ggmf,
-    /// [mmap](https://en.wikipedia.org/wiki/Mmap)-able format.
-    Ggjt,
+    Ggmf(u32),
+    /// [mmap](https://en.wikipedia.org/wiki/Mmap)-able format, with the given version.
+    ///
+    /// Version 2 changed the order of the values in 4- and 5-bit quantized blocks,
+    /// and version 3 changed the scales of `Q4_0`, `Q4_1` and `Q8_0` blocks to `f16`.
+    Ggjt(u32),
+    /// Self-describing format, with the given version. The hyperparameters and other
+    /// details of the model are stored as typed key/value [metadata](format::Metadata),
+    /// followed by a table of the tensors and their aligned, mmap-able data.
+    Ggkv(u32),
 }
 impl ContainerType {
     /// Does this container type support mmap?
+    ///
+    /// Only GGJT files whose block layouts match those used by `ggml` can be mapped
+    /// directly; other versions have to be converted as they are loaded.
     pub fn support_mmap(&self) -> bool {
         match self {
             ContainerType::Ggml => false,
-            ContainerType::Ggmf => false,
-            ContainerType::Ggjt => true,
+            ContainerType::Ggmf(_) => false,
+            ContainerType::Ggjt(version) => *version == FORMAT_VERSION,
+            ContainerType::Ggkv(_) => true,
+        }
+    }
+
+    /// The GGJT version whose quantized block layouts are used by this container.
+    ///
+    /// The legacy formats predate the layout changes, and use those of version 1.
+    pub fn block_layout_version(&self) -> u32 {
+        match self {
+            ContainerType::Ggml | ContainerType::Ggmf(_) => 1,
+            ContainerType::Ggjt(version) => *version,
+            ContainerType::Ggkv(_) => FORMAT_VERSION,
+        }
+    }
+}
+impl std::fmt::Display for ContainerType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerType::Ggml => write!(f, "GGML (unversioned)"),
+            ContainerType::Ggmf(version) => write!(f, "GGMF v{version}"),
+            ContainerType::Ggjt(version) => write!(f, "GGJT v{version}"),
+            ContainerType::Ggkv(version) => write!(f, "GGKV v{version}"),
         }
     }
 }
@@ -51,11 +83,19 @@ impl ContainerType {
 pub const FILE_MAGIC_GGMF: u32 = 0x67676d66;
 /// Magic constant for `ggml` files (versioned, ggjt).
 pub const FILE_MAGIC_GGJT: u32 = 0x67676a74;
+/// Magic constant for `ggml` files (versioned, ggkv).
+pub const FILE_MAGIC_GGKV: u32 = 0x67676b76;
 /// Magic constant for `ggml` files (unversioned).
 pub const FILE_MAGIC_UNVERSIONED: u32 = 0x67676d6c;
 
-/// The currently-supported format version for `ggml` files.
+/// The format version whose quantized block layouts match those used by `ggml`.
+///
+/// This is the version written by [format::save].
 pub const FORMAT_VERSION: u32 = 1;
+/// The GGJT versions that can be loaded.
+pub const SUPPORTED_GGJT_VERSIONS: std::ops::RangeInclusive<u32> = 1..=3;
+/// The currently-supported format version for GGKV files.
+pub const GGKV_VERSION: u32 = 1;
 
 /// The size of a `ggml` object.
 pub const OBJECT_SIZE: usize = sys::GGML_OBJECT_SIZE;
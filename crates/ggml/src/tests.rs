@@ -60,26 +60,163 @@ fn can_roundtrip_loader_and_saver() {
             .collect(),
     };
 
-    // Save the model.
-    let mut buffer = Vec::new();
-    let mut cursor = std::io::Cursor::new(&mut buffer);
-    let mut save_handler = MockSaveHandler { model: &model };
-    format::save(
-        &mut cursor,
-        &mut save_handler,
-        &model.vocabulary,
-        &model.tensors.keys().cloned().collect::<Vec<String>>(),
-    )
-    .unwrap();
-
-    // Load the model and confirm that it is the same as the original.
-    let mut cursor = std::io::Cursor::new(&buffer);
-    let mut load_handler = MockLoadHandler {
-        data: &buffer,
-        loaded_model: Model::default(),
+    for container_type in [
+        ContainerType::Ggjt(FORMAT_VERSION),
+        ContainerType::Ggkv(GGKV_VERSION),
+    ] {
+        // Save the model.
+        let mut buffer = Vec::new();
+        let mut cursor = std::io::Cursor::new(&mut buffer);
+        let mut save_handler = MockSaveHandler { model: &model };
+        format::save(
+            &mut cursor,
+            &mut save_handler,
+            container_type,
+            &model.vocabulary,
+            &model.tensors.keys().cloned().collect::<Vec<String>>(),
+        )
+        .unwrap();
+
+        // Load the model and confirm that it is the same as the original.
+        let mut cursor = std::io::Cursor::new(&buffer);
+        let mut load_handler = MockLoadHandler {
+            data: &buffer,
+            container_type,
+            loaded_model: Model::default(),
+        };
+        format::load(&mut cursor, &mut load_handler).unwrap();
+        assert_eq!(load_handler.loaded_model, model);
+    }
+}
+
+#[test]
+fn can_load_vocabulary_without_tensors() {
+    let model = Model {
+        hyperparameters: Hyperparameters {
+            some_hyperparameter: 1,
+            some_other_hyperparameter: 2,
+            vocabulary_size: 2,
+        },
+        vocabulary: vec![
+            ("hello".as_bytes().to_vec(), 0.5),
+            ("world".as_bytes().to_vec(), 0.25),
+        ],
+        tensors: BTreeMap::from([(
+            "tensor".to_owned(),
+            format::TensorSaveInfo {
+                n_dims: 1,
+                dims: [4, 1],
+                element_type: crate::Type::F32,
+                data: vec![0; 16],
+            },
+        )]),
     };
-    format::load(&mut cursor, &mut load_handler).unwrap();
-    assert_eq!(load_handler.loaded_model, model);
+
+    for container_type in [
+        ContainerType::Ggjt(FORMAT_VERSION),
+        ContainerType::Ggkv(GGKV_VERSION),
+    ] {
+        let mut buffer = Vec::new();
+        let mut save_handler = MockSaveHandler { model: &model };
+        format::save(
+            &mut std::io::Cursor::new(&mut buffer),
+            &mut save_handler,
+            container_type,
+            &model.vocabulary,
+            &["tensor".to_owned()],
+        )
+        .unwrap();
+
+        // Only the hyperparameters and vocabulary are read; the tensors are not.
+        let mut cursor = std::io::Cursor::new(&buffer);
+        let mut load_handler = MockLoadHandler {
+            data: &buffer,
+            container_type,
+            loaded_model: Model::default(),
+        };
+        let loaded_container_type =
+            format::load_vocabulary(&mut cursor, &mut load_handler).unwrap();
+        assert_eq!(loaded_container_type, container_type);
+        assert_eq!(
+            load_handler.loaded_model.hyperparameters,
+            model.hyperparameters
+        );
+        assert_eq!(load_handler.loaded_model.vocabulary, model.vocabulary);
+        assert!(load_handler.loaded_model.tensors.is_empty());
+    }
+}
+
+#[test]
+fn can_roundtrip_metadata() {
+    let mut metadata = format::Metadata::default();
+    metadata.insert("n_embd", 4096i32);
+    metadata.insert("rope.theta", 10000f32);
+    metadata.insert("parallel_residual", true);
+    metadata.insert("source", "https://example.com/model");
+
+    let mut buffer = Vec::new();
+    format::metadata::write_metadata(&mut buffer, &metadata).unwrap();
+    let loaded: format::Metadata =
+        format::metadata::read_metadata::<DummyError>(&mut buffer.as_slice()).unwrap();
+    assert_eq!(loaded, metadata);
+
+    assert_eq!(loaded.get_usize("n_embd").unwrap(), 4096);
+    assert_eq!(
+        loaded.get_str("source").unwrap(),
+        "https://example.com/model"
+    );
+    assert!(matches!(
+        loaded.get_u32("rope.theta"),
+        Err(format::MetadataError::WrongType { .. })
+    ));
+    assert!(matches!(
+        loaded.get_usize("rope.theta"),
+        Err(format::MetadataError::NotAnInteger {
+            actual: format::MetadataValueType::F32,
+            ..
+        })
+    ));
+    assert!(matches!(
+        loaded.get_bool("missing"),
+        Err(format::MetadataError::MissingKey { .. })
+    ));
+}
+
+#[test]
+fn can_convert_ggjt_v3_blocks() {
+    // The values 0..32 wrap around to 0..16, stored as the first half of the block in the
+    // low nibbles and the second half in the high nibbles.
+    let values: Vec<u8> = (0..32).map(|i| i % 16).collect();
+    let qs: Vec<u8> = (0..16).map(|j| values[j] | values[j + 16] << 4).collect();
+    let expected_qs: Vec<u8> = (0..16)
+        .map(|j| values[2 * j] | values[2 * j + 1] << 4)
+        .collect();
+
+    // A `Q4_0` block with a `f16` scale of 2.0.
+    let block = [&[0x00, 0x40][..], &qs].concat();
+    assert_eq!(format::block_layout(Type::Q4_0, 3), Some((32, block.len())));
+    let converted = format::convert_blocks(Type::Q4_0, 3, &block);
+    assert_eq!(
+        converted,
+        [&2.0f32.to_le_bytes()[..], &expected_qs].concat()
+    );
+
+    // The same block in v2 has a `f32` scale.
+    let block = [&2.0f32.to_le_bytes()[..], &qs].concat();
+    assert_eq!(format::block_layout(Type::Q4_0, 2), Some((32, block.len())));
+    assert_eq!(format::convert_blocks(Type::Q4_0, 2, &block), converted);
+
+    // A `Q8_0` block with a `f16` scale of -0.5, whose values are unchanged.
+    let values: Vec<u8> = (0..32).collect();
+    let block = [&[0x00, 0xB8][..], &values].concat();
+    assert_eq!(format::block_layout(Type::Q8_0, 3), Some((32, block.len())));
+    assert_eq!(
+        format::convert_blocks(Type::Q8_0, 3, &block),
+        [&(-0.5f32).to_le_bytes()[..], &values].concat()
+    );
+
+    // `Q4_2` was removed in v2.
+    assert_eq!(format::block_layout(Type::Q4_2, 2), None);
 }
 
 #[derive(Default, PartialEq, Debug)]
@@ -103,6 +240,22 @@ impl Hyperparameters {
         util::write_u32(writer, self.vocabulary_size)?;
         Ok(())
     }
+
+    fn from_metadata(metadata: &format::Metadata) -> Result<Self, format::MetadataError> {
+        Ok(Self {
+            some_hyperparameter: metadata.get_u32("some_hyperparameter")?,
+            some_other_hyperparameter: metadata.get_u32("some_other_hyperparameter")?,
+            vocabulary_size: metadata.get_u32("vocabulary_size")?,
+        })
+    }
+
+    fn to_metadata(&self) -> format::Metadata {
+        let mut metadata = format::Metadata::default();
+        metadata.insert("some_hyperparameter", self.some_hyperparameter);
+        metadata.insert("some_other_hyperparameter", self.some_other_hyperparameter);
+        metadata.insert("vocabulary_size", self.vocabulary_size);
+        metadata
+    }
 }
 
 #[derive(Default, PartialEq, Debug)]
@@ -121,6 +274,10 @@ impl format::SaveHandler<DummyError> for MockSaveHandler<'_> {
         Ok(())
     }
 
+    fn metadata(&mut self) -> Result<format::Metadata, DummyError> {
+        Ok(self.model.hyperparameters.to_metadata())
+    }
+
     fn tensor_data(&mut self, tensor_name: &str) -> Result<format::TensorSaveInfo, DummyError> {
         self.model
             .tensors
@@ -132,11 +289,12 @@ impl format::SaveHandler<DummyError> for MockSaveHandler<'_> {
 
 struct MockLoadHandler<'a> {
     data: &'a [u8],
+    container_type: ContainerType,
     loaded_model: Model,
 }
 impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
     fn container_type(&mut self, container_type: ContainerType) -> Result<(), DummyError> {
-        assert_eq!(container_type, ContainerType::Ggjt);
+        assert_eq!(container_type, self.container_type);
         Ok(())
     }
 
@@ -161,6 +319,21 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
         })
     }
 
+    fn read_metadata(
+        &mut self,
+        metadata: format::Metadata,
+    ) -> Result<format::PartialHyperparameters, DummyError> {
+        self.loaded_model.hyperparameters = Hyperparameters::from_metadata(&metadata).unwrap();
+        Ok(format::PartialHyperparameters {
+            n_vocab: self
+                .loaded_model
+                .hyperparameters
+                .vocabulary_size
+                .try_into()
+                .unwrap(),
+        })
+    }
+
     fn tensor_buffer(&mut self, info: format::TensorLoadInfo) -> Result<(), DummyError> {
         let data = format::TensorSaveInfo {
             n_dims: info.n_dims,
@@ -19,6 +19,11 @@ pub fn read_u32(reader: &mut dyn BufRead) -> Result<u32, std::io::Error> {
     Ok(u32::from_le_bytes(read_bytes::<4>(reader)?))
 }
 
+/// Read a `u64` from a reader.
+pub fn read_u64(reader: &mut dyn BufRead) -> Result<u64, std::io::Error> {
+    Ok(u64::from_le_bytes(read_bytes::<8>(reader)?))
+}
+
 /// Read a `f32` from a reader.
 pub fn read_f32(reader: &mut dyn BufRead) -> Result<f32, std::io::Error> {
     Ok(f32::from_le_bytes(read_bytes::<4>(reader)?))
@@ -44,6 +49,11 @@ pub fn write_u32(writer: &mut dyn Write, value: u32) -> Result<(), std::io::Erro
     writer.write_all(&value.to_le_bytes())
 }
 
+/// Write a `u64` from a writer.
+pub fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), std::io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
 /// Write a `f32` from a writer.
 pub fn write_f32(writer: &mut dyn Write, value: f32) -> Result<(), std::io::Error> {
     writer.write_all(&value.to_le_bytes())
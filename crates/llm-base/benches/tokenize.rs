@@ -0,0 +1,104 @@
+//! Tokenization throughput on large documents.
+//!
+//! Token lookups go through a byte trie and are linear in the length of the text.
+//! Byte-level BPE merges each pretokenized piece (roughly, each word) separately,
+//! so it is linear in the length of text made of ordinary words. SentencePiece
+//! merges the whole text through a priority queue, which is `O(n log n)`: its
+//! throughput is expected to drop slightly, by a logarithmic factor, from the
+//! 64 KiB to the 1 MiB document. A larger drop than that is a regression.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use llm_base::{TokenId, TokenizerKind, Vocabulary};
+
+const WORDS: &str = "the of and to in is that for it as was with be by on not he this are or \
+    his from at which but have an had they you were their one all we can her has there been if \
+    more when will would who so no tokenizer vocabulary language model retrieval document \
+    benchmark performance";
+
+/// A document of roughly `len` bytes, made of words with and without vocabulary tokens.
+fn document(len: usize) -> String {
+    let mut text = String::with_capacity(len + 32);
+    let words: Vec<&str> = WORDS.split_whitespace().collect();
+    let mut index = 0usize;
+    while text.len() < len {
+        let word = words[index.wrapping_mul(7919) % words.len()];
+        text.push_str(word);
+        text.push_str(match index % 13 {
+            0 => ". ",
+            5 => ", ",
+            9 => "\n",
+            _ => " ",
+        });
+        if index % 17 == 0 {
+            text.push_str(&format!("{index} naïve 日本語 "));
+        }
+        index += 1;
+    }
+    text
+}
+
+/// A vocabulary with every prefix of every word, with and without a leading space,
+/// so that BPE can reach each word.
+fn vocabulary(tokenizer: TokenizerKind) -> Vocabulary {
+    let mut vocabulary = Vocabulary {
+        tokenizer,
+        ..Default::default()
+    };
+    let push = |vocabulary: &mut Vocabulary, token: Vec<u8>, score: f32| {
+        if !vocabulary.token_to_id.contains_key(&token) {
+            let id = vocabulary.id_to_token.len() as TokenId;
+            vocabulary.push_token(id, token, score);
+        }
+    };
+
+    let space = match tokenizer {
+        TokenizerKind::SentencePiece => {
+            for control in ["<unk>", "<s>", "</s>"] {
+                push(&mut vocabulary, control.as_bytes().to_vec(), 0.0);
+            }
+            for byte in 0..=255u8 {
+                push(&mut vocabulary, format!("<0x{byte:02X}>").into_bytes(), 0.0);
+            }
+            "\u{2581}"
+        }
+        TokenizerKind::ByteLevelBpe => {
+            for byte in 0..=255u8 {
+                push(&mut vocabulary, vec![byte], 0.0);
+            }
+            " "
+        }
+    };
+    push(&mut vocabulary, space.as_bytes().to_vec(), 0.0);
+
+    for word in WORDS.split_whitespace() {
+        for word in [word.to_string(), format!("{space}{word}")] {
+            for (end, _) in word.char_indices().skip(1).chain([(word.len(), ' ')]) {
+                let score = -(vocabulary.id_to_token.len() as f32);
+                push(&mut vocabulary, word.as_bytes()[..end].to_vec(), score);
+            }
+        }
+    }
+    vocabulary
+}
+
+fn tokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenize");
+    group.sample_size(10);
+    for (name, tokenizer) in [
+        ("sentencepiece", TokenizerKind::SentencePiece),
+        ("byte_level_bpe", TokenizerKind::ByteLevelBpe),
+    ] {
+        let vocabulary = vocabulary(tokenizer);
+        for len in [64 * 1024, 1024 * 1024] {
+            let text = document(len);
+            group.throughput(Throughput::Bytes(text.len() as u64));
+            group.bench_with_input(BenchmarkId::new(name, len), &text, |b, text| {
+                b.iter(|| vocabulary.tokenize(text, false).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tokenize);
+criterion_main!(benches);
@@ -0,0 +1,211 @@
+//! Byte-level BPE, as used by GPT-2 and the models derived from its tokenizer.
+
+use std::collections::HashMap;
+
+use crate::vocabulary::Token;
+
+/// The ranks of the merges of a byte-level BPE vocabulary, keyed by the pair of
+/// tokens that are merged. Lower ranks are merged first.
+pub(crate) type Merges = HashMap<(Token, Token), usize>;
+
+/// Splits `text` into the pieces that are tokenized separately, following GPT-2's
+/// pre-tokenization pattern:
+///
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+`
+pub(crate) fn pretokenize(text: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+
+    let mut pieces = vec![];
+    let mut start = 0;
+    while start < chars.len() {
+        let end = match_piece(&chars, start);
+        let end_byte = chars.get(end).map_or(text.len(), |(byte, _)| *byte);
+        pieces.push(&text[chars[start].0..end_byte]);
+        start = end;
+    }
+    pieces
+}
+
+/// Returns the end of the piece that starts at `start`, as an index into `chars`.
+fn match_piece(chars: &[(usize, char)], start: usize) -> usize {
+    let char_at = |index: usize| chars.get(index).map(|(_, c)| *c);
+
+    if char_at(start) == Some('\'') {
+        for suffix in ["s", "t", "re", "ve", "m", "ll", "d"] {
+            if suffix
+                .chars()
+                .enumerate()
+                .all(|(i, c)| char_at(start + 1 + i) == Some(c))
+            {
+                return start + 1 + suffix.len();
+            }
+        }
+    }
+
+    // A run of letters, numbers or other symbols, optionally preceded by a space.
+    let run_start = if char_at(start) == Some(' ') {
+        start + 1
+    } else {
+        start
+    };
+    if let Some(first) = char_at(run_start) {
+        for class in [is_letter, is_number, is_symbol] {
+            if class(first) {
+                let mut end = run_start + 1;
+                while char_at(end).map_or(false, class) {
+                    end += 1;
+                }
+                return end;
+            }
+        }
+    }
+
+    // A run of whitespace. If it is followed by something else, its last character
+    // is left to start the next piece, so that words keep their leading space.
+    let mut end = start;
+    while char_at(end).map_or(false, char::is_whitespace) {
+        end += 1;
+    }
+    if end > start + 1 && char_at(end).is_some() {
+        end - 1
+    } else {
+        end
+    }
+}
+
+fn is_letter(c: char) -> bool {
+    c.is_alphabetic()
+}
+
+fn is_number(c: char) -> bool {
+    c.is_numeric()
+}
+
+fn is_symbol(c: char) -> bool {
+    !c.is_whitespace() && !c.is_alphabetic() && !c.is_numeric()
+}
+
+/// Merges the bytes of `piece` into tokens, always merging the adjacent pair with
+/// the lowest rank first. Returns the `(start, len)` byte ranges of the tokens.
+pub(crate) fn merge(
+    piece: &[u8],
+    rank: impl Fn(&[u8], &[u8]) -> Option<usize>,
+) -> Vec<(usize, usize)> {
+    let mut parts: Vec<(usize, usize)> = (0..piece.len()).map(|i| (i, 1)).collect();
+
+    loop {
+        let best = parts
+            .windows(2)
+            .enumerate()
+            .filter_map(|(i, pair)| {
+                let (left, right) = (pair[0], pair[1]);
+                rank(
+                    &piece[left.0..left.0 + left.1],
+                    &piece[right.0..right.0 + right.1],
+                )
+                .map(|rank| (rank, i))
+            })
+            .min();
+
+        match best {
+            Some((_, i)) => {
+                parts[i].1 += parts[i + 1].1;
+                parts.remove(i + 1);
+            }
+            None => return parts,
+        }
+    }
+}
+
+/// Parses the contents of a `merges.txt` file, in which each line is a pair of
+/// tokens written with [byte_to_unicode]. Returns the 1-based line number of the
+/// first invalid line on failure.
+pub(crate) fn parse_merges(contents: &str) -> Result<Merges, usize> {
+    let mapping = byte_to_unicode();
+    let mut merges = Merges::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.starts_with("#version") || line.trim().is_empty() {
+            continue;
+        }
+
+        let pair = line.split_once(' ').and_then(|(left, right)| {
+            Some((
+                unicode_to_bytes(left, &mapping)?,
+                unicode_to_bytes(right, &mapping)?,
+            ))
+        });
+        match pair {
+            Some(pair) => {
+                let rank = merges.len();
+                merges.entry(pair).or_insert(rank);
+            }
+            None => return Err(index + 1),
+        }
+    }
+    Ok(merges)
+}
+
+/// The mapping GPT-2 uses to represent each byte with a printable character:
+/// printable bytes represent themselves, and the others are shifted past 255.
+pub(crate) fn byte_to_unicode() -> [char; 256] {
+    let is_printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+
+    let mut mapping = ['\0'; 256];
+    let mut shifted = 0;
+    for byte in 0..=255u8 {
+        mapping[byte as usize] = if is_printable(byte) {
+            char::from(byte)
+        } else {
+            shifted += 1;
+            char::from_u32(255 + shifted).unwrap()
+        };
+    }
+    mapping
+}
+
+/// Converts a token written with `mapping` back to its bytes.
+pub(crate) fn unicode_to_bytes(text: &str, mapping: &[char; 256]) -> Option<Token> {
+    text.chars()
+        .map(|c| mapping.iter().position(|&m| m == c).map(|b| b as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretokenize() {
+        assert_eq!(
+            pretokenize("Hello world! It's 2023,  ok?\n\nBye"),
+            [
+                "Hello", " world", "!", " It", "'s", " 2023", ",", " ", " ok", "?", "\n", "\n",
+                "Bye"
+            ]
+        );
+        assert_eq!(pretokenize("  trailing  "), [" ", " trailing", "  "]);
+        assert!(pretokenize("").is_empty());
+    }
+
+    #[test]
+    fn test_byte_to_unicode() {
+        let mapping = byte_to_unicode();
+        assert_eq!(mapping[b'a' as usize], 'a');
+        assert_eq!(mapping[b' ' as usize], '\u{120}');
+        assert_eq!(mapping[b'\n' as usize], '\u{10A}');
+        assert_eq!(
+            unicode_to_bytes("\u{120}the", &mapping),
+            Some(b" the".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let merges = parse_merges("#version: 0.2\nh e\nl l\nhe ll\nhell o\n").unwrap();
+        let rank = |a: &[u8], b: &[u8]| merges.get(&(a.to_vec(), b.to_vec())).copied();
+
+        assert_eq!(merge(b"hello", rank), [(0, 5)]);
+        assert_eq!(merge(b"hey", rank), [(0, 2), (2, 1)]);
+        assert_eq!(parse_merges("a b\nnot-a-pair\n"), Err(2));
+    }
+}
@@ -0,0 +1,625 @@
+//! Structured conversations, and the chat templates used to turn them into prompts.
+
+use std::{
+    convert::Infallible,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    InferenceError, InferenceParameters, InferenceRequest, InferenceSession, InferenceStats, Model,
+    OutputRequest,
+};
+
+/// The placeholder that the content of a message is substituted into in a
+/// [ChatTemplate].
+pub const MESSAGE_PLACEHOLDER: &str = "{{MESSAGE}}";
+
+/// The author of a [Message].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Instructions for the assistant, usually given once at the start.
+    System,
+    /// The person talking to the assistant.
+    User,
+    /// The model.
+    Assistant,
+}
+
+/// A message in a [Conversation].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The author of the message.
+    pub role: Role,
+    /// The text of the message.
+    pub content: String,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while loading a [ChatTemplate].
+pub enum ChatTemplateError {
+    #[error("could not open file {path:?}")]
+    /// The file failed to open.
+    OpenFileFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("invalid chat template: {0}")]
+    /// The template could not be parsed.
+    Invalid(#[from] serde_json::Error),
+    #[error("the {role:?} template does not contain {MESSAGE_PLACEHOLDER}")]
+    /// The template for a role has nowhere to put the content of the message.
+    MissingPlaceholder {
+        /// The role whose template is invalid.
+        role: Role,
+    },
+}
+
+/// How the messages of a [Conversation] are written in the prompt, following
+/// the format that a model was fine-tuned on.
+///
+/// Each role has a template in which [MESSAGE_PLACEHOLDER] is replaced by the
+/// content of the message. The text of the assistant's template that comes before
+/// the placeholder starts the assistant's reply.
+///
+/// User-defined templates can be loaded from JSON files with [ChatTemplate::load]:
+///
+/// ```json
+/// {
+///     "system": "{{MESSAGE}}\n\n",
+///     "user": "### Instruction:\n{{MESSAGE}}\n\n",
+///     "assistant": "### Response:\n{{MESSAGE}}\n\n",
+///     "stop_sequences": ["### Instruction:"]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ChatTemplate {
+    /// The template for system messages.
+    pub system: String,
+    /// The template for user messages.
+    pub user: String,
+    /// The template for a user message that directly follows a system message,
+    /// for formats that combine the two. If not specified, [Self::user] is used.
+    #[serde(default)]
+    pub user_after_system: Option<String>,
+    /// The template for assistant messages.
+    pub assistant: String,
+    /// The strings that end the assistant's reply when generated.
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+}
+impl ChatTemplate {
+    /// The names of the built-in templates, as accepted by [ChatTemplate::builtin].
+    pub const BUILTIN_NAMES: &'static [&'static str] = &["alpaca", "vicuna", "chatml", "llama2"];
+
+    /// The built-in template called `name`, if there is one.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "alpaca" => Some(Self::alpaca()),
+            "vicuna" => Some(Self::vicuna()),
+            "chatml" => Some(Self::chatml()),
+            "llama2" => Some(Self::llama2()),
+            _ => None,
+        }
+    }
+
+    /// The instruction format used by Alpaca.
+    pub fn alpaca() -> Self {
+        Self {
+            system: "{{MESSAGE}}\n\n".to_string(),
+            user: "### Instruction:\n{{MESSAGE}}\n\n".to_string(),
+            user_after_system: None,
+            assistant: "### Response:\n{{MESSAGE}}\n\n".to_string(),
+            stop_sequences: vec!["### Instruction:".to_string()],
+        }
+    }
+
+    /// The conversation format used by Vicuna v1.1 and later.
+    pub fn vicuna() -> Self {
+        Self {
+            system: "{{MESSAGE}}\n\n".to_string(),
+            user: "USER: {{MESSAGE}}\n".to_string(),
+            user_after_system: None,
+            assistant: "ASSISTANT: {{MESSAGE}}\n".to_string(),
+            stop_sequences: vec!["USER:".to_string()],
+        }
+    }
+
+    /// OpenAI's ChatML, as used by many fine-tunes.
+    pub fn chatml() -> Self {
+        Self {
+            system: "<|im_start|>system\n{{MESSAGE}}<|im_end|>\n".to_string(),
+            user: "<|im_start|>user\n{{MESSAGE}}<|im_end|>\n".to_string(),
+            user_after_system: None,
+            assistant: "<|im_start|>assistant\n{{MESSAGE}}<|im_end|>\n".to_string(),
+            stop_sequences: vec!["<|im_end|>".to_string()],
+        }
+    }
+
+    /// The format used by Llama 2's chat models, in which the system message is
+    /// part of the first instruction.
+    pub fn llama2() -> Self {
+        Self {
+            system: "[INST] <<SYS>>\n{{MESSAGE}}\n<</SYS>>\n\n".to_string(),
+            user: "[INST] {{MESSAGE}} [/INST]".to_string(),
+            user_after_system: Some("{{MESSAGE}} [/INST]".to_string()),
+            assistant: " {{MESSAGE}} </s>".to_string(),
+            stop_sequences: vec!["</s>".to_string(), "[INST]".to_string()],
+        }
+    }
+
+    /// Parses a template from JSON.
+    pub fn from_json(json: &str) -> Result<Self, ChatTemplateError> {
+        let template: Self = serde_json::from_str(json)?;
+        let templates = [
+            (Role::System, Some(&template.system)),
+            (Role::User, Some(&template.user)),
+            (Role::User, template.user_after_system.as_ref()),
+            (Role::Assistant, Some(&template.assistant)),
+        ];
+        for (role, text) in templates {
+            if text.map_or(false, |text| !text.contains(MESSAGE_PLACEHOLDER)) {
+                return Err(ChatTemplateError::MissingPlaceholder { role });
+            }
+        }
+        Ok(template)
+    }
+
+    /// Loads a template from the JSON file at `path`.
+    pub fn load(path: &Path) -> Result<Self, ChatTemplateError> {
+        let json =
+            std::fs::read_to_string(path).map_err(|e| ChatTemplateError::OpenFileFailed {
+                source: e,
+                path: path.to_owned(),
+            })?;
+        Self::from_json(&json)
+    }
+
+    /// Writes `message`, which follows a message from `previous` if there is one.
+    pub fn render_message(&self, message: &Message, previous: Option<Role>) -> String {
+        let template = match (message.role, previous) {
+            (Role::System, _) => &self.system,
+            (Role::User, Some(Role::System)) => {
+                self.user_after_system.as_ref().unwrap_or(&self.user)
+            }
+            (Role::User, _) => &self.user,
+            (Role::Assistant, _) => &self.assistant,
+        };
+        template.replace(MESSAGE_PLACEHOLDER, &message.content)
+    }
+
+    /// The text that starts the assistant's reply.
+    pub fn reply_prefix(&self) -> &str {
+        self.assistant
+            .split(MESSAGE_PLACEHOLDER)
+            .next()
+            .unwrap_or_default()
+    }
+}
+
+/// How a [Conversation] is kept inside the context window of the model once it
+/// has grown too long for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextStrategy {
+    /// Nothing is done, and feeding the conversation fails with
+    /// [InferenceError::ContextFull].
+    #[default]
+    None,
+    /// The oldest turns are dropped, keeping the system prompt.
+    DropOldest,
+    /// The oldest turns are replaced by a summary that the model writes of them,
+    /// which is kept as a system message after the system prompt. The summary is
+    /// rewritten to include the turns that are dropped each time.
+    Summarize,
+}
+
+/// The text that a summary written for [ContextStrategy::Summarize] starts with.
+const SUMMARY_PREFIX: &str = "Summary of the conversation so far: ";
+/// The request the model is given to write a summary for [ContextStrategy::Summarize].
+const SUMMARY_REQUEST: &str = "Summarize the following conversation in a few sentences, \
+    keeping any details that are needed to continue it.";
+/// The maximum number of tokens of a summary.
+const SUMMARY_MAX_TOKENS: usize = 256;
+
+/// A conversation between a user and an assistant, which is written into prompts
+/// with a [ChatTemplate].
+///
+/// As every message is written with the same template, the prompt for each reply
+/// is a continuation of the prompt for the previous one, so a session can be fed
+/// one message at a time with [Conversation::render_message], or with
+/// [Conversation::feed] and [Conversation::reply], which also keep the conversation
+/// inside the context window with its [ContextStrategy].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conversation {
+    template: ChatTemplate,
+    messages: Vec<Message>,
+    context_strategy: ContextStrategy,
+    /// The position in the session of each message that has been fed to it.
+    offsets: Vec<usize>,
+    /// Whether the message after the system prompt is a summary written for
+    /// [ContextStrategy::Summarize].
+    has_summary: bool,
+}
+impl Conversation {
+    /// Creates an empty conversation that is written with `template`.
+    pub fn new(template: ChatTemplate) -> Self {
+        Self {
+            template,
+            messages: vec![],
+            context_strategy: ContextStrategy::default(),
+            offsets: vec![],
+            has_summary: false,
+        }
+    }
+
+    /// Sets how this conversation is kept inside the context window.
+    pub fn with_context_strategy(mut self, context_strategy: ContextStrategy) -> Self {
+        self.context_strategy = context_strategy;
+        self
+    }
+
+    /// The template this conversation is written with.
+    pub fn template(&self) -> &ChatTemplate {
+        &self.template
+    }
+
+    /// How this conversation is kept inside the context window.
+    pub fn context_strategy(&self) -> ContextStrategy {
+        self.context_strategy
+    }
+
+    /// The messages of this conversation, from first to last.
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Adds a message to the end of the conversation.
+    pub fn push(&mut self, role: Role, content: impl Into<String>) {
+        self.messages.push(Message {
+            role,
+            content: content.into(),
+        });
+    }
+
+    /// Writes the message at `index` with the template of this conversation.
+    ///
+    /// # Panics
+    /// If there is no message at `index`.
+    pub fn render_message(&self, index: usize) -> String {
+        let previous = index
+            .checked_sub(1)
+            .map(|previous| self.messages[previous].role);
+        self.template
+            .render_message(&self.messages[index], previous)
+    }
+
+    /// Writes the whole conversation, followed by the start of the assistant's
+    /// reply. This is the prompt for the next reply.
+    pub fn render(&self) -> String {
+        let mut prompt: String = (0..self.messages.len())
+            .map(|index| self.render_message(index))
+            .collect();
+        prompt.push_str(self.template.reply_prefix());
+        prompt
+    }
+
+    /// Feeds the messages that have not been fed yet to `session`, leaving room
+    /// for at least `reserved_tokens` more tokens in the context window.
+    ///
+    /// If they do not fit, room is made with the [ContextStrategy] of this
+    /// conversation. The key/value memory of the system prompt is kept, so only
+    /// the messages after it that are kept have to be evaluated again.
+    ///
+    /// A conversation keeps track of where its messages are in the session, so it
+    /// must always be fed to the same session, and nothing else should be fed to
+    /// that session in between.
+    pub fn feed(
+        &mut self,
+        session: &mut InferenceSession,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+        reserved_tokens: usize,
+    ) -> Result<(), InferenceError> {
+        let mut needed = reserved_tokens + usize::from(session.n_past == 0);
+        for index in self.offsets.len()..self.messages.len() {
+            let text = self.render_message(index);
+            needed += model.vocabulary().tokenize(&text, false)?.len();
+        }
+        if session.n_past + needed >= model.n_context_tokens() {
+            self.make_room(session, model, params, rng, needed)?;
+        }
+
+        for index in self.offsets.len()..self.messages.len() {
+            let offset = session.n_past;
+            self.offsets.push(offset);
+            if let Err(e) = session.feed_prompt::<Infallible>(
+                model,
+                params,
+                &self.render_message(index),
+                &mut OutputRequest::default(),
+                |_| Ok(()),
+            ) {
+                self.offsets.pop();
+                session.truncate(offset);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates the assistant's reply to this conversation, and adds it to the
+    /// conversation. The text of the reply is passed to `callback` as it is
+    /// generated.
+    ///
+    /// The messages that have not been fed to `session` yet are fed first with
+    /// [Self::feed], leaving room for up to `maximum_token_count` tokens of reply,
+    /// or a quarter of the context window. Once the reply has been generated, the
+    /// session is truncated to before it, and the reply is fed to the session as it
+    /// is written by the template, so that the session holds the conversation
+    /// exactly as the template writes it, regardless of how generation ended. The
+    /// key/value memory of the conversation before the reply is kept throughout.
+    pub fn reply<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        session: &mut InferenceSession,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+        maximum_token_count: Option<usize>,
+        mut callback: impl FnMut(&str) -> Result<(), E>,
+    ) -> Result<InferenceStats, InferenceError> {
+        let reserved_tokens = maximum_token_count
+            .unwrap_or(usize::MAX)
+            .min(model.n_context_tokens() / 4);
+        self.feed(session, model, params, rng, reserved_tokens)?;
+
+        let offset = session.n_past;
+        let mut reply = String::new();
+        let result = session.infer(
+            model,
+            rng,
+            &InferenceRequest {
+                prompt: self.template.reply_prefix(),
+                parameters: Some(params),
+                maximum_token_count,
+                stop_sequences: &self.template.stop_sequences,
+                ..Default::default()
+            },
+            &mut OutputRequest::default(),
+            |text| {
+                reply.push_str(text);
+                callback(text)
+            },
+        );
+        session.truncate(offset);
+        let stats = result?;
+
+        self.push(Role::Assistant, reply.trim());
+        self.feed(session, model, params, rng, 0)?;
+        Ok(stats)
+    }
+
+    /// Removes the oldest turns from the conversation and the session, so that
+    /// `needed` more tokens fit in the context window, and writes a summary of
+    /// them if the strategy calls for it.
+    fn make_room(
+        &mut self,
+        session: &mut InferenceSession,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+        needed: usize,
+    ) -> Result<(), InferenceError> {
+        if self.context_strategy == ContextStrategy::None {
+            return Ok(());
+        }
+        let range = match self.drop_range(session.n_past, needed, model.n_context_tokens()) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        let offset = self.offsets[range.start];
+        let dropped: Vec<Message> = self.messages.drain(range.clone()).collect();
+        self.offsets.truncate(range.start);
+        self.has_summary = false;
+        session.truncate(offset);
+
+        if self.context_strategy == ContextStrategy::Summarize {
+            // If the summary cannot be written, the turns are dropped instead.
+            match self.summarize(session, model, params, rng, &dropped) {
+                Ok(summary) => {
+                    self.messages.insert(
+                        range.start,
+                        Message {
+                            role: Role::System,
+                            content: format!("{SUMMARY_PREFIX}{summary}"),
+                        },
+                    );
+                    self.has_summary = true;
+                }
+                Err(InferenceError::ContextFull) => {}
+                Err(e) => return Err(e),
+            }
+            session.truncate(offset);
+        }
+        Ok(())
+    }
+
+    /// The range of messages to drop so that `needed` more tokens fit in a context
+    /// window of `n_context_tokens`, if any can be dropped.
+    ///
+    /// Messages are dropped until half of the context window is free, so that this
+    /// does not have to be done again for every message. Only messages that have
+    /// been fed can be dropped, and whole turns are dropped, so that the kept
+    /// messages start with a message from the user.
+    fn drop_range(
+        &self,
+        n_past: usize,
+        needed: usize,
+        n_context_tokens: usize,
+    ) -> Option<Range<usize>> {
+        let start = self.system_prompt_len();
+        let message_len = |index: usize| {
+            self.offsets.get(index + 1).copied().unwrap_or(n_past) - self.offsets[index]
+        };
+
+        let mut used = n_past + needed;
+        let mut end = start;
+        while end < self.offsets.len()
+            && (used > n_context_tokens / 2 || self.messages[end].role == Role::Assistant)
+        {
+            used -= message_len(end);
+            end += 1;
+        }
+        (end > start).then_some(start..end)
+    }
+
+    /// Has the model write a summary of `dropped`, following the system prompt
+    /// that `session` holds, and returns it.
+    fn summarize(
+        &self,
+        session: &mut InferenceSession,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+        dropped: &[Message],
+    ) -> Result<String, InferenceError> {
+        let transcript: String = dropped
+            .iter()
+            .map(|message| format!("{:?}: {}\n", message.role, message.content))
+            .collect();
+        let request = Message {
+            role: Role::User,
+            content: format!("{SUMMARY_REQUEST}\n\n{transcript}"),
+        };
+        let previous = self
+            .offsets
+            .len()
+            .checked_sub(1)
+            .map(|index| self.messages[index].role);
+        let mut prompt = self.template.render_message(&request, previous);
+        prompt.push_str(self.template.reply_prefix());
+
+        let mut summary = String::new();
+        session.infer::<Infallible>(
+            model,
+            rng,
+            &InferenceRequest {
+                prompt: &prompt,
+                parameters: Some(params),
+                maximum_token_count: Some(SUMMARY_MAX_TOKENS),
+                stop_sequences: &self.template.stop_sequences,
+                ..Default::default()
+            },
+            &mut OutputRequest::default(),
+            |text| {
+                summary.push_str(text);
+                Ok(())
+            },
+        )?;
+        Ok(summary.trim().to_string())
+    }
+
+    /// The number of messages at the start of the conversation that are never
+    /// dropped: the system messages before the first turn, without the summary.
+    fn system_prompt_len(&self) -> usize {
+        let system_messages = self
+            .messages
+            .iter()
+            .take_while(|message| message.role == Role::System)
+            .count();
+        system_messages - usize::from(self.has_summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut conversation = Conversation::new(ChatTemplate::chatml());
+        conversation.push(Role::System, "Be brief.");
+        conversation.push(Role::User, "Hi!");
+        assert_eq!(
+            conversation.render(),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi!<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+
+        conversation.push(Role::Assistant, "Hello.");
+        assert_eq!(
+            conversation.render_message(2),
+            "<|im_start|>assistant\nHello.<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_llama2() {
+        let mut conversation = Conversation::new(ChatTemplate::llama2());
+        conversation.push(Role::System, "Be brief.");
+        conversation.push(Role::User, "Hi!");
+        conversation.push(Role::Assistant, "Hello.");
+        conversation.push(Role::User, "Bye!");
+        assert_eq!(
+            conversation.render(),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi! [/INST] Hello. </s>[INST] Bye! [/INST] "
+        );
+    }
+
+    #[test]
+    fn test_drop_range() {
+        let mut conversation = Conversation::new(ChatTemplate::alpaca())
+            .with_context_strategy(ContextStrategy::DropOldest);
+        conversation.push(Role::System, "Be brief.");
+        for (role, content) in [
+            (Role::User, "Hi!"),
+            (Role::Assistant, "Hello."),
+            (Role::User, "How are you?"),
+            (Role::Assistant, "Fine."),
+        ] {
+            conversation.push(role, content);
+        }
+        conversation.offsets = vec![0, 10, 40, 60, 70];
+
+        // Whole turns are dropped until half of the context is free.
+        assert_eq!(conversation.drop_range(90, 10, 100), Some(1..3));
+        assert_eq!(conversation.drop_range(90, 30, 100), Some(1..5));
+        assert_eq!(conversation.drop_range(20, 10, 100), None);
+
+        // The summary is dropped along with the turns, to be rewritten.
+        conversation.messages[1].role = Role::System;
+        conversation.has_summary = true;
+        assert_eq!(conversation.drop_range(90, 10, 100), Some(1..3));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let template = ChatTemplate::from_json(
+            r####"{
+                "system": "{{MESSAGE}}\n\n",
+                "user": "### Instruction:\n{{MESSAGE}}\n\n",
+                "assistant": "### Response:\n{{MESSAGE}}\n\n",
+                "stop_sequences": ["### Instruction:"]
+            }"####,
+        )
+        .unwrap();
+        assert_eq!(template, ChatTemplate::alpaca());
+
+        assert!(matches!(
+            ChatTemplate::from_json(
+                r#"{"system": "", "user": "{{MESSAGE}}", "assistant": "{{MESSAGE}}"}"#
+            ),
+            Err(ChatTemplateError::MissingPlaceholder { role: Role::System })
+        ));
+        for name in ChatTemplate::BUILTIN_NAMES {
+            assert!(ChatTemplate::builtin(name).is_some());
+        }
+    }
+}
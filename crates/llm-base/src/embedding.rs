@@ -0,0 +1,122 @@
+use crate::{
+    InferenceError, InferenceParameters, InferenceSessionConfig, Model, OutputRequest, TokenId,
+};
+
+/// How the per-token hidden states of a text are combined into a single embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum Pooling {
+    /// The mean of the hidden states of all tokens.
+    #[default]
+    Mean,
+    /// The hidden state of the last token. This is the only token that has
+    /// attended to the entire text in a causal model.
+    LastToken,
+    /// The hidden state of the first token. With models that prepend a beginning
+    /// of sentence token, this is the hidden state of that token.
+    Cls,
+}
+
+/// The parameters for computing an embedding with [embed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub struct EmbeddingParameters {
+    /// How the per-token hidden states are combined.
+    pub pooling: Pooling,
+    /// Whether to scale the embedding to unit length, so that the dot product of
+    /// two embeddings is their cosine similarity.
+    pub normalize: bool,
+}
+
+/// Computes an embedding of `text` from the final-layer hidden states of `model`.
+///
+/// The whole text is evaluated in a single session, so it must fit within the
+/// model's context; if it does not, [InferenceError::ContextFull] is returned.
+pub fn embed(
+    model: &dyn Model,
+    params: &InferenceParameters,
+    session_config: InferenceSessionConfig,
+    embedding_params: &EmbeddingParameters,
+    text: &str,
+) -> Result<Vec<f32>, InferenceError> {
+    let tokens: Vec<TokenId> = model
+        .vocabulary()
+        .tokenize(text, true)?
+        .iter()
+        .map(|(_, tok)| *tok)
+        .collect();
+    if tokens.is_empty() {
+        return Err(InferenceError::TokenizationFailed);
+    }
+    if tokens.len() > model.n_context_tokens() {
+        return Err(InferenceError::ContextFull);
+    }
+
+    let mut session = model.start_session(session_config);
+    let mut output_request = OutputRequest {
+        embeddings: Some(vec![]),
+        ..Default::default()
+    };
+
+    let mut hidden_states = vec![];
+    for batch in tokens.chunks(params.n_batch) {
+        model.evaluate(&mut session, params, batch, &mut output_request);
+        hidden_states.extend_from_slice(output_request.embeddings.as_deref().unwrap_or_default());
+    }
+
+    let n_embd = hidden_states.len() / tokens.len();
+    let mut embedding = pool(&hidden_states, n_embd, embedding_params.pooling);
+    if embedding_params.normalize {
+        normalize(&mut embedding);
+    }
+    Ok(embedding)
+}
+
+/// Combines the `n_embd`-sized hidden states of each token into one vector.
+pub(crate) fn pool(hidden_states: &[f32], n_embd: usize, pooling: Pooling) -> Vec<f32> {
+    let n_tokens = hidden_states.len() / n_embd;
+    match pooling {
+        Pooling::Mean => {
+            let mut mean = vec![0.0; n_embd];
+            for token in hidden_states.chunks(n_embd) {
+                for (m, h) in mean.iter_mut().zip(token) {
+                    *m += h;
+                }
+            }
+            mean.iter_mut().for_each(|m| *m /= n_tokens as f32);
+            mean
+        }
+        Pooling::LastToken => hidden_states[(n_tokens - 1) * n_embd..].to_vec(),
+        Pooling::Cls => hidden_states[..n_embd].to_vec(),
+    }
+}
+
+/// Scales `embedding` to unit length. Zero vectors are left unchanged.
+pub(crate) fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|e| e * e).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|e| *e /= norm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let hidden_states = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        assert_eq!(pool(&hidden_states, 2, Pooling::Mean), vec![3.0, 4.0]);
+        assert_eq!(pool(&hidden_states, 2, Pooling::LastToken), vec![5.0, 6.0]);
+        assert_eq!(pool(&hidden_states, 2, Pooling::Cls), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut embedding = [3.0, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, [0.6, 0.8]);
+
+        let mut embedding = [0.0, 0.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, [0.0, 0.0]);
+    }
+}
@@ -1,18 +1,31 @@
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use partial_sort::PartialSort;
 use rand::{distributions::WeightedIndex, prelude::Distribution};
 use thiserror::Error;
 
 use crate::{
-    mulf, InferenceError, InferenceParameters, Model, OutputRequest, TokenId, TokenUtf8Buffer,
+    mulf, perplexity::log_softmax, InferenceError, InferenceParameters, Model, OutputRequest,
+    TokenDecoder, TokenId, Vocabulary,
 };
 
 // The size of a scratch buffer used for inference. This is used for temporary
 // storage of intermediate results during inference.
 //
 // The specific value was copied from `llama.cpp`.
-const SCRATCH_SIZE: usize = 512 * 1024 * 1024;
+pub(crate) const SCRATCH_SIZE: usize = 512 * 1024 * 1024;
+
+// The number of scratch buffers used by each session.
+pub(crate) const SCRATCH_BUFFER_COUNT: usize = 2;
 
 /// An inference session represents the state of the text generation. This holds
 /// the full context window, as well as several additional parameters used
@@ -51,6 +64,11 @@ pub struct InferenceSession {
     #[doc(hidden)]
     pub mem_per_token: usize,
 
+    /// The largest amount of memory used by the temporary context of an
+    /// evaluation since this was last reset.
+    #[doc(hidden)]
+    pub peak_eval_memory: usize,
+
     /// All tokens generated by this inference session
     pub(crate) tokens: Vec<TokenId>,
 
@@ -63,41 +81,87 @@ pub struct InferenceSession {
     /// The number of scratch buffers was copied from `llama.cpp`.
     /// There is no specific reason for this number, but one is insufficient.
     #[doc(hidden)]
-    pub scratch: [ggml::Buffer; 2],
+    pub scratch: [ggml::Buffer; SCRATCH_BUFFER_COUNT],
 }
 unsafe impl Send for InferenceSession {}
 impl InferenceSession {
     /// Feed a prompt to the model for this session.
-    pub fn feed_prompt<E: std::error::Error + 'static>(
+    pub fn feed_prompt<E: std::error::Error + Send + Sync + 'static>(
         &mut self,
         model: &dyn Model,
         params: &InferenceParameters,
         prompt: &str,
         output_request: &mut OutputRequest,
-        mut callback: impl FnMut(&[u8]) -> Result<(), E>,
+        callback: impl FnMut(&[u8]) -> Result<(), E>,
     ) -> Result<(), InferenceError> {
+        let prompt_tokens = self.tokenize_prompt(model, params, prompt)?;
+        self.feed_prompt_tokens(
+            model,
+            params,
+            &prompt_tokens,
+            output_request,
+            &StopConditions::default(),
+            callback,
+        )
+    }
+
+    /// Tokenize a prompt to be fed to this session, starting it with the
+    /// beginning-of-string token if nothing has been fed yet.
+    fn tokenize_prompt(
+        &self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        prompt: &str,
+    ) -> Result<Vec<TokenId>, InferenceError> {
         let beginning_of_sentence = self.n_past == 0;
 
         let vocab = model.vocabulary();
-        let prompt_tokens: Vec<TokenId> = vocab
-            .tokenize(prompt, beginning_of_sentence)?
-            .iter()
-            .map(|(_, tok)| *tok)
-            .collect();
+        let prompt_tokens = if params.parse_special_tokens {
+            vocab.tokenize_with_special_tokens(prompt, beginning_of_sentence)?
+        } else {
+            vocab.tokenize(prompt, beginning_of_sentence)?
+        };
+        Ok(prompt_tokens.iter().map(|(_, tok)| *tok).collect())
+    }
 
+    /// Feed the tokens of a prompt to the model for this session, checking
+    /// `stop_conditions` before each batch is evaluated.
+    fn feed_prompt_tokens<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        prompt_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+        stop_conditions: &StopConditions,
+        mut callback: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), InferenceError> {
+        let vocab = model.vocabulary();
         if self.n_past + prompt_tokens.len() >= model.n_context_tokens() {
             return Err(InferenceError::ContextFull);
         }
 
-        for batch in prompt_tokens.chunks(params.n_batch) {
+        let mut tokens_fed = 0;
+        let mut remaining = prompt_tokens;
+        while !remaining.is_empty() {
+            stop_conditions.check(tokens_fed)?;
+
+            // The last batch is shortened so that the token budget is never exceeded.
+            let batch_len = params
+                .n_batch
+                .min(stop_conditions.remaining_budget(tokens_fed))
+                .min(remaining.len());
+            let (batch, rest) = remaining.split_at(batch_len);
+            remaining = rest;
+
             model.evaluate(self, params, batch, output_request);
+            tokens_fed += batch.len();
             for &tk in batch {
                 let should_call_callback = Some(tk) != model.bot_token_id();
 
                 if should_call_callback {
                     // NOTE: No string ever tokenizes to the end of sentence. So we
                     // can just return the id here.
-                    if let Err(e) = callback(vocab.token(tk as usize)) {
+                    if let Err(e) = callback(&vocab.decode_token(tk)) {
                         return Err(InferenceError::UserCallback(Box::new(e)));
                     }
                 }
@@ -111,13 +175,15 @@ impl InferenceSession {
     }
 
     /// Infer the next token for this session.
+    ///
+    /// Returns the bytes of the token, as decoded by [Vocabulary::decode_token](crate::Vocabulary::decode_token).
     pub fn infer_next_token<'v>(
         &mut self,
         model: &'v dyn Model,
         params: &InferenceParameters,
         output_request: &mut OutputRequest,
         rng: &mut impl rand::Rng,
-    ) -> Result<&'v [u8], InferenceError> {
+    ) -> Result<Cow<'v, [u8]>, InferenceError> {
         if self.n_past + 1 >= model.n_context_tokens() {
             return Err(InferenceError::ContextFull);
         }
@@ -135,18 +201,31 @@ impl InferenceSession {
         if next_token as TokenId == model.eot_token_id() {
             Err(InferenceError::EndOfText)
         } else {
-            Ok(model.vocabulary().token(next_token as usize))
+            Ok(model.vocabulary().decode_token(next_token))
         }
     }
 
     /// Generate text by using the provided [Model] to evaluate the `prompt`.
     ///
     /// The `callback` is called with each new token until an end-of-text (EOT)
-    /// token is encountered or the maximum number of tokens have been
-    /// generated (specified by [InferenceRequest::maximum_token_count]).
+    /// token is encountered, the maximum number of tokens have been
+    /// generated (specified by [InferenceRequest::maximum_token_count]), or
+    /// one of the [stop sequences](InferenceRequest::stop_sequences) is generated.
+    /// The reason generation stopped is reported in [InferenceStats::finish_reason].
+    ///
+    /// If the request's [cancellation token](InferenceRequest::cancellation_token)
+    /// is cancelled, or its [deadline](InferenceRequest::deadline) or
+    /// [token budget](InferenceRequest::token_budget) is exceeded, inference stops.
+    /// These are checked between batches of the prompt and between generated tokens;
+    /// the last batch of the prompt is shortened so that the token budget is never
+    /// exceeded.
+    /// While the prompt is being fed, this results in [InferenceError::Cancelled] or
+    /// [InferenceError::DeadlineExceeded]; afterwards, the tokens generated so far are
+    /// kept and the corresponding [FinishReason] is reported instead. Similarly, running
+    /// out of context while generating is reported as [FinishReason::ContextFull].
     ///
     /// This is a wrapper around [Self::feed_prompt] and [Self::infer_next_token].
-    pub fn infer<E: std::error::Error + 'static>(
+    pub fn infer<E: std::error::Error + Send + Sync + 'static>(
         &mut self,
         model: &dyn Model,
         rng: &mut impl rand::Rng,
@@ -155,16 +234,15 @@ impl InferenceSession {
         mut callback: impl FnMut(&str) -> Result<(), E>,
     ) -> Result<InferenceStats, InferenceError> {
         let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
+        // Decodes tokens to text, buffering them until they form complete characters.
+        let mut decoder = TokenDecoder::default();
         if request.play_back_previous_tokens {
             // "Play back" the existing tokens, so that loading from an inference snapshot works
             // as expected.
-            let mut token_utf8_buf = TokenUtf8Buffer::new();
-            for token_id in &self.tokens {
-                // Buffer the token until it's valid UTF-8, then call the callback.
-                if let Some(tokens) =
-                    token_utf8_buf.push(model.vocabulary().token(*token_id as usize))
-                {
-                    if let Err(e) = callback(&tokens) {
+            for &token_id in &self.tokens {
+                let text = decoder.push(model.vocabulary(), token_id);
+                if !text.is_empty() {
+                    if let Err(e) = callback(&text) {
                         return Err(InferenceError::UserCallback(Box::new(e)));
                     }
                 }
@@ -172,51 +250,242 @@ impl InferenceSession {
         }
 
         let mut stats = InferenceStats::default();
-        let start_at = std::time::SystemTime::now();
+        let start_at = Instant::now();
+        self.peak_eval_memory = 0;
 
         let parameters = request.parameters.unwrap_or(model.inference_parameters());
+        let stop_conditions = StopConditions::from(request);
+
+        // With token healing, the last tokens of the prompt are held back, and the
+        // model regenerates them (or something that starts with them) instead.
+        let mut prompt_tokens = self.tokenize_prompt(model, parameters, request.prompt)?;
+        let mut healing_prefix = vec![];
+        if request.token_healing > 0 {
+            // The first token of a session is kept, as there would be nothing to
+            // predict the next token from otherwise.
+            let keep = prompt_tokens
+                .len()
+                .saturating_sub(request.token_healing)
+                .max(usize::from(self.n_past == 0));
+            for token in prompt_tokens.drain(keep.min(prompt_tokens.len())..) {
+                healing_prefix.extend_from_slice(model.vocabulary().token(token as usize));
+            }
+        }
 
         // Feed the initial prompt through the transformer, to update its
         // context window with new data.
-        self.feed_prompt(
+        let n_past_before_prompt = self.n_past;
+        self.feed_prompt_tokens(
             model,
             parameters,
-            request.prompt,
+            &prompt_tokens,
             output_request,
-            TokenUtf8Buffer::adapt_callback(&mut callback),
+            &stop_conditions,
+            |token| {
+                let text = decoder.push_bytes(token);
+                if text.is_empty() {
+                    Ok(())
+                } else {
+                    callback(&text)
+                }
+            },
         )?;
-        stats.feed_prompt_duration = start_at.elapsed().unwrap();
-        stats.prompt_tokens = self.n_past;
+        stats.feed_prompt_duration = start_at.elapsed();
+        stats.prompt_tokens = self.n_past - n_past_before_prompt;
 
         // After the prompt is consumed, sample tokens by repeatedly calling
         // `infer_next_token`. We generate tokens until the model returns an
-        // EndOfText token, or we run out of space in the context window,
-        // or we reach the specified limit.
-        let mut tokens_processed = 0;
-        let mut token_utf8_buf = TokenUtf8Buffer::new();
-        while tokens_processed < maximum_token_count {
+        // EndOfText token, we run out of space in the context window, we
+        // encounter a stop sequence, or we reach the specified limit.
+        let predict_start_at = Instant::now();
+        let mut stop_sequence_buf = StopSequenceBuffer::new(request.stop_sequences);
+        stats.finish_reason = loop {
+            if stats.predict_tokens >= maximum_token_count {
+                break FinishReason::MaxTokens;
+            }
+            match stop_conditions.check(stats.prompt_tokens + stats.predict_tokens) {
+                Ok(()) => {}
+                Err(InferenceError::Cancelled) => break FinishReason::Cancelled,
+                Err(InferenceError::DeadlineExceeded) => break FinishReason::DeadlineExceeded,
+                Err(e) => return Err(e),
+            }
+
+            if !healing_prefix.is_empty() {
+                self.constrain_to_prefix(model.vocabulary(), &healing_prefix);
+            }
             let token = match self.infer_next_token(model, parameters, &mut Default::default(), rng)
             {
                 Ok(token) => token,
-                Err(InferenceError::EndOfText) => break,
+                Err(InferenceError::EndOfText) => break FinishReason::EndOfText,
+                Err(InferenceError::ContextFull) => break FinishReason::ContextFull,
                 Err(e) => return Err(e),
             };
+            stats.predict_tokens += 1;
+            if !healing_prefix.is_empty() {
+                let generated = self.tokens.last().copied().unwrap_or_default();
+                let generated_len = model.vocabulary().token(generated as usize).len();
+                healing_prefix.drain(..generated_len.min(healing_prefix.len()));
+            }
+            if stats.time_to_first_token.is_none() {
+                stats.time_to_first_token = Some(start_at.elapsed());
+            }
 
-            // Buffer the token until it's valid UTF-8, then call the callback.
-            if let Some(tokens) = token_utf8_buf.push(token) {
-                if let Err(e) = callback(&tokens) {
+            // Buffer the token until it forms complete characters, and hold back
+            // any text that may be the start of a stop sequence, then call the callback.
+            let text = decoder.push_bytes(&token);
+            if !text.is_empty() {
+                let (text, stopped) = stop_sequence_buf.push(&text);
+                if !text.is_empty() {
+                    if let Err(e) = callback(&text) {
+                        return Err(InferenceError::UserCallback(Box::new(e)));
+                    }
+                }
+                if stopped {
+                    break FinishReason::StopSequence;
+                }
+            }
+        };
+        if stats.finish_reason != FinishReason::StopSequence {
+            let mut text = stop_sequence_buf.flush();
+            text.push_str(&decoder.flush());
+            if !text.is_empty() {
+                if let Err(e) = callback(&text) {
                     return Err(InferenceError::UserCallback(Box::new(e)));
                 }
             }
-
-            tokens_processed += 1;
         }
-        stats.predict_duration = start_at.elapsed().unwrap();
-        stats.predict_tokens = self.n_past;
+        stats.predict_duration = predict_start_at.elapsed();
+        stats.prompt_tokens_per_second =
+            tokens_per_second(stats.prompt_tokens, stats.feed_prompt_duration);
+        stats.predict_tokens_per_second =
+            tokens_per_second(stats.predict_tokens, stats.predict_duration);
+        stats.peak_eval_memory = self.peak_eval_memory;
 
         Ok(stats)
     }
 
+    /// Restricts the next token to those that are consistent with `prefix`, as
+    /// found by [healing_candidates]. If there are none, the next token is left
+    /// unrestricted.
+    fn constrain_to_prefix(&mut self, vocabulary: &Vocabulary, prefix: &[u8]) {
+        let allowed = healing_candidates(vocabulary, prefix);
+        if allowed.is_empty() {
+            return;
+        }
+
+        let mut is_allowed = vec![false; self.last_logits.len()];
+        for id in allowed {
+            if let Some(is_allowed) = is_allowed.get_mut(id as usize) {
+                *is_allowed = true;
+            }
+        }
+        for (logit, is_allowed) in self.last_logits.iter_mut().zip(is_allowed) {
+            if !is_allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Clears the context of this session, so that it can be reused as if it
+    /// had just been started.
+    pub fn reset(&mut self) {
+        self.n_past = 0;
+        self.tokens.clear();
+        self.last_logits.iter_mut().for_each(|l| *l = 0.0);
+    }
+
+    /// Discards all but the first `n_tokens` tokens of the context, so that the
+    /// rest can be replaced without evaluating the tokens that are kept again.
+    ///
+    /// The logits of the last kept token are not restored, so more tokens must be
+    /// fed before the next token is inferred.
+    pub fn truncate(&mut self, n_tokens: usize) {
+        if n_tokens >= self.n_past {
+            return;
+        }
+        self.n_past = n_tokens;
+        self.tokens.truncate(n_tokens);
+        self.last_logits.iter_mut().for_each(|l| *l = 0.0);
+    }
+
+    /// Computes the log-probability that the model assigns to `continuation`
+    /// following `context`, without sampling.
+    ///
+    /// The context is fed to the session as with [Self::feed_prompt], and stays in
+    /// the session afterwards. The continuation is evaluated in a single batch
+    /// and then discarded, so that further continuations can be scored against
+    /// the same context by passing an empty `context`, without evaluating it again.
+    pub fn score(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        context: &str,
+        continuation: &str,
+    ) -> Result<Score, InferenceError> {
+        self.feed_prompt::<Infallible>(
+            model,
+            params,
+            context,
+            &mut OutputRequest::default(),
+            |_| Ok(()),
+        )?;
+
+        let continuation_tokens: Vec<TokenId> = model
+            .vocabulary()
+            .tokenize(continuation, false)?
+            .iter()
+            .map(|(_, tok)| *tok)
+            .collect();
+        if self.n_past + continuation_tokens.len() >= model.n_context_tokens() {
+            return Err(InferenceError::ContextFull);
+        }
+        if continuation_tokens.is_empty() {
+            return Ok(score_tokens(&self.last_logits, &[], &[]));
+        }
+
+        let n_past = self.n_past;
+        let last_logits = self.last_logits.clone();
+
+        let mut output_request = OutputRequest {
+            all_logits: Some(vec![]),
+            ..Default::default()
+        };
+        model.evaluate(self, params, &continuation_tokens, &mut output_request);
+
+        // Rewind to the end of the context; the key/value memory of the
+        // continuation will be overwritten by whatever is evaluated next.
+        self.n_past = n_past;
+        self.last_logits = last_logits;
+
+        Ok(score_tokens(
+            &self.last_logits,
+            output_request.all_logits.as_deref().unwrap_or_default(),
+            &continuation_tokens,
+        ))
+    }
+
+    /// Returns an [Iterator] that infers one token per call to [Iterator::next],
+    /// yielding the generated text as it forms complete characters.
+    ///
+    /// The prompt is not fed by the iterator; use [Self::feed_prompt] first.
+    /// Iteration stops when an end-of-text token is generated, or after the
+    /// first error (such as [InferenceError::ContextFull]).
+    pub fn infer_iter<'a, R: rand::Rng>(
+        &'a mut self,
+        model: &'a dyn Model,
+        params: &'a InferenceParameters,
+        rng: &'a mut R,
+    ) -> InferenceIter<'a, R> {
+        InferenceIter {
+            session: self,
+            model,
+            params,
+            rng,
+            decoder: TokenDecoder::default(),
+            finished: false,
+        }
+    }
+
     /// Sample a token using Top-P/Top-K sampling and the last logits from this session.
     pub fn sample_top_p_top_k(
         &self,
@@ -374,23 +643,7 @@ impl InferenceSession {
         n_embd: usize,
         n_vocab: usize,
     ) -> InferenceSession {
-        let ctx_size = {
-            let mut ctx_size = 0;
-            ctx_size += mulf!(
-                n_ctx,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_k_type.into())
-            ); // memory_k
-            ctx_size += mulf!(
-                n_ctx,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_v_type.into())
-            ); // memory_v
-            ctx_size += (5 + 10 * n_layer) * 256; // object overhead
-            ctx_size
-        };
+        let ctx_size = session_context_size(config, n_ctx, n_layer, n_embd);
 
         let session_ctx = ggml::Context::init(ctx_size, true);
 
@@ -408,6 +661,7 @@ impl InferenceSession {
             memory_v,
             n_past: 0,
             mem_per_token: 0,
+            peak_eval_memory: 0,
             tokens: vec![],
             last_logits: vec![0.0; n_vocab],
             scratch: scratch_buffers(),
@@ -417,8 +671,28 @@ impl InferenceSession {
 impl Clone for InferenceSession {
     fn clone(&self) -> Self {
         let context = ggml::Context::init(self.memory_size, true);
-        let memory_k = context.new_tensor_1d(self.memory_k.get_type(), self.memory_k.nelements());
-        let memory_v = context.new_tensor_1d(self.memory_v.get_type(), self.memory_v.nelements());
+        let mut memory_k =
+            context.new_tensor_1d(self.memory_k.get_type(), self.memory_k.nelements());
+        let mut memory_v =
+            context.new_tensor_1d(self.memory_v.get_type(), self.memory_v.nelements());
+
+        // The key/value memory holds the evaluated tokens, so it must be copied for
+        // the clone to continue from the same state.
+        //
+        // SAFETY: The new tensors are owned by `context`, and have the same type and
+        // size as those of `self`, which cannot be evaluated while it is borrowed.
+        unsafe {
+            for (source, destination) in [
+                (&self.memory_k, &mut memory_k),
+                (&self.memory_v, &mut memory_v),
+            ] {
+                let data = std::slice::from_raw_parts_mut(
+                    destination.data() as *mut u8,
+                    destination.nbytes(),
+                );
+                source.read_data(0, data);
+            }
+        }
 
         Self {
             _session_ctx: context,
@@ -428,6 +702,7 @@ impl Clone for InferenceSession {
             memory_v,
             n_past: self.n_past,
             mem_per_token: self.mem_per_token,
+            peak_eval_memory: self.peak_eval_memory,
             tokens: self.tokens.clone(),
             last_logits: self.last_logits.clone(),
             scratch: scratch_buffers(),
@@ -435,6 +710,63 @@ impl Clone for InferenceSession {
     }
 }
 
+/// An [Iterator] over the text generated by an [InferenceSession].
+///
+/// Created by [InferenceSession::infer_iter].
+pub struct InferenceIter<'a, R: rand::Rng> {
+    session: &'a mut InferenceSession,
+    model: &'a dyn Model,
+    params: &'a InferenceParameters,
+    rng: &'a mut R,
+    decoder: TokenDecoder,
+    finished: bool,
+}
+impl<'a, R: rand::Rng> InferenceIter<'a, R> {
+    /// The session that is being used for inference.
+    pub fn session(&self) -> &InferenceSession {
+        self.session
+    }
+
+    /// Changes the parameters used to infer subsequent tokens.
+    pub fn set_parameters(&mut self, params: &'a InferenceParameters) {
+        self.params = params;
+    }
+}
+impl<R: rand::Rng> Iterator for InferenceIter<'_, R> {
+    type Item = Result<String, InferenceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            let token = match self.session.infer_next_token(
+                self.model,
+                self.params,
+                &mut Default::default(),
+                self.rng,
+            ) {
+                Ok(token) => token,
+                Err(InferenceError::EndOfText) => {
+                    self.finished = true;
+                    break;
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            };
+
+            // Buffer the token until it forms complete characters.
+            let text = self.decoder.push_bytes(&token);
+            if !text.is_empty() {
+                return Some(Ok(text));
+            }
+        }
+
+        // Anything still buffered can no longer form complete characters.
+        let text = self.decoder.flush();
+        (!text.is_empty()).then_some(Ok(text))
+    }
+}
+
 #[derive(Error, Debug)]
 /// Errors encountered during the snapshot process.
 pub enum SnapshotError {
@@ -549,10 +881,114 @@ pub struct InferenceRequest<'a> {
     pub play_back_previous_tokens: bool,
     /// The maximum number of tokens to generate.
     pub maximum_token_count: Option<usize>,
+    /// A token that can be used to cancel inference from another thread.
+    pub cancellation_token: Option<&'a CancellationToken>,
+    /// The point in time after which inference will be stopped.
+    pub deadline: Option<Instant>,
+    /// The maximum number of tokens, including those of the prompt, that can be
+    /// evaluated before inference is stopped.
+    ///
+    /// Unlike [Self::maximum_token_count], exceeding this is treated as a deadline.
+    pub token_budget: Option<usize>,
+    /// Generation stops when any of these strings is generated. The stop sequence
+    /// itself is not passed to the callback.
+    pub stop_sequences: &'a [String],
+    /// The number of tokens at the end of the prompt to heal, or 0 to disable
+    /// token healing.
+    ///
+    /// A prompt that ends partway through a word, or with a space, is tokenized
+    /// differently from how the model would continue it, which pushes the model
+    /// towards unusual continuations. With token healing, these tokens are removed
+    /// from the prompt, and the tokens generated in their place are constrained to
+    /// start with their text. The removed text is passed to the callback as part
+    /// of the generated text, rather than the prompt.
+    pub token_healing: usize,
+}
+
+/// The tokens that can be generated while `prefix` has not yet been regenerated
+/// during token healing: those that start with it, and the non-empty tokens that
+/// it starts with, which leave the rest of it to be generated next.
+fn healing_candidates(vocabulary: &Vocabulary, prefix: &[u8]) -> Vec<TokenId> {
+    let trie = &vocabulary.token_to_id;
+    trie.starting_with(prefix)
+        .into_iter()
+        .chain(
+            trie.prefixes_of(prefix)
+                .into_iter()
+                .filter(|&(_, len)| len > 0 && len < prefix.len())
+                .map(|(id, _)| id),
+        )
+        .collect()
+}
+
+/// A handle that can be used to cancel inference, including from another thread.
+///
+/// Clones of a token share the same state; cancelling one cancels all of them.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+impl CancellationToken {
+    /// Creates a new token that has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Signals that inference using this token should stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether or not [Self::cancel] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The conditions from an [InferenceRequest] under which inference should stop early.
+#[derive(Default)]
+struct StopConditions<'a> {
+    cancellation_token: Option<&'a CancellationToken>,
+    deadline: Option<Instant>,
+    token_budget: Option<usize>,
+}
+impl StopConditions<'_> {
+    /// Checks whether inference should stop, given that `tokens_evaluated`
+    /// tokens have been evaluated so far.
+    fn check(&self, tokens_evaluated: usize) -> Result<(), InferenceError> {
+        if self.cancellation_token.map_or(false, |t| t.is_cancelled()) {
+            return Err(InferenceError::Cancelled);
+        }
+        if self.deadline.map_or(false, |d| Instant::now() >= d)
+            || self.token_budget.map_or(false, |b| tokens_evaluated >= b)
+        {
+            return Err(InferenceError::DeadlineExceeded);
+        }
+        Ok(())
+    }
+
+    /// The number of tokens that can still be evaluated within the token budget,
+    /// given that `tokens_evaluated` tokens have been evaluated so far.
+    fn remaining_budget(&self, tokens_evaluated: usize) -> usize {
+        self.token_budget
+            .map_or(usize::MAX, |b| b.saturating_sub(tokens_evaluated))
+    }
+}
+impl<'a> From<&InferenceRequest<'a>> for StopConditions<'a> {
+    fn from(request: &InferenceRequest<'a>) -> Self {
+        Self {
+            cancellation_token: request.cancellation_token,
+            deadline: request.deadline,
+            token_budget: request.token_budget,
+        }
+    }
 }
 
 /// Statistics about the inference process.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct InferenceStats {
     /// How long it took to feed the prompt.
     pub feed_prompt_duration: std::time::Duration,
@@ -562,6 +998,18 @@ pub struct InferenceStats {
     pub predict_duration: std::time::Duration,
     /// The number of predicted tokens.
     pub predict_tokens: usize,
+    /// The number of prompt tokens fed per second.
+    pub prompt_tokens_per_second: f64,
+    /// The number of tokens predicted per second.
+    pub predict_tokens_per_second: f64,
+    /// How long it took from the start of inference until the first token was
+    /// predicted, if any were.
+    pub time_to_first_token: Option<std::time::Duration>,
+    /// The largest amount of memory, in bytes, used by the `ggml` context of a
+    /// single evaluation.
+    pub peak_eval_memory: usize,
+    /// Why generation stopped.
+    pub finish_reason: FinishReason,
 }
 impl Default for InferenceStats {
     fn default() -> Self {
@@ -570,6 +1018,11 @@ impl Default for InferenceStats {
             prompt_tokens: 0,
             predict_duration: std::time::Duration::from_secs(0),
             predict_tokens: 0,
+            prompt_tokens_per_second: 0.0,
+            predict_tokens_per_second: 0.0,
+            time_to_first_token: None,
+            peak_eval_memory: 0,
+            finish_reason: FinishReason::EndOfText,
         }
     }
 }
@@ -577,16 +1030,169 @@ impl Display for InferenceStats {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "feed_prompt_duration: {}ms\nprompt_tokens: {}\npredict_duration: {}ms\npredict_tokens: {}\nper_token_duration: {:.3}ms",
+            "feed_prompt_duration: {}ms\nprompt_tokens: {}\nprompt_tokens_per_second: {:.3}\npredict_duration: {}ms\npredict_tokens: {}\npredict_tokens_per_second: {:.3}\nper_token_duration: {:.3}ms\n",
             self.feed_prompt_duration.as_millis(),
             self.prompt_tokens,
+            self.prompt_tokens_per_second,
             self.predict_duration.as_millis(),
             self.predict_tokens,
+            self.predict_tokens_per_second,
             (self.predict_duration.as_millis() as f64) / (self.predict_tokens as f64),
+        )?;
+        if let Some(time_to_first_token) = self.time_to_first_token {
+            writeln!(
+                f,
+                "time_to_first_token: {}ms",
+                time_to_first_token.as_millis()
+            )?;
+        }
+        write!(
+            f,
+            "peak_eval_memory: {} bytes\nfinish_reason: {}",
+            self.peak_eval_memory, self.finish_reason
         )
     }
 }
 
+fn tokens_per_second(tokens: usize, duration: std::time::Duration) -> f64 {
+    let seconds = duration.as_secs_f64();
+    if seconds > 0.0 {
+        tokens as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+/// The reason that generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum FinishReason {
+    /// The model produced an end-of-text token.
+    EndOfText,
+    /// The [maximum number of tokens](InferenceRequest::maximum_token_count) were generated.
+    MaxTokens,
+    /// The context window is full.
+    ContextFull,
+    /// One of the [stop sequences](InferenceRequest::stop_sequences) was generated.
+    StopSequence,
+    /// Inference was cancelled through the [cancellation token](InferenceRequest::cancellation_token).
+    Cancelled,
+    /// The [deadline](InferenceRequest::deadline) or [token budget](InferenceRequest::token_budget)
+    /// was exceeded.
+    DeadlineExceeded,
+}
+impl Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FinishReason::EndOfText => write!(f, "end of text"),
+            FinishReason::MaxTokens => write!(f, "maximum token count reached"),
+            FinishReason::ContextFull => write!(f, "context window full"),
+            FinishReason::StopSequence => write!(f, "stop sequence"),
+            FinishReason::Cancelled => write!(f, "cancelled"),
+            FinishReason::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+/// The log-likelihood of a continuation, as computed by [InferenceSession::score].
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub struct Score {
+    /// The total log-probability of the continuation.
+    pub log_probability: f64,
+    /// The log-probability of each token of the continuation.
+    pub token_log_probabilities: Vec<f64>,
+    /// Whether every token of the continuation was the most likely token, so
+    /// that greedy sampling would have generated the continuation.
+    pub is_greedy: bool,
+}
+
+/// Scores `tokens`, where `first_logits` predict the first token and each
+/// `n_vocab`-sized chunk of `all_logits` predicts the token after it.
+fn score_tokens(first_logits: &[f32], all_logits: &[f32], tokens: &[TokenId]) -> Score {
+    let n_vocab = first_logits.len();
+    let mut score = Score {
+        is_greedy: true,
+        ..Default::default()
+    };
+    for (i, &token) in tokens.iter().enumerate() {
+        let logits = if i == 0 {
+            first_logits
+        } else {
+            &all_logits[(i - 1) * n_vocab..i * n_vocab]
+        };
+
+        let log_probability = log_softmax(logits, token as usize);
+        score.log_probability += log_probability;
+        score.token_log_probabilities.push(log_probability);
+
+        let most_likely = logits
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index);
+        score.is_greedy &= most_likely == Some(token as usize);
+    }
+    score
+}
+
+/// Holds back generated text that may be the beginning of a stop sequence,
+/// until it is known whether or not the stop sequence was generated.
+struct StopSequenceBuffer<'a> {
+    stop_sequences: &'a [String],
+    pending: String,
+}
+impl<'a> StopSequenceBuffer<'a> {
+    fn new(stop_sequences: &'a [String]) -> Self {
+        Self {
+            stop_sequences,
+            pending: String::new(),
+        }
+    }
+
+    /// Adds `text` to the buffer, returning the text that can be safely emitted,
+    /// and whether or not a stop sequence was found. If one was found, the text
+    /// up to the stop sequence is returned.
+    fn push(&mut self, text: &str) -> (String, bool) {
+        self.pending.push_str(text);
+
+        let stop_index = self
+            .stop_sequences
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| self.pending.find(s.as_str()))
+            .min();
+        if let Some(stop_index) = stop_index {
+            let mut text = std::mem::take(&mut self.pending);
+            text.truncate(stop_index);
+            return (text, true);
+        }
+
+        // Keep the longest suffix that is the beginning of a stop sequence.
+        let held = self
+            .stop_sequences
+            .iter()
+            .map(|s| {
+                (1..s.len().min(self.pending.len() + 1))
+                    .rev()
+                    .map(|len| self.pending.len() - len)
+                    .find(|&start| {
+                        self.pending.is_char_boundary(start)
+                            && s.starts_with(&self.pending[start..])
+                    })
+                    .map_or(0, |start| self.pending.len() - start)
+            })
+            .max()
+            .unwrap_or(0);
+        let text = self.pending[..self.pending.len() - held].to_owned();
+        self.pending.drain(..self.pending.len() - held);
+        (text, false)
+    }
+
+    /// Returns all of the text that is being held back.
+    fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
 /// Allowed types for the model memory K/V tensors.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum ModelKVMemoryType {
@@ -604,9 +1210,197 @@ impl From<ModelKVMemoryType> for ggml::Type {
     }
 }
 
-fn scratch_buffers() -> [ggml::Buffer; 2] {
+/// The size of the context that holds the key/value memory of a session.
+pub(crate) fn session_context_size(
+    config: InferenceSessionConfig,
+    n_ctx: usize,
+    n_layer: usize,
+    n_embd: usize,
+) -> usize {
+    let mut ctx_size = 0;
+    ctx_size += mulf!(
+        n_ctx,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_k_type.into())
+    ); // memory_k
+    ctx_size += mulf!(
+        n_ctx,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_v_type.into())
+    ); // memory_v
+    ctx_size += (5 + 10 * n_layer) * 256; // object overhead
+    ctx_size
+}
+
+fn scratch_buffers() -> [ggml::Buffer; SCRATCH_BUFFER_COUNT] {
     [
         ggml::Buffer::new(SCRATCH_SIZE),
         ggml::Buffer::new(SCRATCH_SIZE),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_tokens() {
+        let first_logits = [0.0, 5.0, 0.0];
+        let all_logits = [5.0, 0.0, 0.0, 0.0, 0.0, 5.0];
+
+        let score = score_tokens(&first_logits, &all_logits, &[1, 0, 2]);
+        assert_eq!(score.token_log_probabilities.len(), 3);
+        assert!(score.is_greedy);
+        assert!(
+            (score.log_probability - score.token_log_probabilities.iter().sum::<f64>()).abs()
+                < 1e-9
+        );
+
+        let score = score_tokens(&first_logits, &all_logits, &[1, 2, 2]);
+        assert!(!score.is_greedy);
+        assert!(score.token_log_probabilities[1] < score.token_log_probabilities[0]);
+    }
+
+    #[test]
+    fn test_stop_conditions_cancellation() {
+        let token = CancellationToken::new();
+        let conditions = StopConditions {
+            cancellation_token: Some(&token),
+            ..Default::default()
+        };
+        assert!(conditions.check(0).is_ok());
+
+        token.clone().cancel();
+        assert!(matches!(
+            conditions.check(0),
+            Err(InferenceError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_stop_conditions_deadlines() {
+        let conditions = StopConditions {
+            token_budget: Some(4),
+            ..Default::default()
+        };
+        assert!(conditions.check(3).is_ok());
+        assert!(matches!(
+            conditions.check(4),
+            Err(InferenceError::DeadlineExceeded)
+        ));
+        assert_eq!(conditions.remaining_budget(1), 3);
+        assert_eq!(conditions.remaining_budget(6), 0);
+        assert_eq!(StopConditions::default().remaining_budget(6), usize::MAX);
+
+        let conditions = StopConditions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            conditions.check(0),
+            Err(InferenceError::DeadlineExceeded)
+        ));
+    }
+
+    /// A model whose logits depend on everything in its key memory, which it fills
+    /// with the tokens it evaluates, as a transformer fills its key/value memory.
+    #[derive(Default)]
+    struct MemoryModel {
+        vocabulary: Vocabulary,
+        inference_parameters: InferenceParameters,
+    }
+    impl Model for MemoryModel {
+        fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+            InferenceSession::new(config, self.n_context_tokens(), 1, 1, 4)
+        }
+
+        fn evaluate(
+            &self,
+            session: &mut InferenceSession,
+            _params: &InferenceParameters,
+            input_tokens: &[TokenId],
+            _output_request: &mut OutputRequest,
+        ) {
+            let memory = unsafe {
+                std::slice::from_raw_parts_mut(
+                    session.memory_k.data() as *mut f32,
+                    session.memory_k.nelements(),
+                )
+            };
+            for &token in input_tokens {
+                memory[session.n_past] = (token + 1) as f32;
+                session.n_past += 1;
+            }
+
+            let sum: f32 = memory[..session.n_past].iter().sum();
+            for (index, logit) in session.last_logits.iter_mut().enumerate() {
+                *logit = sum * (index + 1) as f32;
+            }
+        }
+
+        fn vocabulary(&self) -> &Vocabulary {
+            &self.vocabulary
+        }
+
+        fn n_context_tokens(&self) -> usize {
+            16
+        }
+
+        fn bot_token_id(&self) -> Option<TokenId> {
+            None
+        }
+
+        fn eot_token_id(&self) -> TokenId {
+            0
+        }
+
+        fn inference_parameters(&self) -> &InferenceParameters {
+            &self.inference_parameters
+        }
+    }
+
+    #[test]
+    fn test_clone_keeps_memory() {
+        let model = MemoryModel::default();
+        let params = InferenceParameters::default();
+        let mut session = model.start_session(Default::default());
+        model.evaluate(&mut session, &params, &[3, 1, 2], &mut Default::default());
+
+        let mut clone = session.clone();
+        for session in [&mut session, &mut clone] {
+            model.evaluate(session, &params, &[1], &mut Default::default());
+        }
+        assert_eq!(clone.n_past, session.n_past);
+        assert_eq!(clone.last_logits, session.last_logits);
+    }
+
+    #[test]
+    fn test_stop_sequence_buffer() {
+        let stop_sequences = ["###".to_string(), "User:".to_string()];
+        let mut buffer = StopSequenceBuffer::new(&stop_sequences);
+        assert_eq!(buffer.push("Hello"), ("Hello".to_string(), false));
+        assert_eq!(buffer.push(" world#"), (" world".to_string(), false));
+        assert_eq!(buffer.push("#!"), ("##!".to_string(), false));
+        assert_eq!(buffer.push(" Us"), (" ".to_string(), false));
+        assert_eq!(buffer.push("er: hi"), ("".to_string(), true));
+
+        let mut buffer = StopSequenceBuffer::new(&stop_sequences);
+        assert_eq!(buffer.push("a ##"), ("a ".to_string(), false));
+        assert_eq!(buffer.flush(), "##");
+    }
+
+    #[test]
+    fn test_healing_candidates() {
+        let mut vocabulary = Vocabulary::default();
+        for (id, token) in ["h", "he", "hel", "hello", "help", "x"].iter().enumerate() {
+            vocabulary.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+
+        let mut candidates = healing_candidates(&vocabulary, b"hel");
+        candidates.sort();
+        assert_eq!(candidates, [0, 1, 2, 3, 4]);
+        assert!(healing_candidates(&vocabulary, b"y").is_empty());
+    }
+}
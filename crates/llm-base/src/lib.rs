@@ -9,9 +9,17 @@
 
 use thiserror::Error;
 
+mod bpe;
+mod conversation;
+mod embedding;
 mod inference_session;
 mod loader;
+mod memory;
+mod perplexity;
 mod quantize;
+mod safetensors;
+mod token_trie;
+mod tokenizer_json;
 mod vocabulary;
 
 pub mod model;
@@ -20,19 +28,37 @@ pub mod util;
 pub use ggml;
 pub use ggml::Type as ElementType;
 
+pub use conversation::{
+    ChatTemplate, ChatTemplateError, ContextStrategy, Conversation, Message, Role,
+    MESSAGE_PLACEHOLDER,
+};
+pub use embedding::{embed, EmbeddingParameters, Pooling};
 pub use inference_session::{
-    InferenceRequest, InferenceSession, InferenceSessionConfig, InferenceSnapshot, InferenceStats,
-    ModelKVMemoryType, SnapshotError,
+    CancellationToken, FinishReason, InferenceIter, InferenceRequest, InferenceSession,
+    InferenceSessionConfig, InferenceSnapshot, InferenceStats, ModelKVMemoryType, Score,
+    SnapshotError,
 };
 pub use loader::{
-    load, load_progress_callback_stdout, ContainerType, FileType, LoadError, LoadProgress, Loader,
-    TensorLoader,
+    load, load_progress_callback_stdout, load_vocabulary, ContainerType, FileType, LoadError,
+    LoadProgress, Loader, Metadata, MetadataError, MetadataValue, SplitAxis, TensorLoader,
 };
 pub use memmap2::Mmap;
-pub use model::{Hyperparameters, KnownModel, Model, ModelParameters, OutputRequest};
+pub use memory::{estimate_memory_requirements, MemoryRequirements};
+pub use model::{
+    Hyperparameters, KnownModel, LayerTensor, Model, ModelParameters, OutputRequest,
+    ARCHITECTURE_KEY, CONTEXT_LENGTH_KEY, SOURCE_KEY,
+};
+pub use perplexity::{perplexity, Perplexity, PerplexityWindow};
 pub use quantize::{quantize, QuantizeError, QuantizeProgress};
+pub use safetensors::{
+    load_hugging_face, load_hugging_face_config, HuggingFaceLayout, HuggingFaceTensor,
+};
+pub use token_trie::TokenTrie;
 pub use util::TokenUtf8Buffer;
-pub use vocabulary::{InvalidTokenBias, TokenBias, TokenId, Vocabulary};
+pub use vocabulary::{
+    InvalidTokenBias, InvalidUtf8, SpecialTokens, TokenBias, TokenDecoder, TokenId, TokenizerKind,
+    Vocabulary,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 /// The parameters for text generation.
@@ -59,6 +85,9 @@ pub struct InferenceParameters {
     pub bias_tokens: TokenBias,
     /// The number of tokens to consider for the repetition penalty.
     pub repetition_penalty_last_n: usize,
+    /// Whether the text of [special tokens](SpecialTokens) in prompts is recognised
+    /// as those tokens. This should only be enabled for trusted prompts.
+    pub parse_special_tokens: bool,
 }
 impl Default for InferenceParameters {
     fn default() -> Self {
@@ -71,6 +100,7 @@ impl Default for InferenceParameters {
             temperature: 0.80,
             bias_tokens: TokenBias::default(),
             repetition_penalty_last_n: 512,
+            parse_special_tokens: false,
         }
     }
 }
@@ -89,7 +119,14 @@ pub enum InferenceError {
     ///
     /// Note that this error *can* be ignored and inference can continue, but the results are not guaranteed to be sensical.
     EndOfText,
+    #[error("inference was cancelled")]
+    /// Inference was cancelled through the [CancellationToken] of the [InferenceRequest].
+    Cancelled,
+    #[error("the inference deadline was exceeded")]
+    /// The [deadline](InferenceRequest::deadline) or [token budget](InferenceRequest::token_budget)
+    /// of the [InferenceRequest] was exceeded.
+    DeadlineExceeded,
     #[error("the user-specified callback returned an error")]
     /// The user-specified callback returned an error.
-    UserCallback(Box<dyn std::error::Error>),
+    UserCallback(Box<dyn std::error::Error + Send + Sync>),
 }
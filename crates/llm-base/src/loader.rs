@@ -7,14 +7,19 @@ use std::{
 };
 
 use crate::{
+    memory::weights_context_size,
+    tokenizer_json,
     util::{self, FindAllModelFilesError},
     Hyperparameters, KnownModel, ModelParameters, TokenId, Vocabulary,
 };
-pub use ggml::ContainerType;
 use ggml::{
     format::{LoadError as FormatLoadError, PartialHyperparameters, TensorLoadInfo},
     Context,
 };
+pub use ggml::{
+    format::{Metadata, MetadataError, MetadataValue},
+    ContainerType,
+};
 use memmap2::Mmap;
 use thiserror::Error;
 
@@ -75,6 +80,16 @@ impl TryFrom<i32> for FileType {
         }
     }
 }
+impl FileType {
+    /// The file type of a Hugging Face checkpoint whose weights are stored as the
+    /// `torch_dtype` of its `config.json`. `bfloat16` weights are loaded as `f32`.
+    pub fn from_torch_dtype(torch_dtype: &str) -> Self {
+        match torch_dtype {
+            "float16" => FileType::MostlyF16,
+            _ => FileType::F32,
+        }
+    }
+}
 impl Display for FileType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -129,6 +144,30 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    #[error("invalid BPE merge on line {line} of {path:?}")]
+    /// A line of a BPE merges file was not a pair of tokens.
+    InvalidMerge {
+        /// The path that failed.
+        path: PathBuf,
+        /// The 1-based number of the invalid line.
+        line: usize,
+    },
+    #[error("invalid tokenizer {path:?}: {reason}")]
+    /// A `tokenizer.json` file could not be understood.
+    InvalidTokenizer {
+        /// The path that failed.
+        path: PathBuf,
+        /// Why the tokenizer is invalid.
+        reason: String,
+    },
+    #[error("the vocabulary has {actual} tokens, but the model has {expected}")]
+    /// The number of tokens in a vocabulary does not match the model.
+    InvalidVocabularySize {
+        /// The number of tokens the model has.
+        expected: usize,
+        /// The number of tokens in the vocabulary.
+        actual: usize,
+    },
     #[error("no parent path for {path:?}")]
     /// There is no parent path for a given path.
     NoParentPath {
@@ -224,14 +263,36 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
-    /// Multiple parts of the model were found.
-    ///
-    /// Multi-part models are not supported. Please convert the model to a single part.
-    #[error("multipart models are not supported")]
-    MultipartNotSupported {
-        /// The paths that were found.
-        paths: Vec<PathBuf>,
+    #[error("could not detect the model architecture of {path:?}")]
+    /// The tensors in the file did not match any known model architecture.
+    UnknownArchitecture {
+        /// The path that failed.
+        path: PathBuf,
     },
+    #[error("invalid metadata")]
+    /// The metadata of the model could not be read, or did not hold the hyperparameters.
+    InvalidMetadata(#[from] MetadataError),
+    #[error("invalid Hugging Face checkpoint {path:?}: {reason}")]
+    /// A file of a Hugging Face checkpoint could not be read.
+    InvalidHuggingFaceCheckpoint {
+        /// The path that failed.
+        path: PathBuf,
+        /// Why the file could not be read.
+        reason: String,
+    },
+    #[error("the tensor `{tensor_name}` has the unsupported data type {dtype} in {path:?}")]
+    /// A tensor in a safetensors file had a data type that cannot be loaded.
+    UnsupportedDtype {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The data type of the tensor.
+        dtype: String,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("this architecture cannot be loaded from Hugging Face checkpoints")]
+    /// The architecture does not implement [KnownModel::hugging_face_hyperparameters].
+    HuggingFaceUnsupported,
 }
 impl From<FindAllModelFilesError> for LoadError {
     fn from(value: FindAllModelFilesError) -> Self {
@@ -259,6 +320,7 @@ impl LoadError {
                 LoadError::InvalidIntegerConversion(err)
             }
             FormatLoadError::ImplementationError(err) => err,
+            FormatLoadError::InvalidMetadata(err) => LoadError::InvalidMetadata(err),
             FormatLoadError::UnsupportedElementType { tensor_name, ftype } => {
                 LoadError::UnsupportedElementType {
                     path,
@@ -274,6 +336,19 @@ impl LoadError {
     }
 }
 
+/// How a tensor of a multipart model is split between its parts.
+///
+/// Multipart models come from checkpoints that were sharded for model parallelism,
+/// such as the original LLaMA checkpoints. One-dimensional tensors are never split;
+/// every part holds all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitAxis {
+    /// Each part holds a slice of every row, so the first dimension is split.
+    Columns,
+    /// Each part holds some of the rows, so the second dimension is split.
+    Rows,
+}
+
 /// Used by models to fetch tensors from a loader.
 pub trait TensorLoader<E: std::error::Error> {
     /// Gets a tensor from the loader.
@@ -284,11 +359,84 @@ pub trait TensorLoader<E: std::error::Error> {
     fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>);
 }
 
+/// Load the hyperparameters and vocabulary of a GGML model from the `path`, without
+/// loading its tensors. The vocabulary is configured per the tokenizer options of
+/// the `params`, as with [load].
+///
+/// This is much faster than [load], and is suitable for programs that only need
+/// to tokenize text. As with [load], the model in `path` *must* match the
+/// architecture of `M`; only the first part of a multi-part model is read.
+pub fn load_vocabulary<M: KnownModel>(
+    path: &Path,
+    params: &ModelParameters,
+) -> Result<(M::Hyperparameters, Vocabulary), LoadError> {
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut reader = BufReader::new(&file);
+
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(|_| {});
+    ggml::format::load_vocabulary(&mut reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    let Loader {
+        hyperparameters,
+        vocabulary,
+        ..
+    } = loader;
+    let vocabulary = prepare_vocabulary::<M>(vocabulary, &hyperparameters, params)?;
+    Ok((hyperparameters, vocabulary))
+}
+
+/// Checks the vocabulary read from a model against its hyperparameters, and
+/// configures it for the architecture `M` and the tokenizer options of `params`.
+fn prepare_vocabulary<M: KnownModel>(
+    mut vocabulary: Vocabulary,
+    hyperparameters: &M::Hyperparameters,
+    params: &ModelParameters,
+) -> Result<Vocabulary, LoadError> {
+    let n_vocabulary = hyperparameters.n_vocabulary();
+    if vocabulary.id_to_token.len() != n_vocabulary {
+        return Err(LoadError::InvalidVocabularySize {
+            expected: n_vocabulary,
+            actual: vocabulary.id_to_token.len(),
+        });
+    }
+
+    match &params.tokenizer_path {
+        Some(path) => {
+            vocabulary = tokenizer_json::load(path)?;
+            // A tokenizer may have fewer tokens than the model, which is often
+            // padded, but it cannot produce tokens that the model does not have.
+            if vocabulary.id_to_token.len() > n_vocabulary {
+                return Err(LoadError::InvalidVocabularySize {
+                    expected: n_vocabulary,
+                    actual: vocabulary.id_to_token.len(),
+                });
+            }
+            vocabulary.pad_to(n_vocabulary);
+        }
+        None => {
+            vocabulary.tokenizer = M::tokenizer_kind();
+            if let Some(path) = &params.bpe_merges_path {
+                vocabulary.load_bpe_merges(path)?;
+            }
+        }
+    }
+    vocabulary.special_tokens = M::special_tokens(&vocabulary);
+    Ok(vocabulary)
+}
+
 /// Load a GGML model from the `path` and configure it per the `params`. The status
 /// of the loading process will be reported through `load_progress_callback`.
 ///
-/// Note that the model must be a single-part model, and the model in `path`
-/// *must* match the architecture of `M`.
+/// If there are other parts of the model next to `path` (`model.bin.1`,
+/// `model.bin.2` and so on), they are loaded too, and their tensors are joined
+/// along the axis given by [KnownModel::tensor_split_axis]. Multipart models are
+/// always copied into memory, rather than memory-mapped.
+///
+/// Note that the model in `path` *must* match the architecture of `M`.
 ///
 /// # Panics
 ///
@@ -303,9 +451,9 @@ pub fn load<M: KnownModel>(
     params: ModelParameters,
     load_progress_callback: impl FnMut(LoadProgress),
 ) -> Result<M, LoadError> {
-    let paths = util::find_all_model_files(path)?;
-    if paths.len() != 1 {
-        return Err(LoadError::MultipartNotSupported { paths });
+    let mut paths = util::find_all_model_files(path)?;
+    if paths.is_empty() {
+        paths.push(path.to_owned());
     }
 
     let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
@@ -314,7 +462,7 @@ pub fn load<M: KnownModel>(
     })?;
     let mut reader = BufReader::new(&file);
 
-    let mut loader = Loader::new(load_progress_callback);
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(load_progress_callback);
 
     ggml::format::load(&mut reader, &mut loader)
         .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
@@ -328,38 +476,93 @@ pub fn load<M: KnownModel>(
         ..
     } = loader;
 
-    let use_mmap = params.prefer_mmap && container_type.support_mmap();
+    let vocabulary = prepare_vocabulary::<M>(vocabulary, &hyperparameters, &params)?;
+
+    // The other parts of a multipart model repeat the header of the first part,
+    // and hold their share of each tensor. The paths are sorted by part number, so
+    // the first is the main file, although it may be spelled differently than `path`.
+    let mut parts = vec![Part { file, tensors }];
+    for part_path in &paths[1..] {
+        let file = File::open(part_path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: part_path.clone(),
+        })?;
+        let mut loader: Loader<M::Hyperparameters, _> = Loader::new(|_| {});
+        ggml::format::load(&mut BufReader::new(&file), &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, part_path.clone()))?;
+        parts.push(Part {
+            file,
+            tensors: loader.tensors,
+        });
+    }
+    let part_tensors: Vec<_> = parts.iter().map(|part| &part.tensors).collect();
+    let tensors = merge_part_tensors(&part_tensors, M::tensor_split_axis, path)?;
+
+    let use_mmap = params.prefer_mmap && container_type.support_mmap() && parts.len() == 1;
 
-    let ctx_size = tensors
-        .values()
-        .map(|ti| {
-            ggml::Tensor::C_TYPE_SIZE
-                + ggml::OBJECT_SIZE
-                + if use_mmap { 0 } else { ti.calc_size() }
-        })
-        .sum::<usize>();
+    let ctx_size = weights_context_size(&tensors, use_mmap);
     (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
     let context = Context::init(ctx_size, !use_mmap);
 
-    let (mmap, file_size) = {
-        let file = File::open(path)?;
-        let mmap = if use_mmap {
-            Some(unsafe { Mmap::map(&file)? })
-        } else {
-            None
-        };
-        (mmap, file.metadata()?.len())
+    let mmap = if use_mmap {
+        Some(unsafe { Mmap::map(&parts[0].file)? })
+    } else {
+        None
     };
+    let mut file_size = 0;
+    for part in &parts {
+        file_size += part.file.metadata()?.len();
+    }
 
     struct MmapCompatibleLoader<'a> {
         path: PathBuf,
-        file: File,
+        parts: Vec<Part>,
+        split_axis: fn(&str) -> Option<SplitAxis>,
         tensors: HashMap<String, TensorLoadInfo>,
         context: Context,
         mmap: Option<Mmap>,
         load_progress_callback: &'a mut dyn FnMut(LoadProgress),
         loaded_tensors: HashMap<String, ggml::Tensor>,
     }
+    impl MmapCompatibleLoader<'_> {
+        /// Reads the data of the tensor `name` into `buf`, joining it from the
+        /// parts of the model if it is split between them.
+        fn read_data(
+            &mut self,
+            name: &str,
+            n_dims: usize,
+            buf: &mut [u8],
+        ) -> Result<(), LoadError> {
+            let n_parts = self.parts.len();
+            let split_axis = if n_dims > 1 && n_parts > 1 {
+                (self.split_axis)(name)
+            } else {
+                None
+            };
+
+            let rows = self.tensors[name].dims[1];
+            for (index, part) in self.parts.iter_mut().enumerate() {
+                let info = &part.tensors[name];
+                if split_axis.is_none() && info.container_type.support_mmap() {
+                    part.file.seek(SeekFrom::Start(info.start_offset))?;
+                    return Ok(part.file.read_exact(buf)?);
+                }
+
+                // The data may need to be converted to the block layouts used by `ggml`.
+                let data = info.read_data(&mut BufReader::new(&mut part.file))?;
+                match split_axis {
+                    None => {
+                        buf.copy_from_slice(&data);
+                        return Ok(());
+                    }
+                    Some(split_axis) => {
+                        copy_part(buf, &data, index, n_parts, split_axis, buf.len() / rows);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
     impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
         fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
             let tensor_dims = self
@@ -417,8 +620,8 @@ pub fn load<M: KnownModel>(
                     let buf: &mut [u8] = unsafe {
                         std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
                     };
-                    self.file.seek(SeekFrom::Start(info.start_offset))?;
-                    self.file.read_exact(buf)?;
+                    let n_dims = info.n_dims;
+                    self.read_data(name, n_dims, buf)?;
                 }
             }
 
@@ -439,7 +642,8 @@ pub fn load<M: KnownModel>(
     let tensors_len = tensors.len();
     let tl = MmapCompatibleLoader {
         path: path.to_owned(),
-        file,
+        parts,
+        split_axis: M::tensor_split_axis,
         tensors,
         context,
         mmap,
@@ -457,6 +661,86 @@ pub fn load<M: KnownModel>(
     Ok(model)
 }
 
+/// A part of a model, with the information about the tensors it holds.
+struct Part {
+    file: File,
+    tensors: HashMap<String, TensorLoadInfo>,
+}
+
+/// Combines the information about the tensors held by each part of a model into
+/// information about the whole tensors, which are split between the parts along
+/// the axis given by `split_axis`.
+fn merge_part_tensors(
+    parts: &[&HashMap<String, TensorLoadInfo>],
+    split_axis: fn(&str) -> Option<SplitAxis>,
+    path: &Path,
+) -> Result<HashMap<String, TensorLoadInfo>, LoadError> {
+    let mut tensors = parts[0].clone();
+    for part in &parts[1..] {
+        if let Some(name) = part.keys().find(|name| !tensors.contains_key(*name)) {
+            return Err(LoadError::UnknownTensor {
+                tensor_name: name.clone(),
+                path: path.to_owned(),
+            });
+        }
+    }
+
+    for (name, info) in tensors.iter_mut() {
+        for part in &parts[1..] {
+            let part_info = part.get(name).ok_or_else(|| LoadError::InvariantBroken {
+                path: Some(path.to_owned()),
+                invariant: format!("the tensor {name} should be in every part of the model"),
+            })?;
+            if part_info.dims() != info.dims() || part_info.element_type != info.element_type {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: name.clone(),
+                    path: path.to_owned(),
+                });
+            }
+        }
+
+        let split_axis = if info.n_dims > 1 && parts.len() > 1 {
+            split_axis(name)
+        } else {
+            None
+        };
+        if let Some(split_axis) = split_axis {
+            let dim = match split_axis {
+                SplitAxis::Columns => 0,
+                SplitAxis::Rows => 1,
+            };
+            info.dims[dim] *= parts.len();
+            info.n_elements *= parts.len();
+        }
+    }
+    Ok(tensors)
+}
+
+/// Copies `part`, the data of part `index` of `n_parts` of a tensor that is split
+/// along `split_axis`, to where it belongs in `dst`, the data of the whole tensor,
+/// whose rows are `row_bytes` long.
+fn copy_part(
+    dst: &mut [u8],
+    part: &[u8],
+    index: usize,
+    n_parts: usize,
+    split_axis: SplitAxis,
+    row_bytes: usize,
+) {
+    match split_axis {
+        SplitAxis::Rows => {
+            dst[index * part.len()..(index + 1) * part.len()].copy_from_slice(part);
+        }
+        SplitAxis::Columns => {
+            let part_row_bytes = row_bytes / n_parts;
+            for (row, part_row) in part.chunks_exact(part_row_bytes).enumerate() {
+                let start = row * row_bytes + index * part_row_bytes;
+                dst[start..start + part_row_bytes].copy_from_slice(part_row);
+            }
+        }
+    }
+}
+
 /// A GGML format loader for LLMs.
 pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     // Input
@@ -467,6 +751,11 @@ pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     pub container_type: ContainerType,
     /// The hyperparameters of the model.
     pub hyperparameters: Hp,
+    /// The metadata of the model, including its hyperparameters.
+    ///
+    /// Formats other than GGKV only store the hyperparameters, which are read into
+    /// this under their [Hyperparameters::GGML_KEYS].
+    pub metadata: Metadata,
     /// The vocabulary of the model.
     pub vocabulary: Vocabulary,
     /// The tensors of the model.
@@ -478,8 +767,9 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> Loader<Hp, F> {
         Self {
             load_progress_callback,
 
-            container_type: ContainerType::Ggjt,
+            container_type: ContainerType::Ggjt(ggml::FORMAT_VERSION),
             hyperparameters: Hp::default(),
+            metadata: Metadata::default(),
             vocabulary: Vocabulary::default(),
             tensors: HashMap::default(),
         }
@@ -508,11 +798,17 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
         reader: &mut dyn BufRead,
     ) -> Result<PartialHyperparameters, LoadError> {
         // NOTE: Field order matters! Data is laid out in the file exactly in this order.
-        let hyperparameters = Hp::read_ggml(reader)?;
+        let metadata = crate::model::read_ggml_metadata(Hp::GGML_KEYS, reader)?;
+        self.read_metadata(metadata)
+    }
+
+    fn read_metadata(&mut self, metadata: Metadata) -> Result<PartialHyperparameters, LoadError> {
+        let hyperparameters = Hp::read_metadata(&metadata)?;
         let partial = PartialHyperparameters {
             n_vocab: hyperparameters.n_vocabulary(),
         };
         self.hyperparameters = hyperparameters;
+        self.metadata = metadata;
         (self.load_progress_callback)(LoadProgress::HyperparametersLoaded);
 
         Ok(partial)
@@ -555,3 +851,61 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(name: &str, dims: &[usize]) -> TensorLoadInfo {
+        let mut info = TensorLoadInfo {
+            name: name.to_owned(),
+            n_dims: dims.len(),
+            dims: [1, 1],
+            n_elements: dims.iter().product(),
+            element_type: ggml::Type::F32,
+            start_offset: 0,
+            container_type: ContainerType::Ggjt(ggml::FORMAT_VERSION),
+        };
+        info.dims[..dims.len()].copy_from_slice(dims);
+        info
+    }
+
+    #[test]
+    fn test_merge_part_tensors() {
+        let part: HashMap<_, _> = [info("norm", &[4]), info("wo", &[2, 3]), info("wq", &[4, 3])]
+            .into_iter()
+            .map(|info| (info.name.clone(), info))
+            .collect();
+        let split_axis = |name: &str| match name {
+            "wo" => Some(SplitAxis::Columns),
+            _ => Some(SplitAxis::Rows),
+        };
+
+        let tensors = merge_part_tensors(&[&part, &part], split_axis, Path::new("model")).unwrap();
+        assert_eq!(tensors["norm"].dims(), [4]);
+        assert_eq!(tensors["wo"].dims(), [4, 3]);
+        assert_eq!(tensors["wq"].dims(), [4, 6]);
+        assert_eq!(tensors["wq"].n_elements, 24);
+
+        let mut other = part.clone();
+        other.insert("wq".to_owned(), info("wq", &[4, 2]));
+        assert!(matches!(
+            merge_part_tensors(&[&part, &other], split_axis, Path::new("model")),
+            Err(LoadError::TensorWrongSize { .. })
+        ));
+    }
+
+    #[test]
+    fn test_copy_part() {
+        // A 2x4 tensor, split into two parts.
+        let mut columns = [0; 8];
+        copy_part(&mut columns, &[1, 2, 5, 6], 0, 2, SplitAxis::Columns, 4);
+        copy_part(&mut columns, &[3, 4, 7, 8], 1, 2, SplitAxis::Columns, 4);
+        assert_eq!(columns, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let mut rows = [0; 8];
+        copy_part(&mut rows, &[1, 2, 3, 4], 0, 2, SplitAxis::Rows, 4);
+        copy_part(&mut rows, &[5, 6, 7, 8], 1, 2, SplitAxis::Rows, 4);
+        assert_eq!(rows, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+use ggml::format::TensorLoadInfo;
+
+use crate::{
+    inference_session::{session_context_size, SCRATCH_BUFFER_COUNT, SCRATCH_SIZE},
+    model::common::initial_eval_buffer_size,
+    ContainerType, Hyperparameters, InferenceSessionConfig, KnownModel, LoadError, Loader,
+    ModelParameters,
+};
+
+/// An estimate of the memory required to load a model and run inference
+/// sessions with it. All sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MemoryRequirements {
+    /// The size of the weights that are memory-mapped from the model file.
+    ///
+    /// This memory is backed by the file, and can be shared between processes
+    /// that load the same model.
+    pub weights_mmap: usize,
+    /// The size of the context allocated for the weights. When the weights are
+    /// not memory-mapped, this includes the weights themselves.
+    pub weights_owned: usize,
+    /// The size of the key/value memory of each session.
+    pub kv_cache_per_session: usize,
+    /// The size of the scratch buffers of each session.
+    pub scratch_per_session: usize,
+    /// The size of the temporary context used by each session to evaluate tokens.
+    ///
+    /// This is the initial size; it may grow when evaluating large batches.
+    pub eval_context_per_session: usize,
+}
+impl MemoryRequirements {
+    /// Computes the memory requirements for a model from the information
+    /// read by a [Loader].
+    pub fn new<Hp: Hyperparameters>(
+        container_type: ContainerType,
+        hyperparameters: &Hp,
+        tensors: &HashMap<String, TensorLoadInfo>,
+        params: &ModelParameters,
+        session_config: InferenceSessionConfig,
+    ) -> Self {
+        let use_mmap = params.prefer_mmap && container_type.support_mmap();
+        let n_context_tokens = hyperparameters
+            .n_context_tokens()
+            .unwrap_or(params.n_context_tokens);
+
+        Self {
+            weights_mmap: if use_mmap {
+                tensors.values().map(|ti| ti.calc_size()).sum()
+            } else {
+                0
+            },
+            weights_owned: weights_context_size(tensors, use_mmap),
+            kv_cache_per_session: session_context_size(
+                session_config,
+                n_context_tokens,
+                hyperparameters.n_layer(),
+                hyperparameters.n_embd(),
+            ),
+            scratch_per_session: SCRATCH_BUFFER_COUNT * SCRATCH_SIZE,
+            eval_context_per_session: initial_eval_buffer_size(hyperparameters.n_layer()),
+        }
+    }
+
+    /// The memory required by each session.
+    pub fn per_session(&self) -> usize {
+        self.kv_cache_per_session + self.scratch_per_session + self.eval_context_per_session
+    }
+
+    /// The memory required by the model and `n_sessions` sessions, including the
+    /// memory-mapped weights.
+    pub fn total(&self, n_sessions: usize) -> usize {
+        self.weights_mmap + self.weights_owned + n_sessions * self.per_session()
+    }
+}
+impl Display for MemoryRequirements {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "weights_mmap: {}\nweights_owned: {}\nkv_cache_per_session: {}\nscratch_per_session: {}\neval_context_per_session: {}\nper_session: {}",
+            self.weights_mmap,
+            self.weights_owned,
+            self.kv_cache_per_session,
+            self.scratch_per_session,
+            self.eval_context_per_session,
+            self.per_session(),
+        )
+    }
+}
+
+/// Estimates the memory required to load the model at `path` with `params`, and to
+/// run sessions configured with `session_config`.
+///
+/// Only the header and the tensor table of the model are read; the weights are
+/// not loaded.
+pub fn estimate_memory_requirements<M: KnownModel>(
+    path: &Path,
+    params: &ModelParameters,
+    session_config: InferenceSessionConfig,
+) -> Result<MemoryRequirements, LoadError> {
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut reader = BufReader::new(&file);
+
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(|_| {});
+    ggml::format::load(&mut reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    Ok(MemoryRequirements::new(
+        loader.container_type,
+        &loader.hyperparameters,
+        &loader.tensors,
+        params,
+        session_config,
+    ))
+}
+
+/// The size of the context that holds the weights of a model.
+pub(crate) fn weights_context_size(
+    tensors: &HashMap<String, TensorLoadInfo>,
+    use_mmap: bool,
+) -> usize {
+    tensors
+        .values()
+        .map(|ti| {
+            ggml::Tensor::C_TYPE_SIZE
+                + ggml::OBJECT_SIZE
+                + if use_mmap { 0 } else { ti.calc_size() }
+        })
+        .sum::<usize>()
+}
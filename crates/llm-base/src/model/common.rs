@@ -1,6 +1,8 @@
-use ggml::{Context, Tensor};
+use std::collections::HashSet;
 
-use crate::{InferenceSession, OutputRequest, TokenId};
+use ggml::{ComputationGraph, Context, Tensor};
+
+use crate::{model::LayerTensor, InferenceSession, OutputRequest, TokenId};
 
 /// Common code to prepare a model to evaluate input
 pub fn prepare_for_evaluate(
@@ -10,18 +12,7 @@ pub fn prepare_for_evaluate(
 ) -> (Context, Tensor) {
     // For the first run, we need to guess a maximum buffer size so we can measure
     // the actual memory consumption of the temporary ggml context.
-    //
-    // These numbers are from `llama.cpp`, and could potentially be more efficient.
-    let mut buf_size = {
-        let buf_size_mb = if n_layer >= 80 {
-            1536
-        } else if n_layer >= 60 {
-            1280
-        } else {
-            1024
-        };
-        buf_size_mb * 1024 * 1024
-    };
+    let mut buf_size = initial_eval_buffer_size(n_layer);
 
     let n = input_tokens.len();
     if session.mem_per_token > 0 && session.mem_per_token * n > buf_size {
@@ -36,6 +27,20 @@ pub fn prepare_for_evaluate(
     (ctx0, embd)
 }
 
+/// The size of the temporary context used for evaluation, before the actual
+/// memory required per token has been measured.
+pub(crate) fn initial_eval_buffer_size(n_layer: usize) -> usize {
+    // These numbers are from `llama.cpp`, and could potentially be more efficient.
+    let buf_size_mb = if n_layer >= 80 {
+        1536
+    } else if n_layer >= 60 {
+        1280
+    } else {
+        1024
+    };
+    buf_size_mb * 1024 * 1024
+}
+
 /// Return result for just the last token
 pub fn read_last_token(
     session: &mut InferenceSession,
@@ -72,9 +77,12 @@ pub fn extract_logits(
 }
 
 /// Extract embeddings from [OutputRequest] evaluation
+///
+/// `embeddings_tensor` should be the final-layer hidden states, after normalization
+/// and before they are projected to logits.
 pub fn extract_embeddings(
     output_request: &mut OutputRequest,
-    embd: &Tensor,
+    embeddings_tensor: &Tensor,
     n_embd: usize,
     n: usize,
 ) {
@@ -82,20 +90,131 @@ pub fn extract_embeddings(
     if let Some(embeddings) = &mut output_request.embeddings {
         embeddings.resize(n_embd * n, 0.0);
         // SAFETY: Same rationale as for the "Extract logits" section applies.
-        assert_eq!(embd.nelements(), n_embd * n);
+        assert_eq!(embeddings_tensor.nelements(), n_embd * n);
         unsafe {
-            embd.read_data(0, bytemuck::cast_slice_mut(embeddings));
+            embeddings_tensor.read_data(0, bytemuck::cast_slice_mut(embeddings));
+        }
+    }
+}
+
+/// Captures the intermediate tensors requested through
+/// [OutputRequest::intermediate_tensors] during evaluation.
+///
+/// Each captured tensor is copied into memory of its own as part of the graph, so
+/// it remains intact even if its source lives in a scratch buffer that is reused
+/// by later layers.
+pub struct IntermediateTensors {
+    requested: HashSet<String>,
+    captured: Vec<(String, Context, Tensor)>,
+}
+impl IntermediateTensors {
+    /// Prepares to capture the tensors requested by `output_request`.
+    pub fn new(output_request: &OutputRequest) -> Self {
+        Self {
+            requested: output_request
+                .intermediate_tensors
+                .keys()
+                .cloned()
+                .collect(),
+            captured: vec![],
+        }
+    }
+
+    /// Copies `tensor` out as `kind` of the layer `il`, if it was requested.
+    pub fn capture(
+        &mut self,
+        ctx0: &Context,
+        gf: &mut ComputationGraph,
+        il: usize,
+        kind: LayerTensor,
+        tensor: &Tensor,
+    ) {
+        let name = match self.requested_name(il, kind) {
+            Some(name) => name,
+            None => return,
+        };
+
+        let n_elements = tensor.nelements();
+        let size = ggml::Tensor::C_TYPE_SIZE
+            + ggml::OBJECT_SIZE
+            + n_elements * std::mem::size_of::<f32>()
+            // room for alignment
+            + 256;
+        let capture_ctx = Context::init(size, true);
+        let destination = capture_ctx.new_tensor_1d(ggml::Type::F32, n_elements);
+        gf.build_forward_expand(&ctx0.op_cpy(tensor, &destination));
+
+        self.captured.push((name, capture_ctx, destination));
+    }
+
+    /// The name of `kind` of the layer `il`, if it was requested.
+    fn requested_name(&self, il: usize, kind: LayerTensor) -> Option<String> {
+        let name = kind.name(il);
+        self.requested.contains(&name).then_some(name)
+    }
+
+    /// Writes the captured tensors to `output_request`. This must be called after
+    /// the graph has been computed.
+    pub fn extract(self, output_request: &mut OutputRequest) {
+        for (name, _capture_ctx, tensor) in self.captured {
+            if let Some(values) = output_request.intermediate_tensors.get_mut(&name) {
+                values.resize(tensor.nelements(), 0.0);
+                // SAFETY: Same rationale as for the "Extract logits" section applies;
+                // the tensor is a contiguous f32 tensor owned by `_capture_ctx`.
+                unsafe {
+                    tensor.read_data(0, bytemuck::cast_slice_mut(values));
+                }
+            }
         }
     }
 }
 
 /// Update an [InferenceSession] after evaluation
 pub fn update_session(session: &mut InferenceSession, ctx0: &Context, n_input: usize, n: usize) {
+    let used_mem = ctx0.used_mem();
+
     // Adjust the required memory per token if we didn't know that already
     if session.mem_per_token == 0 {
-        session.mem_per_token = ctx0.used_mem() / n;
+        session.mem_per_token = used_mem / n;
     }
+    session.peak_eval_memory = session.peak_eval_memory.max(used_mem);
 
     // Adjust n_past to new length.
     session.n_past += n_input;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intermediate_tensors_bookkeeping() {
+        let mut output_request = OutputRequest::default();
+        for name in ["layer.0.residual", "layer.2.attn_probs"] {
+            output_request
+                .intermediate_tensors
+                .insert(name.to_owned(), vec![]);
+        }
+
+        let tensors = IntermediateTensors::new(&output_request);
+        assert_eq!(
+            tensors.requested_name(0, LayerTensor::Residual).as_deref(),
+            Some("layer.0.residual")
+        );
+        assert_eq!(
+            tensors
+                .requested_name(2, LayerTensor::AttentionProbabilities)
+                .as_deref(),
+            Some("layer.2.attn_probs")
+        );
+        assert_eq!(tensors.requested_name(1, LayerTensor::Residual), None);
+        assert_eq!(tensors.requested_name(0, LayerTensor::MlpActivations), None);
+
+        // Nothing was captured, so the requested outputs are left as they were.
+        tensors.extract(&mut output_request);
+        assert!(output_request
+            .intermediate_tensors
+            .values()
+            .all(|values| values.is_empty()));
+    }
+}
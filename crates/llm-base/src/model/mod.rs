@@ -1,17 +1,23 @@
 //! Large language model traits and types
 
 use std::{
+    collections::HashMap,
     error::Error,
     fmt::Debug,
     io::{BufRead, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use thiserror::Error;
 
+use ggml::format::{Metadata, MetadataError, MetadataValue};
+
 use crate::{
-    loader::TensorLoader, vocabulary::TokenId, InferenceParameters, InferenceSession,
-    InferenceSessionConfig, LoadError, LoadProgress, Vocabulary,
+    loader::{SplitAxis, TensorLoader},
+    safetensors::HuggingFaceTensor,
+    vocabulary::TokenId,
+    InferenceParameters, InferenceSession, InferenceSessionConfig, LoadError, LoadProgress,
+    SpecialTokens, TokenizerKind, Vocabulary,
 };
 
 /// Common functions for model evaluation
@@ -23,6 +29,11 @@ pub trait KnownModel: Send + Sync {
     /// Hyperparameters for the model
     type Hyperparameters: Hyperparameters;
 
+    /// The name of this architecture, as given by the `model_type` of its Hugging
+    /// Face configuration. [quantize](crate::quantize) records it in the metadata of
+    /// GGKV files under [ARCHITECTURE_KEY].
+    const ARCHITECTURE: &'static str;
+
     /// Load this model from the `path` and configure it per the `params`. The status
     /// of the loading process will be reported through `load_progress_callback`. This
     /// is a helper function on top of [llm_base::load](crate::load).
@@ -37,6 +48,20 @@ pub trait KnownModel: Send + Sync {
         crate::load(path, params, load_progress_callback)
     }
 
+    /// Load this model from the Hugging Face checkpoint in `directory` and configure it
+    /// per the `params`. This is a helper function on top of
+    /// [llm_base::load_hugging_face](crate::load_hugging_face).
+    fn load_hugging_face(
+        directory: &Path,
+        params: ModelParameters,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Self, LoadError>
+    where
+        Self: Sized,
+    {
+        crate::load_hugging_face(directory, params, load_progress_callback)
+    }
+
     /// Creates a new model from the provided [ModelParameters] hyperparameters.
     /// This function is called by the [load](crate::loader::load) function.
     fn new<E: Error>(
@@ -48,6 +73,72 @@ pub trait KnownModel: Send + Sync {
     where
         Self: Sized;
 
+    /// The algorithm this architecture uses to tokenize text. [load](crate::load)
+    /// configures the vocabulary of the model with it.
+    fn tokenizer_kind() -> TokenizerKind
+    where
+        Self: Sized,
+    {
+        TokenizerKind::SentencePiece
+    }
+
+    /// The special tokens of this architecture, found in the `vocabulary` it was
+    /// loaded with. [load](crate::load) configures the vocabulary with them.
+    ///
+    /// By default, these are looked up by the pieces SentencePiece conventionally
+    /// uses for them. Any special tokens that are already registered with the
+    /// vocabulary, such as those added by an external tokenizer, are kept.
+    fn special_tokens(vocabulary: &Vocabulary) -> SpecialTokens
+    where
+        Self: Sized,
+    {
+        let mut special_tokens = vocabulary.special_tokens.clone();
+        special_tokens.bos = special_tokens.find(vocabulary, "<s>");
+        special_tokens.eos = special_tokens.find(vocabulary, "</s>");
+        special_tokens.pad = special_tokens.find(vocabulary, "<pad>");
+        special_tokens.unk = special_tokens.find(vocabulary, "<unk>");
+        special_tokens
+    }
+
+    /// The axis along which the two-dimensional tensor `name` is split between
+    /// the parts of a multipart model, or `None` if every part holds all of it.
+    /// [load](crate::load) joins the parts of the tensor along it.
+    ///
+    /// By default, no tensors are split, as only architectures with multipart
+    /// checkpoints need this.
+    fn tensor_split_axis(_name: &str) -> Option<SplitAxis>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Reads the hyperparameters of this architecture from the `config` of a Hugging
+    /// Face checkpoint. [load_hugging_face](crate::load_hugging_face) calls this.
+    ///
+    /// By default, this fails with [LoadError::HuggingFaceUnsupported].
+    fn hugging_face_hyperparameters(_config: &Metadata) -> Result<Self::Hyperparameters, LoadError>
+    where
+        Self: Sized,
+    {
+        Err(LoadError::HuggingFaceUnsupported)
+    }
+
+    /// Where the tensor `name` of this architecture is found in a Hugging Face
+    /// checkpoint with the given `hyperparameters`.
+    ///
+    /// By default, the tensor has the same name in the checkpoint, as is the case
+    /// for architectures whose tensors were named after `transformers`.
+    fn hugging_face_tensor(
+        _hyperparameters: &Self::Hyperparameters,
+        name: &str,
+    ) -> HuggingFaceTensor
+    where
+        Self: Sized,
+    {
+        HuggingFaceTensor::new(name)
+    }
+
     /// Starts a new `InferenceSession` for this model.
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession;
 
@@ -157,14 +248,47 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
 /// Implemented by model hyperparameters for interacting with hyperparameters
 /// without knowing what they are, as well as writing/reading them as required.
 pub trait Hyperparameters: Sized + Default + Debug {
+    /// The [Metadata] keys of the parameters, in the order they are laid out in the
+    /// GGML, GGMF and GGJT formats, which store each of them as an `i32`.
+    ///
+    /// A key may be repeated if the format stores its value more than once.
+    const GGML_KEYS: &'static [&'static str];
+
+    /// Read the parameters from the metadata of a model.
+    fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError>;
+
+    /// Write the parameters to the metadata of a model.
+    fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError>;
+
     /// Read the parameters in GGML format from a reader.
-    fn read_ggml(reader: &mut dyn BufRead) -> Result<Self, LoadError>;
+    fn read_ggml(reader: &mut dyn BufRead) -> Result<Self, LoadError> {
+        Self::read_metadata(&read_ggml_metadata(Self::GGML_KEYS, reader)?)
+    }
 
     /// Write the parameters in GGML format to a writer.
-    fn write_ggml(&self, writer: &mut dyn Write) -> Result<(), HyperparametersWriteError>;
+    fn write_ggml(&self, writer: &mut dyn Write) -> Result<(), HyperparametersWriteError> {
+        let mut metadata = Metadata::default();
+        self.write_metadata(&mut metadata)?;
+        for &key in Self::GGML_KEYS {
+            ggml::util::write_i32(writer, metadata.get_i32(key)?)?;
+        }
+        Ok(())
+    }
 
     /// Get the number of tokens in the vocabulary.
     fn n_vocabulary(&self) -> usize;
+
+    /// Get the number of layers in the model.
+    fn n_layer(&self) -> usize;
+
+    /// Get the size of the model's embedding layer.
+    fn n_embd(&self) -> usize;
+
+    /// Get the context size stored in the hyperparameters, if the model uses it
+    /// instead of [ModelParameters::n_context_tokens] to size its sessions.
+    fn n_context_tokens(&self) -> Option<usize> {
+        None
+    }
 }
 #[derive(Error, Debug)]
 /// Reported from functions that write
@@ -175,6 +299,36 @@ pub enum HyperparametersWriteError {
     #[error("invalid integer conversion")]
     /// One of the integers encountered could not be converted to a more appropriate type.
     InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    #[error("invalid metadata")]
+    /// A parameter could not be found in the metadata written for the model.
+    InvalidMetadata(#[from] MetadataError),
+}
+
+/// The [Metadata] key naming the [architecture](KnownModel::ARCHITECTURE) of a model.
+pub const ARCHITECTURE_KEY: &str = "architecture";
+/// The [Metadata] key holding the context size a model was trained with, if known.
+pub const CONTEXT_LENGTH_KEY: &str = "context_length";
+/// The [Metadata] key describing the file a model was converted from.
+pub const SOURCE_KEY: &str = "source";
+
+/// Reads the `i32` values of `keys`, in order, into [Metadata].
+pub(crate) fn read_ggml_metadata(
+    keys: &[&str],
+    reader: &mut dyn BufRead,
+) -> Result<Metadata, LoadError> {
+    let mut metadata = Metadata::default();
+    for &key in keys {
+        let value = MetadataValue::I32(ggml::util::read_i32(reader)?);
+        if let Some(previous) = metadata.insert(key, value.clone()) {
+            if previous != value {
+                return Err(LoadError::InvariantBroken {
+                    path: None,
+                    invariant: format!("{key} is both {previous} and {value}"),
+                });
+            }
+        }
+    }
+    Ok(metadata)
 }
 
 /// Parameters for tuning model instances
@@ -188,6 +342,14 @@ pub struct ModelParameters {
     pub n_context_tokens: usize,
     /// Default InferenceParameters to use when [evaluating](Model::evaluate) a prompt with this model.
     pub inference_parameters: InferenceParameters,
+    /// The path to a `merges.txt` file for models that use [byte-level BPE](TokenizerKind::ByteLevelBpe).
+    /// GGML files do not include the merges, so tokenization only approximates the original
+    /// tokenizer without them.
+    pub bpe_merges_path: Option<PathBuf>,
+    /// The path to a Hugging Face `tokenizer.json` to use instead of the vocabulary
+    /// embedded in the model, for models whose embedded vocabulary is lossy or
+    /// incomplete. When set, [bpe_merges_path](Self::bpe_merges_path) is ignored.
+    pub tokenizer_path: Option<PathBuf>,
 }
 
 impl Default for ModelParameters {
@@ -196,6 +358,8 @@ impl Default for ModelParameters {
             prefer_mmap: true,
             n_context_tokens: 2048,
             inference_parameters: Default::default(),
+            bpe_merges_path: None,
+            tokenizer_path: None,
         }
     }
 }
@@ -210,7 +374,98 @@ pub struct OutputRequest {
     /// evaluated or generated so far. Output shape is `n_batch * n_vocab`.
     pub all_logits: Option<Vec<f32>>,
     /// Returns all the embeddings for an evaluation. An embedding is a vector
-    /// that measures the relatedness of text strings. Output shape is
+    /// that measures the relatedness of text strings; these are the final-layer
+    /// hidden states of each token, after normalization. Output shape is
     /// `n_batch * n_embd`.
+    ///
+    /// See [embed](crate::embed) for a single embedding of a piece of text.
     pub embeddings: Option<Vec<f32>>,
+    /// Returns intermediate tensors of the model, keyed by name. To request a
+    /// tensor, insert its name (see [LayerTensor::name]) with an empty `Vec`;
+    /// after evaluation, the `Vec` will hold the tensor's values. Names that
+    /// the model does not produce are left empty.
+    pub intermediate_tensors: HashMap<String, Vec<f32>>,
+}
+
+/// The intermediate tensors of a layer that can be requested through
+/// [OutputRequest::intermediate_tensors]. These are named consistently across
+/// all models as `layer.{index}.{kind}`, with the shapes described below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LayerTensor {
+    /// `attn_probs`: the attention probabilities of each head, after the
+    /// softmax. Output shape is `n_head * n_batch * (n_past + n_batch)`.
+    AttentionProbabilities,
+    /// `attn_output`: the output of the attention block, after the output
+    /// projection. Output shape is `n_batch * n_embd`.
+    AttentionOutput,
+    /// `mlp_activations`: the activations of the feed-forward network, after
+    /// the nonlinearity. Output shape is `n_batch * n_ff`.
+    MlpActivations,
+    /// `residual`: the residual stream at the output of the layer. Output
+    /// shape is `n_batch * n_embd`.
+    Residual,
+}
+impl LayerTensor {
+    /// All of the kinds of layer tensors.
+    pub const ALL: [LayerTensor; 4] = [
+        LayerTensor::AttentionProbabilities,
+        LayerTensor::AttentionOutput,
+        LayerTensor::MlpActivations,
+        LayerTensor::Residual,
+    ];
+
+    /// The kind of this tensor, as used in its name.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LayerTensor::AttentionProbabilities => "attn_probs",
+            LayerTensor::AttentionOutput => "attn_output",
+            LayerTensor::MlpActivations => "mlp_activations",
+            LayerTensor::Residual => "residual",
+        }
+    }
+
+    /// The name of this tensor for the layer at `index`.
+    pub fn name(&self, index: usize) -> String {
+        format!("layer.{index}.{}", self.kind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_tensor_names() {
+        assert_eq!(
+            LayerTensor::AttentionProbabilities.name(3),
+            "layer.3.attn_probs"
+        );
+        assert_eq!(LayerTensor::Residual.name(0), "layer.0.residual");
+
+        // Every kind has a distinct name.
+        let names: std::collections::HashSet<_> =
+            LayerTensor::ALL.iter().map(|kind| kind.name(1)).collect();
+        assert_eq!(names.len(), LayerTensor::ALL.len());
+    }
+
+    #[test]
+    fn test_read_ggml_metadata() {
+        let bytes: Vec<u8> = [32000i32, 4096, 32000]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+
+        let keys = ["n_vocab", "n_embd", "n_vocab"];
+        let metadata = read_ggml_metadata(&keys, &mut bytes.as_slice()).unwrap();
+        assert_eq!(metadata.get_usize("n_vocab").unwrap(), 32000);
+        assert_eq!(metadata.get_usize("n_embd").unwrap(), 4096);
+        assert_eq!(metadata.len(), 2);
+
+        // A repeated key must have the same value each time.
+        let keys = ["n_vocab", "n_vocab", "n_embd"];
+        assert!(matches!(
+            read_ggml_metadata(&keys, &mut bytes.as_slice()),
+            Err(LoadError::InvariantBroken { .. })
+        ));
+    }
 }
@@ -0,0 +1,231 @@
+use crate::{
+    InferenceError, InferenceParameters, InferenceSessionConfig, Model, OutputRequest, TokenId,
+};
+
+/// The perplexity of a single context-sized window of text.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PerplexityWindow {
+    /// The index of this window within the text.
+    pub index: usize,
+    /// The number of tokens that were predicted in this window. Each window starts
+    /// with the model's beginning-of-text token, which is not predicted; if the model
+    /// has none, the first token of the text in the window is only used as context.
+    pub predicted_tokens: usize,
+    /// The perplexity of this window.
+    pub perplexity: f64,
+}
+
+/// The result of [perplexity].
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Perplexity {
+    /// The perplexity of each window of the text.
+    pub windows: Vec<PerplexityWindow>,
+    /// The perplexity across all of the windows.
+    pub perplexity: f64,
+}
+
+/// Computes the [perplexity](https://huggingface.co/docs/transformers/perplexity) of
+/// `model` on `text`.
+///
+/// The text is split into windows that fill the model's context, each of which is
+/// evaluated from an empty context and, like the perplexity tool of `llama.cpp`,
+/// starts with the beginning-of-text token. `window_callback` is called as each
+/// window is completed.
+pub fn perplexity(
+    model: &dyn Model,
+    params: &InferenceParameters,
+    session_config: InferenceSessionConfig,
+    text: &str,
+    window_callback: impl FnMut(&PerplexityWindow),
+) -> Result<Perplexity, InferenceError> {
+    let tokens: Vec<TokenId> = model
+        .vocabulary()
+        .tokenize(text, false)?
+        .iter()
+        .map(|(_, tok)| *tok)
+        .collect();
+
+    Ok(perplexity_of_tokens(
+        model,
+        params,
+        session_config,
+        &tokens,
+        window_callback,
+    ))
+}
+
+/// Computes the perplexity of `model` on the `tokens` of a text, which must not
+/// start with a beginning-of-text token.
+fn perplexity_of_tokens(
+    model: &dyn Model,
+    params: &InferenceParameters,
+    session_config: InferenceSessionConfig,
+    tokens: &[TokenId],
+    mut window_callback: impl FnMut(&PerplexityWindow),
+) -> Perplexity {
+    let n_vocab = model.vocabulary().id_to_token.len();
+    let bos = model.bot_token_id();
+    let n_text_tokens = (model.n_context_tokens() - usize::from(bos.is_some())).max(1);
+
+    let mut session = model.start_session(session_config);
+    let mut output_request = OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+
+    let mut windows = vec![];
+    let mut total_nll = 0.0;
+    let mut total_predicted = 0;
+    let mut window = Vec::with_capacity(n_text_tokens + 1);
+    for (index, text) in tokens.chunks(n_text_tokens).enumerate() {
+        window.clear();
+        window.extend(bos);
+        window.extend_from_slice(text);
+        // Every token after the first is predicted, so only text is scored.
+        let predicted_tokens = window.len() - 1;
+        if predicted_tokens == 0 {
+            continue;
+        }
+
+        session.reset();
+        let mut nll = 0.0;
+        let mut offset = 0;
+        for batch in window.chunks(params.n_batch) {
+            model.evaluate(&mut session, params, batch, &mut output_request);
+            let logits = output_request.all_logits.as_deref().unwrap_or_default();
+
+            // The logits at each position predict the token at the next position.
+            for (i, token_logits) in logits.chunks(n_vocab).take(batch.len()).enumerate() {
+                if let Some(&next_token) = window.get(offset + i + 1) {
+                    nll -= log_softmax(token_logits, next_token as usize);
+                }
+            }
+            offset += batch.len();
+        }
+
+        let window = PerplexityWindow {
+            index,
+            predicted_tokens,
+            perplexity: (nll / predicted_tokens as f64).exp(),
+        };
+        window_callback(&window);
+        windows.push(window);
+
+        total_nll += nll;
+        total_predicted += predicted_tokens;
+    }
+
+    Perplexity {
+        windows,
+        perplexity: if total_predicted > 0 {
+            (total_nll / total_predicted as f64).exp()
+        } else {
+            f64::NAN
+        },
+    }
+}
+
+/// Computes the log-probability of `index` from the unnormalized `logits`.
+pub(crate) fn log_softmax(logits: &[f32], index: usize) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let sum_exp: f64 = logits.iter().map(|&l| (l as f64 - max).exp()).sum();
+    logits[index] as f64 - max - sum_exp.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InferenceSession, Vocabulary};
+
+    #[test]
+    fn test_log_softmax() {
+        let logits = [1.0, 2.0, 3.0];
+        let probabilities: Vec<f64> = (0..3).map(|i| log_softmax(&logits, i).exp()).collect();
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(probabilities[2] > probabilities[1] && probabilities[1] > probabilities[0]);
+
+        // Large logits should not overflow.
+        let logits = [1000.0, 1000.0];
+        assert!((log_softmax(&logits, 0) - 0.5f64.ln()).abs() < 1e-9);
+    }
+
+    /// A model that predicts each token to be followed by the next of the text tokens
+    /// 1, 2 and 3 in turn, and the beginning-of-text token 0 to be followed by 1.
+    struct CyclingModel {
+        vocabulary: Vocabulary,
+        inference_parameters: InferenceParameters,
+    }
+    impl CyclingModel {
+        fn new() -> Self {
+            let mut vocabulary = Vocabulary::default();
+            for (id, token) in ["<s>", "a", "b", "c"].into_iter().enumerate() {
+                vocabulary.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+            }
+            Self {
+                vocabulary,
+                inference_parameters: Default::default(),
+            }
+        }
+    }
+    impl Model for CyclingModel {
+        fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+            InferenceSession::new(config, self.n_context_tokens(), 1, 1, 4)
+        }
+
+        fn evaluate(
+            &self,
+            session: &mut InferenceSession,
+            _params: &InferenceParameters,
+            input_tokens: &[TokenId],
+            output_request: &mut OutputRequest,
+        ) {
+            // Like the real models, only resize the buffer, and overwrite it.
+            let all_logits = output_request.all_logits.as_mut().unwrap();
+            all_logits.resize(input_tokens.len() * 4, 0.0);
+            for (token, logits) in input_tokens.iter().zip(all_logits.chunks_mut(4)) {
+                let next_token = *token as usize % 3 + 1;
+                for (id, logit) in logits.iter_mut().enumerate() {
+                    *logit = if id == next_token { 100.0 } else { 0.0 };
+                }
+            }
+            session.n_past += input_tokens.len();
+        }
+
+        fn vocabulary(&self) -> &Vocabulary {
+            &self.vocabulary
+        }
+
+        fn n_context_tokens(&self) -> usize {
+            4
+        }
+
+        fn bot_token_id(&self) -> Option<TokenId> {
+            Some(0)
+        }
+
+        fn eot_token_id(&self) -> TokenId {
+            0
+        }
+
+        fn inference_parameters(&self) -> &InferenceParameters {
+            &self.inference_parameters
+        }
+    }
+
+    #[test]
+    fn test_perplexity_windows_start_with_bos() {
+        let model = CyclingModel::new();
+        // Batches of three split each window of four tokens, and leave the logits of
+        // the first batch in the buffer when the second, shorter one is evaluated.
+        let params = InferenceParameters {
+            n_batch: 3,
+            ..Default::default()
+        };
+        let tokens = [1, 2, 3, 1, 2, 3, 1];
+
+        let result = perplexity_of_tokens(&model, &params, Default::default(), &tokens, |_| {});
+        let predicted_tokens: Vec<_> = result.windows.iter().map(|w| w.predicted_tokens).collect();
+        assert_eq!(predicted_tokens, [3, 3, 1]);
+        assert!((result.perplexity - 1.0).abs() < 1e-6);
+    }
+}
@@ -1,7 +1,8 @@
 //! Implements quantization of weights.
 
 use crate::{
-    model::HyperparametersWriteError, Hyperparameters, KnownModel, LoadError, LoadProgress, Loader,
+    model::{HyperparametersWriteError, ARCHITECTURE_KEY, CONTEXT_LENGTH_KEY, SOURCE_KEY},
+    ContainerType, Hyperparameters, KnownModel, LoadError, LoadProgress, Loader, Metadata,
 };
 use ggml::format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
 use half::f16;
@@ -112,6 +113,12 @@ pub enum QuantizeError {
     /// An error was encountered while writing the hyperparameters.
     #[error("an error was encountered while writing the hyperparameters")]
     HyperparametersWriteError(#[source] HyperparametersWriteError),
+    /// Models cannot be saved to the requested container type.
+    #[error("cannot save to {container_type}")]
+    UnsupportedContainerType {
+        /// The requested container type.
+        container_type: ContainerType,
+    },
 }
 impl QuantizeError {
     pub(crate) fn from_format_error(value: SaveError<QuantizeError>, path: PathBuf) -> Self {
@@ -122,14 +129,18 @@ impl QuantizeError {
             SaveError::InvariantBroken(invariant) => {
                 QuantizeError::InvariantBroken { path, invariant }
             }
+            SaveError::UnsupportedContainerType(container_type) => {
+                QuantizeError::UnsupportedContainerType { container_type }
+            }
         }
     }
 }
 
-/// Quantizes a model.
+/// Quantizes a model, saving it to the given container type.
 pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     reader: &mut R,
     writer: &mut W,
+    container_type: ContainerType,
     desired_type: ggml::Type,
     progress_callback: impl Fn(QuantizeProgress),
 ) -> Result<(), QuantizeError> {
@@ -156,7 +167,9 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
 
     // Save the quantized model, quantizing as we go
     let Loader {
+        container_type: source_container_type,
         hyperparameters,
+        metadata,
         vocabulary,
         tensors,
         ..
@@ -169,12 +182,20 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
         .zip(vocabulary.id_to_token_score)
         .collect::<Vec<_>>();
 
-    let mut saver = QuantizeSaver::new(desired_type, &hyperparameters, &tensors, reader, |p| {
-        progress_callback(p)
-    });
+    let mut saver = QuantizeSaver::new(
+        desired_type,
+        M::ARCHITECTURE,
+        source_container_type,
+        &hyperparameters,
+        &metadata,
+        &tensors,
+        reader,
+        |p| progress_callback(p),
+    );
     ggml::format::save(
         writer,
         &mut saver,
+        container_type,
         &vocabulary,
         &tensors.keys().cloned().collect::<Vec<_>>(),
     )
@@ -198,7 +219,10 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
 struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> {
     // Input
     quantization_type: ggml::Type,
+    architecture: &'static str,
+    source_container_type: ContainerType,
     hyperparameters: &'a H,
+    metadata: &'a Metadata,
     tensors: &'a HashMap<String, TensorLoadInfo>,
     source_reader: &'a mut R,
     progress_callback: F,
@@ -211,16 +235,23 @@ struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead
 impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
     QuantizeSaver<'a, F, H, R>
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         quantization_type: ggml::Type,
+        architecture: &'static str,
+        source_container_type: ContainerType,
         hyperparameters: &'a H,
+        metadata: &'a Metadata,
         tensors: &'a HashMap<String, TensorLoadInfo>,
         source_reader: &'a mut R,
         progress_callback: F,
     ) -> Self {
         Self {
             quantization_type,
+            architecture,
+            source_container_type,
             hyperparameters,
+            metadata,
             tensors,
             source_reader,
             progress_callback,
@@ -241,6 +272,26 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         Ok(())
     }
 
+    fn metadata(&mut self) -> Result<Metadata, QuantizeError> {
+        // Keep any metadata from the source model besides the hyperparameters,
+        // including the source of a model that was already converted to GGKV.
+        let mut metadata = self.metadata.clone();
+        self.hyperparameters
+            .write_metadata(&mut metadata)
+            .map_err(QuantizeError::HyperparametersWriteError)?;
+
+        metadata.insert(ARCHITECTURE_KEY, self.architecture);
+        if let Some(n_context_tokens) = self.hyperparameters.n_context_tokens() {
+            let n_context_tokens = u32::try_from(n_context_tokens)
+                .map_err(|err| QuantizeError::HyperparametersWriteError(err.into()))?;
+            metadata.insert(CONTEXT_LENGTH_KEY, n_context_tokens);
+        }
+        if metadata.get(SOURCE_KEY).is_none() {
+            metadata.insert(SOURCE_KEY, self.source_container_type.to_string());
+        }
+        Ok(metadata)
+    }
+
     fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, QuantizeError> {
         let tensor = self.tensors.get(tensor_name).expect(
             "tensor not found; should be impossible due to handler being populated from loader",
@@ -326,3 +377,78 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[derive(Debug, Default)]
+    struct TestHyperparameters {
+        n_ctx: usize,
+    }
+    impl Hyperparameters for TestHyperparameters {
+        const GGML_KEYS: &'static [&'static str] = &["n_ctx"];
+
+        fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError> {
+            Ok(Self {
+                n_ctx: metadata.get_usize("n_ctx")?,
+            })
+        }
+
+        fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+            metadata.insert("n_ctx", i32::try_from(self.n_ctx)?);
+            Ok(())
+        }
+
+        fn n_vocabulary(&self) -> usize {
+            0
+        }
+
+        fn n_layer(&self) -> usize {
+            0
+        }
+
+        fn n_embd(&self) -> usize {
+            0
+        }
+
+        fn n_context_tokens(&self) -> Option<usize> {
+            Some(self.n_ctx)
+        }
+    }
+
+    fn saved_metadata(source_metadata: &Metadata) -> Metadata {
+        let hyperparameters = TestHyperparameters { n_ctx: 2048 };
+        let tensors = HashMap::new();
+        let mut reader = Cursor::new(vec![]);
+        let mut saver = QuantizeSaver::new(
+            ggml::Type::Q4_0,
+            "gptj",
+            ContainerType::Ggjt(3),
+            &hyperparameters,
+            source_metadata,
+            &tensors,
+            &mut reader,
+            |_| {},
+        );
+        saver.metadata().unwrap()
+    }
+
+    #[test]
+    fn test_metadata_describes_model() {
+        let metadata = saved_metadata(&Metadata::default());
+        assert_eq!(metadata.get_str(ARCHITECTURE_KEY).unwrap(), "gptj");
+        assert_eq!(metadata.get_u32(CONTEXT_LENGTH_KEY).unwrap(), 2048);
+        assert_eq!(metadata.get_str(SOURCE_KEY).unwrap(), "GGJT v3");
+        assert_eq!(metadata.get_usize("n_ctx").unwrap(), 2048);
+    }
+
+    #[test]
+    fn test_metadata_keeps_source() {
+        let mut source_metadata = Metadata::default();
+        source_metadata.insert(SOURCE_KEY, "GGMF v1");
+        let metadata = saved_metadata(&source_metadata);
+        assert_eq!(metadata.get_str(SOURCE_KEY).unwrap(), "GGMF v1");
+    }
+}
@@ -0,0 +1,729 @@
+//! Loading models directly from Hugging Face checkpoints, which store their weights
+//! in [safetensors](https://github.com/huggingface/safetensors) files.
+//!
+//! A checkpoint directory holds a `config.json` with the hyperparameters, a
+//! `tokenizer.json` with the vocabulary, and either a single `model.safetensors` or
+//! several shards listed by a `model.safetensors.index.json`.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use ggml::Context;
+use half::f16;
+use memmap2::Mmap;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    tokenizer_json, ElementType, Hyperparameters, KnownModel, LoadError, LoadProgress, Metadata,
+    ModelParameters, TensorLoader,
+};
+
+/// The index of a sharded checkpoint, mapping each tensor to the shard that holds it.
+const INDEX_FILE_NAME: &str = "model.safetensors.index.json";
+
+/// Where a tensor of an architecture is found in a Hugging Face checkpoint, as
+/// returned by [KnownModel::hugging_face_tensor].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuggingFaceTensor {
+    /// The name of the tensor in the checkpoint.
+    pub name: String,
+    /// How the tensor is laid out in the checkpoint.
+    pub layout: HuggingFaceLayout,
+}
+impl HuggingFaceTensor {
+    /// The tensor `name` of the checkpoint, used as-is.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            layout: HuggingFaceLayout::Same,
+        }
+    }
+
+    /// The tensor `name` of the checkpoint, whose rows were permuted for `n_head` heads.
+    pub fn unpermuted(name: impl Into<String>, n_head: usize) -> Self {
+        Self {
+            name: name.into(),
+            layout: HuggingFaceLayout::PermutedHeads(n_head),
+        }
+    }
+
+    /// The tensor `name` of the checkpoint, which is stored transposed.
+    pub fn transposed(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            layout: HuggingFaceLayout::Transposed,
+        }
+    }
+
+    /// The fused query, key and value tensor `name` of the checkpoint, whose rows
+    /// are grouped by each of `n_head` heads.
+    pub fn deinterleaved(name: impl Into<String>, n_head: usize) -> Self {
+        Self {
+            name: name.into(),
+            layout: HuggingFaceLayout::InterleavedHeads(n_head),
+        }
+    }
+}
+
+/// How a tensor of a Hugging Face checkpoint differs from the layout `ggml` expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HuggingFaceLayout {
+    /// The tensor is stored as `ggml` expects it.
+    Same,
+    /// The rows of the tensor were permuted per attention head for the rotary
+    /// embeddings of `transformers`, and must be restored for this many heads.
+    ///
+    /// This is the case for the query and key weights of LLaMA checkpoints.
+    PermutedHeads(usize),
+    /// The tensor is stored transposed.
+    ///
+    /// This is the case for the `Conv1D` weights of GPT-2 checkpoints.
+    Transposed,
+    /// The rows of a fused query, key and value tensor hold the query, key and value
+    /// of each of this many heads in turn, rather than all the queries, then all the
+    /// keys, then all the values.
+    ///
+    /// This is the case for the attention weights and biases of BLOOM checkpoints.
+    InterleavedHeads(usize),
+}
+
+/// Load a model from the Hugging Face checkpoint in `directory` and configure it
+/// per the `params`. The status of the loading process will be reported through
+/// `load_progress_callback`.
+///
+/// The hyperparameters are read from `config.json` by
+/// [KnownModel::hugging_face_hyperparameters], and the vocabulary from
+/// `tokenizer.json`, unless [ModelParameters::tokenizer_path] is set. The tensors
+/// are read from the safetensors files, and found through
+/// [KnownModel::hugging_face_tensor]. `bf16` tensors, and one-dimensional `f16`
+/// tensors such as norms and biases, are converted to `f32`.
+///
+/// The tensors are always copied into memory, rather than memory-mapped.
+pub fn load_hugging_face<M: KnownModel>(
+    directory: &Path,
+    params: ModelParameters,
+    mut load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let config = load_hugging_face_config(directory)?;
+    let hyperparameters = M::hugging_face_hyperparameters(&config)?;
+    (load_progress_callback)(LoadProgress::HyperparametersLoaded);
+
+    let tokenizer_path = params
+        .tokenizer_path
+        .clone()
+        .unwrap_or_else(|| directory.join("tokenizer.json"));
+    let mut vocabulary = tokenizer_json::load(&tokenizer_path)?;
+    // Models are often padded past the end of their tokenizer.
+    let n_vocabulary = hyperparameters.n_vocabulary();
+    if vocabulary.id_to_token.len() > n_vocabulary {
+        return Err(LoadError::InvalidVocabularySize {
+            expected: n_vocabulary,
+            actual: vocabulary.id_to_token.len(),
+        });
+    }
+    vocabulary.pad_to(n_vocabulary);
+    vocabulary.special_tokens = M::special_tokens(&vocabulary);
+
+    let checkpoint = Checkpoint::open(directory)?;
+    let ctx_size = checkpoint.context_size();
+    (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
+    let context = Context::init(ctx_size, true);
+
+    struct SafeTensorsLoader<'a, M: KnownModel> {
+        checkpoint: &'a Checkpoint,
+        hyperparameters: M::Hyperparameters,
+        context: Context,
+        load_progress_callback: &'a mut dyn FnMut(LoadProgress),
+        loaded_tensors: HashMap<String, ggml::Tensor>,
+        /// The loaded tensors, keyed by their name in the checkpoint.
+        loaded_sources: HashMap<String, ggml::Tensor>,
+    }
+    impl<M: KnownModel> TensorLoader<LoadError> for SafeTensorsLoader<'_, M> {
+        fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
+            let source = M::hugging_face_tensor(&self.hyperparameters, name);
+            let info = self.checkpoint.tensor(&source.name, name)?;
+            let dims = match source.layout {
+                HuggingFaceLayout::Transposed => info.shape.clone(),
+                _ => info.dims(),
+            };
+            self.load_manual(name, &dims)
+        }
+
+        fn load_manual(&mut self, name: &str, ne: &[usize]) -> Result<ggml::Tensor, LoadError> {
+            let source = M::hugging_face_tensor(&self.hyperparameters, name);
+            let info = self.checkpoint.tensor(&source.name, name)?;
+            let path = &self.checkpoint.paths[info.file];
+
+            if ne.len() != info.shape.len() || ne.iter().product::<usize>() != info.n_elements() {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: name.to_owned(),
+                    path: path.clone(),
+                });
+            }
+            // Architectures that tie their output weights to their embeddings load the
+            // same checkpoint tensor twice, but the context only has room for it once.
+            if let Some(tensor) = self.loaded_sources.get(&source.name).map(|t| t.share()) {
+                self.loaded_tensors.insert(name.to_owned(), tensor.share());
+                return Ok(tensor);
+            }
+            let dtype = Dtype::parse(&info.dtype).ok_or_else(|| LoadError::UnsupportedDtype {
+                tensor_name: source.name.clone(),
+                dtype: info.dtype.clone(),
+                path: path.clone(),
+            })?;
+
+            let ctx = &self.context;
+            let element_type = dtype.element_type(ne.len());
+            let mut tensor = match ne.len() {
+                1 => ctx.new_tensor_1d(element_type, ne[0]),
+                2 => ctx.new_tensor_2d(element_type, ne[0], ne[1]),
+                3 => ctx.new_tensor_3d(element_type, ne[0], ne[1], ne[2]),
+                _ => {
+                    return Err(LoadError::InvariantBroken {
+                        path: Some(path.clone()),
+                        invariant: format!(
+                            "the tensor {name} had an unsupported dimension count: {ne:?}"
+                        ),
+                    })
+                }
+            };
+
+            let buf: &mut [u8] = unsafe {
+                std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
+            };
+            let data = dtype.convert(self.checkpoint.data(info), ne.len());
+            match source.layout {
+                HuggingFaceLayout::Same => buf.copy_from_slice(&data),
+                HuggingFaceLayout::PermutedHeads(n_head) => {
+                    unpermute_rows(buf, &data, info.shape[0], n_head)
+                }
+                HuggingFaceLayout::Transposed => transpose(buf, &data, &info.shape),
+                HuggingFaceLayout::InterleavedHeads(n_head) => {
+                    deinterleave_rows(buf, &data, info.shape[0], n_head)
+                }
+            }
+
+            self.loaded_sources.insert(source.name, tensor.share());
+            self.loaded_tensors.insert(name.to_owned(), tensor.share());
+            (self.load_progress_callback)(LoadProgress::TensorLoaded {
+                current_tensor: self.loaded_tensors.len(),
+                tensor_count: self.checkpoint.tensors.len(),
+            });
+
+            Ok(tensor)
+        }
+
+        fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>) {
+            (self.context, self.loaded_tensors, None)
+        }
+    }
+
+    let tl: SafeTensorsLoader<M> = SafeTensorsLoader {
+        checkpoint: &checkpoint,
+        // The model takes ownership of its hyperparameters, but the loader needs them
+        // to find the tensors, so they are read again.
+        hyperparameters: M::hugging_face_hyperparameters(&config)?,
+        context,
+        load_progress_callback: &mut load_progress_callback,
+        loaded_tensors: Default::default(),
+        loaded_sources: Default::default(),
+    };
+
+    let model = KnownModel::new(hyperparameters, params, vocabulary, tl)?;
+
+    (load_progress_callback)(LoadProgress::Loaded {
+        file_size: checkpoint.file_size(),
+        tensor_count: checkpoint.tensors.len(),
+    });
+
+    Ok(model)
+}
+
+/// Reads the scalar values of the `config.json` of the Hugging Face checkpoint in
+/// `directory` as [Metadata]. Integers become `i32`s, or `u32`s if they are too
+/// large, and other numbers become `f32`s. Nested objects, arrays and nulls are
+/// skipped.
+pub fn load_hugging_face_config(directory: &Path) -> Result<Metadata, LoadError> {
+    let path = &directory.join("config.json");
+    let contents = std::fs::read_to_string(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let invalid = |reason: String| LoadError::InvalidHuggingFaceCheckpoint {
+        path: path.to_owned(),
+        reason,
+    };
+    let config: HashMap<String, Value> =
+        serde_json::from_str(&contents).map_err(|err| invalid(err.to_string()))?;
+    Ok(config_metadata(config))
+}
+
+fn config_metadata(config: HashMap<String, Value>) -> Metadata {
+    let mut metadata = Metadata::default();
+    for (key, value) in config {
+        match value {
+            Value::Bool(value) => {
+                metadata.insert(key, value);
+            }
+            Value::String(value) => {
+                metadata.insert(key, value);
+            }
+            Value::Number(number) => {
+                if let Some(value) = number.as_i64().and_then(|n| i32::try_from(n).ok()) {
+                    metadata.insert(key, value);
+                } else if let Some(value) = number.as_u64().and_then(|n| u32::try_from(n).ok()) {
+                    metadata.insert(key, value);
+                } else if let Some(value) = number.as_f64() {
+                    metadata.insert(key, value as f32);
+                }
+            }
+            Value::Null | Value::Array(_) | Value::Object(_) => {}
+        }
+    }
+    metadata
+}
+
+/// The safetensors files of a checkpoint, and the tensors they hold.
+struct Checkpoint {
+    paths: Vec<PathBuf>,
+    mmaps: Vec<Mmap>,
+    tensors: HashMap<String, TensorInfo>,
+}
+impl Checkpoint {
+    /// Memory-maps the safetensors files in `directory`: the shards listed by its
+    /// index if there is one, or every `.safetensors` file otherwise.
+    fn open(directory: &Path) -> Result<Self, LoadError> {
+        let index_path = directory.join(INDEX_FILE_NAME);
+        let paths = if index_path.exists() {
+            read_index(&index_path)?
+                .into_iter()
+                .map(|file| directory.join(file))
+                .collect()
+        } else {
+            let mut paths = vec![];
+            let entries = std::fs::read_dir(directory).map_err(|e| LoadError::OpenFileFailed {
+                source: e,
+                path: directory.to_owned(),
+            })?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().map_or(false, |ext| ext == "safetensors") {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+            paths
+        };
+        if paths.is_empty() {
+            return Err(LoadError::InvalidHuggingFaceCheckpoint {
+                path: directory.to_owned(),
+                reason: "there are no .safetensors files".to_owned(),
+            });
+        }
+
+        let mut mmaps = Vec::with_capacity(paths.len());
+        let mut tensors = HashMap::new();
+        for (index, path) in paths.iter().enumerate() {
+            let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+                source: e,
+                path: path.clone(),
+            })?;
+            let mmap = unsafe { Mmap::map(&file)? };
+            let header =
+                parse_header(&mmap).map_err(|reason| LoadError::InvalidHuggingFaceCheckpoint {
+                    path: path.clone(),
+                    reason,
+                })?;
+            for (name, mut info) in header {
+                info.file = index;
+                tensors.insert(name, info);
+            }
+            mmaps.push(mmap);
+        }
+
+        Ok(Self {
+            paths,
+            mmaps,
+            tensors,
+        })
+    }
+
+    /// Gets the checkpoint tensor `name`, which holds the model tensor `model_name`.
+    fn tensor(&self, name: &str, model_name: &str) -> Result<&TensorInfo, LoadError> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| LoadError::UnknownTensor {
+                tensor_name: model_name.to_owned(),
+                path: self.paths[0].clone(),
+            })
+    }
+
+    /// The data of the tensor described by `info`.
+    fn data(&self, info: &TensorInfo) -> &[u8] {
+        &self.mmaps[info.file][info.start..info.end]
+    }
+
+    /// The size of a context that can hold every tensor of the checkpoint that has a
+    /// supported data type, once converted.
+    fn context_size(&self) -> usize {
+        self.tensors
+            .values()
+            .filter_map(|info| {
+                let dtype = Dtype::parse(&info.dtype)?;
+                Some(
+                    ggml::Tensor::C_TYPE_SIZE
+                        + ggml::OBJECT_SIZE
+                        + info.n_elements() * dtype.converted_size(info.shape.len()),
+                )
+            })
+            .sum()
+    }
+
+    fn file_size(&self) -> u64 {
+        self.mmaps.iter().map(|mmap| mmap.len() as u64).sum()
+    }
+}
+
+/// Reads the names of the shards listed by the index at `path`.
+fn read_index(path: &Path) -> Result<BTreeSet<String>, LoadError> {
+    #[derive(Deserialize)]
+    struct Index {
+        weight_map: HashMap<String, String>,
+    }
+
+    let contents = std::fs::read_to_string(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let index: Index =
+        serde_json::from_str(&contents).map_err(|err| LoadError::InvalidHuggingFaceCheckpoint {
+            path: path.to_owned(),
+            reason: err.to_string(),
+        })?;
+    Ok(index.weight_map.into_values().collect())
+}
+
+/// A tensor in a safetensors file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TensorInfo {
+    /// The index of the file in the checkpoint.
+    file: usize,
+    dtype: String,
+    /// The shape, outermost dimension first.
+    shape: Vec<usize>,
+    /// The offsets of the data from the start of the file.
+    start: usize,
+    end: usize,
+}
+impl TensorInfo {
+    fn n_elements(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// The shape in `ggml` order, innermost dimension first.
+    fn dims(&self) -> Vec<usize> {
+        self.shape.iter().rev().copied().collect()
+    }
+}
+
+/// Parses the header of the safetensors file `data`: a little-endian `u64` length,
+/// followed by a JSON object describing each tensor. Offsets are made relative to
+/// the start of the file.
+fn parse_header(data: &[u8]) -> Result<HashMap<String, TensorInfo>, String> {
+    #[derive(Deserialize)]
+    struct RawTensorInfo {
+        dtype: String,
+        shape: Vec<usize>,
+        data_offsets: (usize, usize),
+    }
+
+    let len_bytes: [u8; 8] = data
+        .get(..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("the file is too short for a header")?;
+    let header_len = usize::try_from(u64::from_le_bytes(len_bytes))
+        .ok()
+        .filter(|&len| len <= data.len() - 8)
+        .ok_or("the header is longer than the file")?;
+    let data_start = 8 + header_len;
+
+    let header: HashMap<String, Value> =
+        serde_json::from_slice(&data[8..data_start]).map_err(|err| err.to_string())?;
+
+    let mut tensors = HashMap::new();
+    for (name, value) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let raw: RawTensorInfo =
+            serde_json::from_value(value).map_err(|err| format!("invalid tensor {name}: {err}"))?;
+        let (begin, end) = raw.data_offsets;
+        let info = TensorInfo {
+            file: 0,
+            dtype: raw.dtype,
+            shape: raw.shape,
+            start: data_start + begin,
+            end: data_start + end,
+        };
+        if begin > end || info.end > data.len() {
+            return Err(format!("the data of tensor {name} is out of bounds"));
+        }
+        if let Some(dtype) = Dtype::parse(&info.dtype) {
+            if info.end - info.start != info.n_elements() * dtype.size() {
+                return Err(format!("the tensor {name} has the wrong size"));
+            }
+        }
+        tensors.insert(name, info);
+    }
+    Ok(tensors)
+}
+
+/// The data types of safetensors tensors that can be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dtype {
+    F32,
+    F16,
+    BF16,
+}
+impl Dtype {
+    fn parse(dtype: &str) -> Option<Self> {
+        match dtype {
+            "F32" => Some(Dtype::F32),
+            "F16" => Some(Dtype::F16),
+            "BF16" => Some(Dtype::BF16),
+            _ => None,
+        }
+    }
+
+    /// The size of an element in the file.
+    fn size(self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F16 | Dtype::BF16 => 2,
+        }
+    }
+
+    /// The type in `ggml` of a tensor with `n_dims` dimensions. `ggml` has no `bf16`
+    /// type, and the one-dimensional tensors that the models add to or multiply
+    /// with their activations must be `f32`, as they are in converted GGML files.
+    fn element_type(self, n_dims: usize) -> ElementType {
+        match self {
+            Dtype::F16 if n_dims > 1 => ElementType::F16,
+            Dtype::F32 | Dtype::F16 | Dtype::BF16 => ElementType::F32,
+        }
+    }
+
+    /// The size of an element once converted to [Dtype::element_type].
+    fn converted_size(self, n_dims: usize) -> usize {
+        match self.element_type(n_dims) {
+            ElementType::F16 => 2,
+            _ => 4,
+        }
+    }
+
+    /// Converts the data of a tensor with `n_dims` dimensions to [Dtype::element_type].
+    fn convert(self, data: &[u8], n_dims: usize) -> Vec<u8> {
+        match self {
+            Dtype::F32 => data.to_vec(),
+            Dtype::F16 if n_dims > 1 => data.to_vec(),
+            Dtype::F16 => data
+                .chunks_exact(2)
+                .flat_map(|bytes| {
+                    f16::from_le_bytes([bytes[0], bytes[1]])
+                        .to_f32()
+                        .to_le_bytes()
+                })
+                .collect(),
+            // A `bf16` is the upper half of an `f32`.
+            Dtype::BF16 => data
+                .chunks_exact(2)
+                .flat_map(|bf16| [0, 0, bf16[0], bf16[1]])
+                .collect(),
+        }
+    }
+}
+
+/// Copies the `n_rows` rows of `src` to `dst`, undoing the permutation that
+/// `transformers` applies to the query and key weights of LLaMA for its rotary
+/// embeddings. Within each of the `n_head` heads, `transformers` stores the even
+/// rows followed by the odd rows; `ggml` interleaves them.
+fn unpermute_rows(dst: &mut [u8], src: &[u8], n_rows: usize, n_head: usize) {
+    let row_bytes = src.len() / n_rows;
+    let head_rows = n_rows / n_head;
+    let half = head_rows / 2;
+    for head in 0..n_head {
+        for i in 0..half {
+            for j in 0..2 {
+                let dst_row = head * head_rows + i * 2 + j;
+                let src_row = head * head_rows + j * half + i;
+                dst[dst_row * row_bytes..(dst_row + 1) * row_bytes]
+                    .copy_from_slice(&src[src_row * row_bytes..(src_row + 1) * row_bytes]);
+            }
+        }
+    }
+}
+
+/// Copies the two-dimensional `src` of the given `shape`, outermost dimension
+/// first, to `dst` transposed.
+fn transpose(dst: &mut [u8], src: &[u8], shape: &[usize]) {
+    let (n_rows, n_cols) = (shape[0], shape[1]);
+    let element_size = src.len() / (n_rows * n_cols);
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            let src_start = (row * n_cols + col) * element_size;
+            let dst_start = (col * n_rows + row) * element_size;
+            dst[dst_start..dst_start + element_size]
+                .copy_from_slice(&src[src_start..src_start + element_size]);
+        }
+    }
+}
+
+/// Copies the `n_rows` rows of `src` to `dst`, regrouping the rows of a fused query,
+/// key and value tensor. `transformers` stores the query, key and value rows of each
+/// of the `n_head` heads in turn; `ggml` expects all the query rows, then all the key
+/// rows, then all the value rows.
+fn deinterleave_rows(dst: &mut [u8], src: &[u8], n_rows: usize, n_head: usize) {
+    let row_bytes = src.len() / n_rows;
+    let head_rows = n_rows / (3 * n_head);
+    for head in 0..n_head {
+        for part in 0..3 {
+            for i in 0..head_rows {
+                let src_row = (head * 3 + part) * head_rows + i;
+                let dst_row = (part * n_head + head) * head_rows + i;
+                dst[dst_row * row_bytes..(dst_row + 1) * row_bytes]
+                    .copy_from_slice(&src[src_row * row_bytes..(src_row + 1) * row_bytes]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn safetensors_file(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut file = (header.len() as u64).to_le_bytes().to_vec();
+        file.extend_from_slice(header.as_bytes());
+        file.extend_from_slice(data);
+        file
+    }
+
+    #[test]
+    fn can_parse_header() {
+        let header = r#"{
+            "__metadata__": {"format": "pt"},
+            "a": {"dtype": "F16", "shape": [2, 3], "data_offsets": [0, 12]},
+            "b": {"dtype": "BOOL", "shape": [1], "data_offsets": [12, 13]}
+        }"#;
+        let file = safetensors_file(header, &[0; 13]);
+        let tensors = parse_header(&file).unwrap();
+
+        assert_eq!(tensors.len(), 2);
+        let a = &tensors["a"];
+        assert_eq!(a.start, 8 + header.len());
+        assert_eq!(a.end, 8 + header.len() + 12);
+        assert_eq!(a.dims(), vec![3, 2]);
+        assert_eq!(tensors["b"].dtype, "BOOL");
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let header = r#"{"a": {"dtype": "F32", "shape": [2], "data_offsets": [0, 4]}}"#;
+        assert!(parse_header(&safetensors_file(header, &[0; 4])).is_err());
+
+        let header = r#"{"a": {"dtype": "F32", "shape": [2], "data_offsets": [0, 8]}}"#;
+        assert!(parse_header(&safetensors_file(header, &[0; 4])).is_err());
+
+        assert!(parse_header(&[0xFF; 8]).is_err());
+    }
+
+    #[test]
+    fn can_convert_bf16() {
+        let values = [1.0f32, -2.5, 0.15625];
+        let bf16: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes()[2..].to_vec())
+            .collect();
+        let converted = Dtype::BF16.convert(&bf16, 2);
+        let converted: Vec<f32> = converted
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(converted, values);
+    }
+
+    #[test]
+    fn converts_one_dimensional_f16_to_f32() {
+        let values = [1.0f32, -2.5, 0.15625];
+        let data: Vec<u8> = values
+            .iter()
+            .flat_map(|&value| f16::from_f32(value).to_le_bytes())
+            .collect();
+
+        assert_eq!(Dtype::F16.element_type(2), ElementType::F16);
+        assert_eq!(Dtype::F16.converted_size(2), 2);
+        assert_eq!(Dtype::F16.convert(&data, 2), data);
+
+        assert_eq!(Dtype::F16.element_type(1), ElementType::F32);
+        assert_eq!(Dtype::F16.converted_size(1), 4);
+        let converted: Vec<f32> = Dtype::F16
+            .convert(&data, 1)
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(converted, values);
+    }
+
+    #[test]
+    fn can_unpermute_rows() {
+        // Two heads of four one-byte rows, each stored as its even rows then its odd rows.
+        let src = [0, 2, 1, 3, 4, 6, 5, 7];
+        let mut dst = [0; 8];
+        unpermute_rows(&mut dst, &src, 8, 2);
+        assert_eq!(dst, [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn can_transpose() {
+        // Two rows of three two-byte elements.
+        let src = [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5];
+        let mut dst = [0; 12];
+        transpose(&mut dst, &src, &[2, 3]);
+        assert_eq!(dst, [0, 0, 3, 3, 1, 1, 4, 4, 2, 2, 5, 5]);
+    }
+
+    #[test]
+    fn can_deinterleave_rows() {
+        // Two heads of one-byte query, key and value rows, two rows each.
+        let src = [0, 1, 4, 5, 8, 9, 2, 3, 6, 7, 10, 11];
+        let mut dst = [0; 12];
+        deinterleave_rows(&mut dst, &src, 12, 2);
+        assert_eq!(dst, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn can_read_config_scalars() {
+        let config = serde_json::from_str(
+            r#"{
+                "hidden_size": 4096,
+                "rms_norm_eps": 1e-06,
+                "tie_word_embeddings": false,
+                "torch_dtype": "float16",
+                "architectures": ["LlamaForCausalLM"],
+                "rope_scaling": null
+            }"#,
+        )
+        .unwrap();
+        let metadata = config_metadata(config);
+
+        assert_eq!(metadata.len(), 4);
+        assert_eq!(metadata.get_usize("hidden_size").unwrap(), 4096);
+        assert_eq!(metadata.get_f32("rms_norm_eps").unwrap(), 1e-6);
+        assert!(!metadata.get_bool("tie_word_embeddings").unwrap());
+        assert_eq!(metadata.get_str("torch_dtype").unwrap(), "float16");
+    }
+}
@@ -0,0 +1,161 @@
+use crate::TokenId;
+
+/// Maps tokens to their IDs with a byte trie.
+///
+/// Looking up a token takes time proportional to its length, without hashing or
+/// allocating, and the concatenation of two tokens can be looked up without
+/// building it first. This makes it well-suited to the repeated lookups that
+/// tokenization performs.
+#[derive(Debug, Clone)]
+pub struct TokenTrie {
+    nodes: Vec<Node>,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    id: Option<TokenId>,
+    /// The children of this node, sorted by the byte that leads to them.
+    children: Vec<(u8, u32)>,
+}
+
+impl Default for TokenTrie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+            len: 0,
+        }
+    }
+}
+
+impl TokenTrie {
+    /// Creates an empty trie.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of tokens in the trie.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the trie has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maps `token` to `id`, returning the ID it was previously mapped to.
+    pub fn insert(&mut self, token: &[u8], id: TokenId) -> Option<TokenId> {
+        let mut node = 0;
+        for &byte in token {
+            node = match self.child(node, byte) {
+                Ok(child) => child,
+                Err(index) => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[node]
+                        .children
+                        .insert(index, (byte, child as u32));
+                    child
+                }
+            };
+        }
+
+        let previous = self.nodes[node].id.replace(id);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    /// The ID of `token`, if it is in the trie.
+    pub fn get(&self, token: &[u8]) -> Option<TokenId> {
+        self.get_concat(token, &[])
+    }
+
+    /// The ID of the concatenation of `left` and `right`, if it is in the trie.
+    pub fn get_concat(&self, left: &[u8], right: &[u8]) -> Option<TokenId> {
+        let mut node = 0;
+        for &byte in left.iter().chain(right) {
+            node = self.child(node, byte).ok()?;
+        }
+        self.nodes[node].id
+    }
+
+    /// Whether `token` is in the trie.
+    pub fn contains_key(&self, token: &[u8]) -> bool {
+        self.get(token).is_some()
+    }
+
+    /// The IDs of the tokens that start with `prefix`, including `prefix` itself.
+    pub fn starting_with(&self, prefix: &[u8]) -> Vec<TokenId> {
+        let mut node = 0;
+        for &byte in prefix {
+            node = match self.child(node, byte) {
+                Ok(child) => child,
+                Err(_) => return vec![],
+            };
+        }
+
+        let mut ids = vec![];
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            ids.extend(node.id);
+            stack.extend(node.children.iter().map(|&(_, child)| child as usize));
+        }
+        ids
+    }
+
+    /// The IDs and lengths of the tokens that `text` starts with, from shortest
+    /// to longest.
+    pub fn prefixes_of(&self, text: &[u8]) -> Vec<(TokenId, usize)> {
+        let mut node = 0;
+        let mut prefixes: Vec<_> = self.nodes[node].id.map(|id| (id, 0)).into_iter().collect();
+        for (index, &byte) in text.iter().enumerate() {
+            node = match self.child(node, byte) {
+                Ok(child) => child,
+                Err(_) => break,
+            };
+            prefixes.extend(self.nodes[node].id.map(|id| (id, index + 1)));
+        }
+        prefixes
+    }
+
+    /// The index of the child of `node` for `byte`, or where it would be inserted
+    /// into the children of `node` if there is none.
+    fn child(&self, node: usize, byte: u8) -> Result<usize, usize> {
+        let children = &self.nodes[node].children;
+        children
+            .binary_search_by_key(&byte, |&(b, _)| b)
+            .map(|index| children[index].1 as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut trie = TokenTrie::new();
+        assert!(trie.is_empty());
+        assert_eq!(trie.insert(b"he", 0), None);
+        assert_eq!(trie.insert(b"hello", 1), None);
+        assert_eq!(trie.insert(b"", 2), None);
+        assert_eq!(trie.insert(b"he", 3), Some(0));
+        assert_eq!(trie.len(), 3);
+
+        assert_eq!(trie.get(b"he"), Some(3));
+        assert_eq!(trie.get(b"hell"), None);
+        assert_eq!(trie.get(b""), Some(2));
+        assert_eq!(trie.get_concat(b"hel", b"lo"), Some(1));
+        assert!(!trie.contains_key(b"hello!"));
+
+        let mut starting_with = trie.starting_with(b"he");
+        starting_with.sort();
+        assert_eq!(starting_with, [1, 3]);
+        assert!(trie.starting_with(b"x").is_empty());
+        assert_eq!(trie.prefixes_of(b"hello!"), [(2, 0), (3, 2), (1, 5)]);
+    }
+}
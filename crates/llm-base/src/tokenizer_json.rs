@@ -0,0 +1,259 @@
+//! Support for the `tokenizer.json` files used by Hugging Face's `tokenizers`.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    bpe,
+    vocabulary::{self, Token, TokenScore},
+    LoadError, SpecialTokens, TokenId, TokenizerKind, Vocabulary,
+};
+
+#[derive(Deserialize)]
+struct TokenizerJson {
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    #[serde(default)]
+    pre_tokenizer: Value,
+    #[serde(default)]
+    decoder: Value,
+    model: ModelJson,
+}
+
+#[derive(Deserialize)]
+struct AddedToken {
+    id: TokenId,
+    content: String,
+    #[serde(default)]
+    special: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum ModelJson {
+    #[serde(rename = "BPE")]
+    Bpe {
+        vocab: HashMap<String, TokenId>,
+        #[serde(default)]
+        merges: Vec<MergeJson>,
+    },
+    Unigram {
+        vocab: Vec<(String, TokenScore)>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MergeJson {
+    /// `"a b"`, as written by most versions of `tokenizers`.
+    Joined(String),
+    /// `["a", "b"]`, as written by newer versions of `tokenizers`.
+    Split(String, String),
+}
+
+/// Loads the `tokenizer.json` at `path` as a [Vocabulary].
+pub(crate) fn load(path: &Path) -> Result<Vocabulary, LoadError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    parse(&contents).map_err(|reason| LoadError::InvalidTokenizer {
+        path: path.to_owned(),
+        reason,
+    })
+}
+
+/// Converts the contents of a `tokenizer.json` to a [Vocabulary].
+///
+/// Tokenizers that use byte-level pre-tokenization are converted to
+/// [TokenizerKind::ByteLevelBpe], with their tokens stored as raw bytes. All
+/// others are treated as SentencePiece tokenizers, and converted to the
+/// conventions of GGML files: `▁` is stored as a space, and byte tokens as the
+/// byte they represent. BPE merges are turned into scores, so that earlier
+/// merges are preferred.
+pub(crate) fn parse(contents: &str) -> Result<Vocabulary, String> {
+    let json: TokenizerJson = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    let byte_level = is_byte_level(&json.pre_tokenizer) || is_byte_level(&json.decoder);
+    let byte_to_unicode = bpe::byte_to_unicode();
+
+    let convert = |text: &str| -> Token {
+        if byte_level {
+            bpe::unicode_to_bytes(text, &byte_to_unicode).unwrap_or_else(|| text.into())
+        } else {
+            match vocabulary::parse_byte_piece(text.as_bytes()) {
+                Some(byte) => vec![byte],
+                None => text.replace('\u{2581}', " ").into_bytes(),
+            }
+        }
+    };
+
+    let mut tokens: Vec<Option<(Token, TokenScore)>> = vec![];
+    let mut set_token = |id: TokenId, token: Token, score: TokenScore| -> Result<(), String> {
+        let index = usize::try_from(id).map_err(|_| format!("invalid token id {id}"))?;
+        if tokens.len() <= index {
+            tokens.resize(index + 1, None);
+        }
+        tokens[index] = Some((token, score));
+        Ok(())
+    };
+
+    let mut bpe_merges = HashMap::new();
+    match json.model {
+        ModelJson::Bpe { vocab, merges } => {
+            let mut scores = HashMap::new();
+            for (rank, merge) in merges.iter().enumerate() {
+                let (left, right) = match merge {
+                    MergeJson::Joined(merge) => merge
+                        .split_once(' ')
+                        .ok_or_else(|| format!("invalid merge {merge:?}"))?,
+                    MergeJson::Split(left, right) => (left.as_str(), right.as_str()),
+                };
+                let (left, right) = (convert(left), convert(right));
+                scores
+                    .entry([left.as_slice(), right.as_slice()].concat())
+                    .or_insert(-(rank as TokenScore));
+                if byte_level {
+                    bpe_merges.entry((left, right)).or_insert(rank);
+                }
+            }
+
+            // Tokens that are not the result of a merge are never merged into.
+            let unmerged_score = -(merges.len() as TokenScore);
+            for (text, id) in vocab {
+                let token = convert(&text);
+                let score = scores.get(&token).copied().unwrap_or(unmerged_score);
+                set_token(id, token, score)?;
+            }
+        }
+        ModelJson::Unigram { vocab } => {
+            for (id, (text, score)) in vocab.into_iter().enumerate() {
+                let id = TokenId::try_from(id).map_err(|e| e.to_string())?;
+                set_token(id, convert(&text), score)?;
+            }
+        }
+    }
+
+    let mut special_tokens = SpecialTokens::default();
+    for added_token in json.added_tokens {
+        if added_token.special {
+            special_tokens.insert(added_token.content.as_str(), added_token.id);
+        }
+        set_token(added_token.id, added_token.content.into_bytes(), 0.0)?;
+    }
+
+    let mut vocabulary = Vocabulary {
+        tokenizer: if byte_level {
+            TokenizerKind::ByteLevelBpe
+        } else {
+            TokenizerKind::SentencePiece
+        },
+        bpe_merges,
+        special_tokens,
+        ..Default::default()
+    };
+    for (id, token) in tokens.into_iter().enumerate() {
+        // Gaps in the IDs are filled with empty tokens, as GGML files do for
+        // control tokens.
+        let (token, score) = token.unwrap_or_default();
+        vocabulary.push_token(id as TokenId, token, score);
+    }
+    Ok(vocabulary)
+}
+
+/// Whether a pre-tokenizer or decoder is, or contains, a `ByteLevel` step.
+fn is_byte_level(value: &Value) -> bool {
+    match value {
+        Value::Object(object) => {
+            object.get("type").and_then(Value::as_str) == Some("ByteLevel")
+                || object.values().any(is_byte_level)
+        }
+        Value::Array(array) => array.iter().any(is_byte_level),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(vocabulary: &Vocabulary, text: &str) -> Vec<TokenId> {
+        vocabulary
+            .tokenize(text, false)
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_byte_level() {
+        let vocabulary = parse(
+            r#"{
+                "added_tokens": [{"id": 6, "content": "<|endoftext|>", "special": true}],
+                "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false},
+                "decoder": {"type": "ByteLevel"},
+                "model": {
+                    "type": "BPE",
+                    "vocab": {"h": 0, "i": 1, "Ġ": 2, "Ġh": 3, "Ġhi": 4, "hi": 5},
+                    "merges": ["Ġ h", "Ġh i", "h i"]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(vocabulary.tokenizer, TokenizerKind::ByteLevelBpe);
+        assert_eq!(vocabulary.token(4), b" hi");
+        assert_eq!(vocabulary.token(6), b"<|endoftext|>");
+        assert_eq!(vocabulary.special_tokens.text_to_id["<|endoftext|>"], 6);
+        assert_eq!(ids(&vocabulary, "hi hi"), [5, 4]);
+    }
+
+    #[test]
+    fn test_parse_sentencepiece() {
+        let vocabulary = parse(
+            r#"{
+                "added_tokens": [
+                    {"id": 0, "content": "<unk>", "special": true},
+                    {"id": 1, "content": "<s>", "special": true}
+                ],
+                "pre_tokenizer": null,
+                "decoder": {"type": "Sequence", "decoders": [
+                    {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                    {"type": "ByteFallback"}
+                ]},
+                "model": {
+                    "type": "BPE",
+                    "byte_fallback": true,
+                    "vocab": {
+                        "<unk>": 0, "<s>": 1, "<0x0A>": 2, "▁": 3, "h": 4, "i": 5,
+                        "▁h": 6, "▁hi": 7
+                    },
+                    "merges": [["▁", "h"], ["▁h", "i"]]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(vocabulary.tokenizer, TokenizerKind::SentencePiece);
+        assert_eq!(vocabulary.token(2), b"\n");
+        assert_eq!(vocabulary.token(7), b" hi");
+        assert_eq!(ids(&vocabulary, " hi\nhi"), [7, 2, 4, 5]);
+    }
+
+    #[test]
+    fn test_parse_unigram() {
+        let vocabulary =
+            parse(r#"{"model": {"type": "Unigram", "vocab": [["<unk>", 0.0], ["▁a", -1.5]]}}"#)
+                .unwrap();
+
+        assert_eq!(vocabulary.id_to_token_score, [0.0, -1.5]);
+        assert_eq!(vocabulary.token(1), b" a");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(r#"{"model": {"type": "WordPiece", "vocab": {}}}"#).is_err());
+    }
+}
@@ -111,28 +111,21 @@ fn collect_related_paths(
 ) -> Vec<PathBuf> {
     let main_filename = main_path.file_name().and_then(|p| p.to_str());
 
-    let mut paths: Vec<PathBuf> = directory_paths
-        .filter(|p| {
-            p.file_name()
-                .and_then(|p| p.to_str())
-                .zip(main_filename)
-                .map(|(part_filename, main_filename)| {
-                    match part_filename.strip_prefix(main_filename) {
-                        Some(suffix) => {
-                            suffix.is_empty()
-                                || (suffix
-                                    .strip_prefix('.')
-                                    .map(|s| s.parse::<usize>().is_ok())
-                                    .unwrap_or(false))
-                        }
-                        None => false,
-                    }
-                })
-                .unwrap_or(false)
-        })
+    // The main file is part 0, and the others are numbered by their suffix.
+    let part_number = |p: &Path| -> Option<usize> {
+        let suffix = p.file_name()?.to_str()?.strip_prefix(main_filename?)?;
+        if suffix.is_empty() {
+            Some(0)
+        } else {
+            suffix.strip_prefix('.')?.parse().ok()
+        }
+    };
+
+    let mut paths: Vec<(usize, PathBuf)> = directory_paths
+        .filter_map(|p| Some((part_number(&p)?, p)))
         .collect();
     paths.sort();
-    paths
+    paths.into_iter().map(|(_, p)| p).collect()
 }
 
 /// mmap with MAP_POPULATE
@@ -150,6 +143,7 @@ mod tests {
         let directory_paths = [
             "/models/llama.bin",
             "/models/llama.bin.1",
+            "/models/llama.bin.10",
             "/models/llama.bin.2",
             "/models/llama.bin.tmp",
         ]
@@ -158,6 +152,7 @@ mod tests {
             "/models/llama.bin",
             "/models/llama.bin.1",
             "/models/llama.bin.2",
+            "/models/llama.bin.10",
         ]
         .map(PathBuf::from);
 
@@ -165,6 +160,18 @@ mod tests {
         assert_eq!(expected_paths.as_slice(), output_paths);
     }
 
+    #[test]
+    fn test_collect_related_paths_of_relative_main_path() {
+        // `find_all_model_files` lists the current directory for a bare file name,
+        // so the main file is found as `./llama.bin` rather than `llama.bin`.
+        let main_path = PathBuf::from("llama.bin");
+        let directory_paths = ["./llama.bin.1", "./llama.bin", "./other.bin"].map(PathBuf::from);
+        let expected_paths = ["./llama.bin", "./llama.bin.1"].map(PathBuf::from);
+
+        let output_paths = collect_related_paths(&main_path, directory_paths.into_iter());
+        assert_eq!(expected_paths.as_slice(), output_paths);
+    }
+
     #[test]
     fn test_valid_utf8() {
         let mut buffer = TokenUtf8Buffer::new();
@@ -1,6 +1,14 @@
-use std::{collections::HashMap, error::Error, fmt::Display, str::FromStr};
+use std::{
+    borrow::Cow,
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    error::Error,
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+};
 
-use crate::InferenceError;
+use crate::{bpe, InferenceError, LoadError, TokenTrie};
 
 /// The identifier of a token in a vocabulary.
 pub type TokenId = i32;
@@ -16,12 +24,83 @@ pub struct Vocabulary {
     /// Maps every integer (index) token ID to corresponding score.
     pub id_to_token_score: Vec<TokenScore>,
 
-    // todo: use a radix tree
     /// Maps a token to a token ID.
-    pub token_to_id: HashMap<Token, TokenId>,
+    pub token_to_id: TokenTrie,
 
     /// The longest token in this vocabulary.
     pub max_token_length: usize,
+
+    /// The algorithm used to tokenize text with this vocabulary.
+    pub tokenizer: TokenizerKind,
+
+    /// The ranks of the merges used by [TokenizerKind::ByteLevelBpe], keyed by the
+    /// pair of tokens that are merged. GGML files do not store merges; when there
+    /// are none, the ID of the merged token is used as its rank instead.
+    pub bpe_merges: HashMap<(Token, Token), usize>,
+
+    /// The special tokens of this vocabulary, such as the beginning-of-string
+    /// token. [load](crate::load) fills these in for the architecture being loaded.
+    pub special_tokens: SpecialTokens,
+}
+
+/// The tokens that have a special meaning to a model, rather than representing text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpecialTokens {
+    /// The beginning-of-string token, inserted before a sequence.
+    pub bos: Option<TokenId>,
+    /// The end-of-string token, which a model generates when its text is complete.
+    pub eos: Option<TokenId>,
+    /// The token used to pad sequences to the same length.
+    pub pad: Option<TokenId>,
+    /// The token used for text that is not in the vocabulary.
+    pub unk: Option<TokenId>,
+    /// The text of every special token, including any that were added by an external
+    /// tokenizer, mapped to its ID. These are the strings that are recognised by
+    /// [Vocabulary::tokenize_with_special_tokens].
+    pub text_to_id: HashMap<String, TokenId>,
+}
+impl SpecialTokens {
+    /// Registers `text` as the text of the special token `id`.
+    pub fn insert(&mut self, text: impl Into<String>, id: TokenId) {
+        self.text_to_id.insert(text.into(), id);
+    }
+
+    /// Looks up the token with the given `text` in `vocabulary`, registering it
+    /// as a special token if it exists.
+    pub fn find(&mut self, vocabulary: &Vocabulary, text: &str) -> Option<TokenId> {
+        let id = self
+            .text_to_id
+            .get(text)
+            .copied()
+            .or_else(|| vocabulary.token_to_id.get(text.as_bytes()))?;
+        self.insert(text, id);
+        Some(id)
+    }
+
+    /// Finds the first special token in `text`, preferring the longest if several
+    /// start at the same position. Returns its ID, and its start and end in `text`.
+    fn find_in(&self, text: &str) -> Option<(TokenId, usize, usize)> {
+        self.text_to_id
+            .iter()
+            .filter(|(special, _)| !special.is_empty())
+            .filter_map(|(special, &id)| {
+                let start = text.find(special.as_str())?;
+                Some((start, Reverse(special.len()), id))
+            })
+            .min()
+            .map(|(start, Reverse(len), id)| (id, start, start + len))
+    }
+}
+
+/// The algorithms that can be used to tokenize text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenizerKind {
+    /// SentencePiece BPE, driven by the scores of the tokens. Used by LLaMA.
+    #[default]
+    SentencePiece,
+    /// Byte-level BPE, driven by merge ranks, with GPT-2's pre-tokenization.
+    /// Used by GPT-2 and the models that share its tokenizer.
+    ByteLevelBpe,
 }
 
 impl Vocabulary {
@@ -42,9 +121,20 @@ impl Vocabulary {
         }
 
         self.max_token_length = self.max_token_length.max(content.len());
-        self.id_to_token.push(content.clone());
+        self.token_to_id.insert(&content, id);
+        self.id_to_token.push(content);
         self.id_to_token_score.push(score);
-        self.token_to_id.insert(content, id);
+    }
+
+    /// Pads the vocabulary with empty tokens until it has `n_vocab` tokens. Models
+    /// are often padded past the end of their tokenizer, and may still sample the
+    /// padding tokens, which then decode to nothing.
+    pub(crate) fn pad_to(&mut self, n_vocab: usize) {
+        let n_padding = n_vocab.saturating_sub(self.id_to_token.len());
+        self.id_to_token
+            .extend(std::iter::repeat_with(Vec::new).take(n_padding));
+        self.id_to_token_score
+            .extend(std::iter::repeat(0.0).take(n_padding));
     }
 
     /// Converts a token index to the token it represents in this vocabulary.
@@ -52,62 +142,458 @@ impl Vocabulary {
         &self.id_to_token[idx]
     }
 
-    // SentencePiece implementation after https://guillaume-be.github.io/2020-05-30/sentence_piece
-    /// Tokenize a `text` with this vocabulary.
+    /// The bytes of the text that the token `id` represents.
+    ///
+    /// For SentencePiece vocabularies, `▁` is decoded as a space and `<0xXX>`
+    /// pieces as the byte they represent. The tokens of byte-level BPE vocabularies
+    /// are already stored as bytes. The bytes of a single token need not be
+    /// valid UTF-8; use [Self::decode] or a [TokenDecoder] to get text.
+    pub fn decode_token(&self, id: TokenId) -> Cow<'_, [u8]> {
+        let token = self.token(id as usize);
+        match self.tokenizer {
+            TokenizerKind::SentencePiece => {
+                if let Some(byte) = parse_byte_piece(token) {
+                    return Cow::Owned(vec![byte]);
+                }
+
+                let space_piece = SPACE_PIECE.as_bytes();
+                if !token.windows(space_piece.len()).any(|w| w == space_piece) {
+                    return Cow::Borrowed(token);
+                }
+                let mut decoded = Vec::with_capacity(token.len());
+                let mut rest = token;
+                while let Some((&first, tail)) = rest.split_first() {
+                    match rest.strip_prefix(space_piece) {
+                        Some(after) => {
+                            decoded.push(b' ');
+                            rest = after;
+                        }
+                        None => {
+                            decoded.push(first);
+                            rest = tail;
+                        }
+                    }
+                }
+                Cow::Owned(decoded)
+            }
+            TokenizerKind::ByteLevelBpe => Cow::Borrowed(token),
+        }
+    }
+
+    /// Decodes `tokens` to text, handling invalid UTF-8 as `invalid_utf8` specifies.
+    pub fn decode(&self, tokens: &[TokenId], invalid_utf8: InvalidUtf8) -> String {
+        let mut decoder = TokenDecoder::new(invalid_utf8);
+        let mut text: String = tokens
+            .iter()
+            .map(|&token| decoder.push(self, token))
+            .collect();
+        text.push_str(&decoder.flush());
+        text
+    }
+
+    /// Loads the merges of a byte-level BPE vocabulary from a `merges.txt` file.
+    pub fn load_bpe_merges(&mut self, path: &Path) -> Result<(), LoadError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        self.bpe_merges = bpe::parse_merges(&contents).map_err(|line| LoadError::InvalidMerge {
+            path: path.to_owned(),
+            line,
+        })?;
+        Ok(())
+    }
+
+    /// Tokenize a `text` with this vocabulary, using its [tokenizer](Self::tokenizer).
     ///
-    /// `bos` controls whether a beginning-of-string token should be inserted.
+    /// `bos` controls whether a beginning-of-string token should be inserted. Byte-level
+    /// BPE vocabularies do not have one, so it is ignored for them.
     pub fn tokenize<'a>(
         &'a self,
         text: &str,
         bos: bool,
     ) -> Result<Vec<(&'a [u8], TokenId)>, InferenceError> {
-        let len = text.len();
-
-        let mut score = vec![0usize; len + 1];
-        let mut prev = vec![TokenId::default(); len + 1];
-
-        for i in 0..len {
-            let max_len = (len - i).min(self.max_token_length);
-            for sub_len in 1..=max_len {
-                let sub = &text.as_bytes()[i..i + sub_len];
-                let token = self.token_to_id.get(sub);
+        match self.tokenizer {
+            TokenizerKind::SentencePiece => self.tokenize_sentencepiece(text, bos),
+            TokenizerKind::ByteLevelBpe => self.tokenize_byte_level_bpe(text),
+        }
+    }
 
-                if let Some(token) = token {
-                    let token_score = sub.len() * sub.len();
-                    let local_score = score[i] + token_score;
-                    let next = i + sub_len;
+    /// Tokenize a `text` with this vocabulary as [Self::tokenize] does, but with the
+    /// text of each of its [special tokens](Self::special_tokens) recognised as that
+    /// token, rather than being tokenized as text.
+    ///
+    /// This should only be used with trusted text, as it allows the text to
+    /// contain control tokens such as the end-of-string token.
+    pub fn tokenize_with_special_tokens<'a>(
+        &'a self,
+        text: &str,
+        bos: bool,
+    ) -> Result<Vec<(&'a [u8], TokenId)>, InferenceError> {
+        let mut res = vec![];
+        let mut rest = text;
+        let mut first = true;
+        loop {
+            let special = self.special_tokens.find_in(rest);
+            let segment = special.map_or(rest, |(_, start, _)| &rest[..start]);
+            // The beginning-of-string token is only inserted before the first segment.
+            let bos = bos && first;
+            if !segment.is_empty() || bos {
+                res.extend(self.tokenize(segment, bos)?);
+            }
 
-                    if score[next] < local_score {
-                        score[next] = local_score;
-                        prev[next] = *token;
-                    }
+            match special {
+                Some((id, _, end)) => {
+                    res.push((self.token(id as usize), id));
+                    rest = &rest[end..];
+                    first = false;
                 }
+                None => return Ok(res),
             }
         }
+    }
+
+    /// Tokenize a `text` with byte-level BPE. The text is split into pieces
+    /// as GPT-2 does, and the bytes of each piece are merged in order of rank.
+    fn tokenize_byte_level_bpe<'a>(
+        &'a self,
+        text: &str,
+    ) -> Result<Vec<(&'a [u8], TokenId)>, InferenceError> {
+        let rank = |left: &[u8], right: &[u8]| {
+            if self.bpe_merges.is_empty() {
+                self.token_to_id
+                    .get_concat(left, right)
+                    .map(|id| id as usize)
+            } else {
+                self.bpe_merges
+                    .get(&(left.to_vec(), right.to_vec()))
+                    .copied()
+            }
+        };
 
-        // Backward pass
         let mut res = vec![];
-        let mut i = len;
-        while i > 0 {
-            let token_id = prev[i];
-            if token_id == 0 {
-                return Err(InferenceError::TokenizationFailed);
+        for piece in bpe::pretokenize(text) {
+            let piece = piece.as_bytes();
+            for (start, len) in bpe::merge(piece, rank) {
+                match self.token_to_id.get(&piece[start..start + len]) {
+                    Some(id) => res.push((self.token(id as usize), id)),
+                    None => return Err(InferenceError::TokenizationFailed),
+                }
             }
-            let token = self.id_to_token[token_id as usize].as_slice();
-            res.push((token, token_id));
-            i -= token.len();
         }
+        Ok(res)
+    }
 
+    /// Tokenize a `text` with SentencePiece BPE.
+    ///
+    /// This follows SentencePiece's BPE algorithm: the text is split into characters,
+    /// and the adjacent pair that forms the highest-scoring token is repeatedly merged
+    /// until no pair forms a token. Characters that are not in the vocabulary are
+    /// encoded as byte tokens (such as `<0x0A>`), or as the unknown token if the
+    /// vocabulary has no byte tokens.
+    ///
+    /// Spaces are represented with `▁` if the vocabulary uses that convention.
+    ///
+    /// Looking up tokens takes time linear in their length, but the merges are ordered
+    /// through a priority queue, so tokenizing `n` characters takes `O(n log n)` time,
+    /// as it does in SentencePiece.
+    ///
+    /// `bos` controls whether a beginning-of-string token should be inserted. As
+    /// this marks the start of a sequence, the text is also prefixed with a space,
+    /// as SentencePiece does.
+    fn tokenize_sentencepiece<'a>(
+        &'a self,
+        text: &str,
+        bos: bool,
+    ) -> Result<Vec<(&'a [u8], TokenId)>, InferenceError> {
+        let mut res = vec![];
         if bos {
-            // TODO: replace with vocab.bos
-            res.push((&[], 1));
+            res.push((&[][..], self.bos_token_id()));
+        }
+        if text.is_empty() {
+            return Ok(res);
+        }
+
+        let mut text = if bos {
+            format!(" {text}")
+        } else {
+            text.to_owned()
+        };
+        if self.token_to_id.contains_key(SPACE_PIECE.as_bytes()) {
+            text = text.replace(' ', SPACE_PIECE);
         }
 
-        // Pieces are in reverse order so correct that
-        res.reverse();
+        for (start, len) in self.merge_symbols(&text) {
+            let piece = &text.as_bytes()[start..start + len];
+            if let Some(id) = self.token_to_id.get(piece) {
+                res.push((self.token(id as usize), id));
+                continue;
+            }
+
+            for &byte in piece {
+                let id = self.byte_token_id(byte).or_else(|| self.unknown_token_id());
+                match id {
+                    Some(id) => res.push((self.token(id as usize), id)),
+                    None => return Err(InferenceError::TokenizationFailed),
+                }
+            }
+        }
 
         Ok(res)
     }
+
+    /// Splits `text` into characters and merges them by score, returning the
+    /// `(start, len)` byte ranges of the resulting symbols.
+    ///
+    /// Each merge pops the best pair from a heap of candidate pairs, which holds at
+    /// most one pair per symbol, so this takes `O(n log n)` time for `n` characters.
+    fn merge_symbols(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut symbols: Vec<Symbol> = text
+            .char_indices()
+            .enumerate()
+            .map(|(i, (start, c))| Symbol {
+                start,
+                len: c.len_utf8(),
+                prev: i.checked_sub(1),
+                next: Some(i + 1),
+            })
+            .collect();
+        if let Some(last) = symbols.last_mut() {
+            last.next = None;
+        }
+
+        let mut queue = BinaryHeap::new();
+        for left in 1..symbols.len() {
+            self.try_add_bigram(text, &symbols, &mut queue, left - 1, left);
+        }
+
+        while let Some(bigram) = queue.pop() {
+            let left = bigram.left;
+            let right = match symbols[left].next {
+                Some(right) => right,
+                None => continue,
+            };
+
+            // Skip bigrams whose symbols have changed since they were queued.
+            if symbols[left].len == 0
+                || symbols[right].len == 0
+                || symbols[left].len + symbols[right].len != bigram.len
+            {
+                continue;
+            }
+
+            symbols[left].len += symbols[right].len;
+            symbols[right].len = 0;
+            symbols[left].next = symbols[right].next;
+            if let Some(next) = symbols[right].next {
+                symbols[next].prev = Some(left);
+            }
+
+            if let Some(prev) = symbols[left].prev {
+                self.try_add_bigram(text, &symbols, &mut queue, prev, left);
+            }
+            if let Some(next) = symbols[left].next {
+                self.try_add_bigram(text, &symbols, &mut queue, left, next);
+            }
+        }
+
+        symbols
+            .iter()
+            .filter(|symbol| symbol.len > 0)
+            .map(|symbol| (symbol.start, symbol.len))
+            .collect()
+    }
+
+    /// Queues the merge of the symbols `left` and `right`, if they form a token.
+    fn try_add_bigram(
+        &self,
+        text: &str,
+        symbols: &[Symbol],
+        queue: &mut BinaryHeap<Bigram>,
+        left: usize,
+        right: usize,
+    ) {
+        let start = symbols[left].start;
+        let len = symbols[left].len + symbols[right].len;
+        if let Some(id) = self.token_to_id.get(&text.as_bytes()[start..start + len]) {
+            queue.push(Bigram {
+                score: self.id_to_token_score[id as usize],
+                left,
+                len,
+            });
+        }
+    }
+
+    /// The ID of the token that represents `byte`, either as a `<0xXX>` piece or
+    /// as the byte itself.
+    fn byte_token_id(&self, byte: u8) -> Option<TokenId> {
+        self.token_to_id
+            .get(format!("<0x{byte:02X}>").as_bytes())
+            .or_else(|| self.token_to_id.get(&[byte]))
+    }
+
+    /// The ID of the token used for text that is not in the vocabulary.
+    fn unknown_token_id(&self) -> Option<TokenId> {
+        self.special_tokens.unk.or_else(|| {
+            UNKNOWN_PIECES
+                .iter()
+                .find_map(|piece| self.token_to_id.get(piece.as_bytes()))
+        })
+    }
+
+    /// The ID of the beginning-of-string token. Without a registered one, this is
+    /// `<s>` if the vocabulary has it, and 1 otherwise, as is conventional for
+    /// SentencePiece models.
+    fn bos_token_id(&self) -> TokenId {
+        self.special_tokens
+            .bos
+            .or_else(|| self.token_to_id.get(BOS_PIECE.as_bytes()))
+            .unwrap_or(1)
+    }
+}
+
+/// The piece SentencePiece uses to represent a space.
+const SPACE_PIECE: &str = "\u{2581}";
+/// The piece for the beginning-of-string token.
+const BOS_PIECE: &str = "<s>";
+/// The pieces that may represent the unknown token. GGML files store it as ` ⁇ `.
+const UNKNOWN_PIECES: [&str; 2] = ["<unk>", " \u{2047} "];
+
+/// Parses a SentencePiece byte piece of the form `<0xXX>`.
+pub(crate) fn parse_byte_piece(piece: &[u8]) -> Option<u8> {
+    let hex = piece.strip_prefix(b"<0x")?.strip_suffix(b">")?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// How bytes that are not valid UTF-8 are handled when decoding tokens to text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Each invalid sequence is replaced with the given character.
+    Replace(char),
+    /// Invalid sequences are dropped.
+    Skip,
+}
+impl Default for InvalidUtf8 {
+    fn default() -> Self {
+        Self::Replace(char::REPLACEMENT_CHARACTER)
+    }
+}
+
+/// Incrementally decodes a stream of tokens to text.
+///
+/// A character may be split across several tokens, so the bytes of each token
+/// are buffered until they form complete characters. This makes it suitable
+/// for decoding tokens as they are generated.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TokenDecoder {
+    buffer: Vec<u8>,
+    invalid_utf8: InvalidUtf8,
+}
+impl TokenDecoder {
+    /// Creates a decoder that handles invalid UTF-8 as `invalid_utf8` specifies.
+    pub fn new(invalid_utf8: InvalidUtf8) -> Self {
+        Self {
+            buffer: vec![],
+            invalid_utf8,
+        }
+    }
+
+    /// Decodes `token` with `vocabulary`, and returns the text that is now complete.
+    pub fn push(&mut self, vocabulary: &Vocabulary, token: TokenId) -> String {
+        self.push_bytes(&vocabulary.decode_token(token))
+    }
+
+    /// Adds the bytes of a token that has already been decoded with
+    /// [Vocabulary::decode_token], and returns the text that is now complete.
+    /// Bytes that may be the start of an incomplete character are held back.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> String {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        let mut start = 0;
+        while start < self.buffer.len() {
+            match std::str::from_utf8(&self.buffer[start..]) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    start = self.buffer.len();
+                }
+                Err(e) => {
+                    let valid_end = start + e.valid_up_to();
+                    // The bytes up to `valid_up_to` were just validated.
+                    text.push_str(std::str::from_utf8(&self.buffer[start..valid_end]).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            self.push_invalid(&mut text);
+                            start = valid_end + len;
+                        }
+                        // The buffer ends partway through a character.
+                        None => {
+                            start = valid_end;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.buffer.drain(..start);
+        text
+    }
+
+    /// Returns the text for any bytes that are still held back, as the stream has
+    /// ended and they can no longer form a complete character.
+    pub fn flush(&mut self) -> String {
+        let mut text = String::new();
+        if !self.buffer.is_empty() {
+            self.buffer.clear();
+            self.push_invalid(&mut text);
+        }
+        text
+    }
+
+    fn push_invalid(&self, text: &mut String) {
+        match self.invalid_utf8 {
+            InvalidUtf8::Replace(replacement) => text.push(replacement),
+            InvalidUtf8::Skip => {}
+        }
+    }
+}
+
+/// A span of the text being tokenized, linked to its neighbours.
+struct Symbol {
+    start: usize,
+    len: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+/// A candidate merge of the symbol at `left` with the symbol after it.
+struct Bigram {
+    score: TokenScore,
+    left: usize,
+    len: usize,
+}
+impl PartialEq for Bigram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Bigram {}
+impl PartialOrd for Bigram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Bigram {
+    // The highest score is merged first, with ties going to the leftmost bigram.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.left.cmp(&self.left))
+    }
 }
 
 #[derive(Default, Clone, Debug, PartialEq)]
@@ -194,3 +680,297 @@ impl std::fmt::Display for TokenBias {
         write!(f, "{:?}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a vocabulary in the style of LLaMA's SentencePiece model: control
+    /// tokens, byte tokens, and then scored pieces.
+    fn sentencepiece_vocabulary(pieces: &[(&str, TokenScore)]) -> Vocabulary {
+        let mut vocabulary = Vocabulary::default();
+        let mut push = |content: Vec<u8>, score| {
+            let id = vocabulary.id_to_token.len() as TokenId;
+            vocabulary.push_token(id, content, score);
+        };
+
+        for control in ["<unk>", "<s>", "</s>"] {
+            push(control.as_bytes().to_vec(), 0.0);
+        }
+        for byte in 0..=255u8 {
+            push(format!("<0x{byte:02X}>").into_bytes(), 0.0);
+        }
+        for (piece, score) in pieces {
+            push(piece.as_bytes().to_vec(), *score);
+        }
+        vocabulary
+    }
+
+    fn ids(vocabulary: &Vocabulary, text: &str, bos: bool) -> Vec<TokenId> {
+        vocabulary
+            .tokenize(text, bos)
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    fn pieces(vocabulary: &Vocabulary, text: &str) -> Vec<String> {
+        vocabulary
+            .tokenize(text, false)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| String::from_utf8_lossy(token).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_merges_by_score() {
+        let vocabulary = sentencepiece_vocabulary(&[
+            ("\u{2581}", -1.0),
+            ("a", -2.0),
+            ("b", -2.0),
+            ("c", -2.0),
+            ("ab", -5.0),
+            ("bc", -1.5),
+        ]);
+
+        // `bc` scores higher than `ab`, so it is merged first.
+        assert_eq!(pieces(&vocabulary, "abc"), ["a", "bc"]);
+        assert_eq!(pieces(&vocabulary, "ab"), ["ab"]);
+    }
+
+    #[test]
+    fn test_tokenize_known_sequence() {
+        let vocabulary = sentencepiece_vocabulary(&[
+            ("\u{2581}", -1.0),
+            ("h", -8.0),
+            ("e", -8.0),
+            ("l", -8.0),
+            ("o", -8.0),
+            ("w", -8.0),
+            ("r", -8.0),
+            ("d", -8.0),
+            ("\u{2581}h", -4.0),
+            ("\u{2581}he", -3.0),
+            ("ll", -3.5),
+            ("llo", -3.2),
+            ("\u{2581}hello", -2.0),
+            ("\u{2581}w", -4.0),
+            ("or", -3.0),
+            ("\u{2581}wor", -2.5),
+            ("ld", -3.0),
+            ("\u{2581}world", -2.0),
+        ]);
+        let id = |piece: &str| vocabulary.token_to_id.get(piece.as_bytes()).unwrap();
+
+        assert_eq!(
+            ids(&vocabulary, "hello world", true),
+            [1, id("\u{2581}hello"), id("\u{2581}world")]
+        );
+        assert_eq!(
+            ids(&vocabulary, " world hello", false),
+            [id("\u{2581}world"), id("\u{2581}hello")]
+        );
+        assert_eq!(
+            pieces(&vocabulary, " hello world"),
+            ["\u{2581}hello", "\u{2581}world"]
+        );
+        assert_eq!(ids(&vocabulary, "", true), [1]);
+        assert!(ids(&vocabulary, "", false).is_empty());
+    }
+
+    #[test]
+    fn test_tokenize_byte_fallback() {
+        let vocabulary = sentencepiece_vocabulary(&[("\u{2581}", -1.0), ("a", -2.0)]);
+
+        // Newlines and characters outside of the vocabulary are split into bytes.
+        let a = vocabulary.token_to_id.get(b"a").unwrap();
+        assert_eq!(ids(&vocabulary, "a\n", false), [a, 3 + 0x0A]);
+        assert_eq!(ids(&vocabulary, "\u{e9}", false), [3 + 0xC3, 3 + 0xA9]);
+    }
+
+    #[test]
+    fn test_tokenize_unknown_fallback() {
+        let mut vocabulary = Vocabulary::default();
+        vocabulary.push_token(0, b"<unk>".to_vec(), 0.0);
+        vocabulary.push_token(1, b"<s>".to_vec(), 0.0);
+        vocabulary.push_token(2, b"a".to_vec(), -1.0);
+
+        assert_eq!(ids(&vocabulary, "aza", false), [2, 0, 2]);
+    }
+
+    #[test]
+    fn test_tokenize_byte_level_bpe() {
+        // GGML files store the tokens of byte-level BPE vocabularies as raw bytes.
+        let mut vocabulary = Vocabulary {
+            tokenizer: TokenizerKind::ByteLevelBpe,
+            ..Default::default()
+        };
+        for byte in 0..=255u8 {
+            vocabulary.push_token(byte as TokenId, vec![byte], 0.0);
+        }
+        for (id, token) in [" w", " wo", "or", " wor", "ld", " world", "He"]
+            .iter()
+            .enumerate()
+        {
+            vocabulary.push_token(256 + id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        let id = |token: &str| vocabulary.token_to_id.get(token.as_bytes()).unwrap();
+
+        // Without merges, pairs are merged in order of the ID of the merged token.
+        // `bos` is ignored, as there is no beginning-of-string token.
+        let expected = [id("He"), id("y"), id(" world")];
+        assert_eq!(ids(&vocabulary, "Hey world", false), expected);
+        assert_eq!(ids(&vocabulary, "Hey world", true), expected);
+
+        vocabulary.bpe_merges =
+            bpe::parse_merges("o r\n\u{120} w\n\u{120}w or\nl d\n\u{120}wor ld").unwrap();
+        assert_eq!(
+            ids(&vocabulary, "Hey world", false),
+            [id("H"), id("e"), id("y"), id(" world")]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_ggml_conventions() {
+        // GGML files store spaces as ` `, bytes as themselves and control tokens as
+        // empty strings.
+        let mut vocabulary = Vocabulary::default();
+        vocabulary.push_token(0, " \u{2047} ".as_bytes().to_vec(), 0.0);
+        vocabulary.push_token(1, vec![], 0.0);
+        vocabulary.push_token(2, vec![], 0.0);
+        vocabulary.push_token(3, b"\n".to_vec(), 0.0);
+        vocabulary.push_token(4, b" ".to_vec(), -1.0);
+        vocabulary.push_token(5, b"h".to_vec(), -2.0);
+        vocabulary.push_token(6, b"i".to_vec(), -2.0);
+        vocabulary.push_token(7, b" h".to_vec(), -1.5);
+        vocabulary.push_token(8, b" hi".to_vec(), -1.0);
+
+        assert_eq!(ids(&vocabulary, "hi\nhi", true), [1, 8, 3, 5, 6]);
+        assert_eq!(ids(&vocabulary, "x", false), [0]);
+    }
+
+    #[test]
+    fn test_decode_sentencepiece() {
+        let vocabulary = sentencepiece_vocabulary(&[("\u{2581}", -1.0), ("\u{2581}hi", -2.0)]);
+        let hi = vocabulary.token_to_id.get("\u{2581}hi".as_bytes()).unwrap();
+        let euro = [0xE2, 0x82, 0xAC].map(|b| vocabulary.byte_token_id(b).unwrap());
+
+        assert_eq!(vocabulary.decode_token(hi).as_ref(), b" hi");
+        assert_eq!(vocabulary.decode_token(euro[0]).as_ref(), [0xE2]);
+        assert_eq!(
+            vocabulary.decode(&[hi, euro[0], euro[1], euro[2]], InvalidUtf8::default()),
+            " hi€"
+        );
+        assert_eq!(
+            vocabulary.decode(&[hi, euro[0], hi], InvalidUtf8::Replace('?')),
+            " hi? hi"
+        );
+        assert_eq!(
+            vocabulary.decode(&[hi, euro[0], euro[1]], InvalidUtf8::Skip),
+            " hi"
+        );
+    }
+
+    #[test]
+    fn test_token_decoder_streaming() {
+        let mut decoder = TokenDecoder::default();
+        assert_eq!(decoder.push_bytes(b"a\xE2"), "a");
+        assert_eq!(decoder.push_bytes(b"\x82"), "");
+        assert_eq!(decoder.push_bytes(b"\xACb"), "€b");
+        assert_eq!(decoder.push_bytes(b"\xFFc\xE2"), "\u{FFFD}c");
+        assert_eq!(decoder.flush(), "\u{FFFD}");
+        assert_eq!(decoder.flush(), "");
+    }
+
+    #[test]
+    fn test_tokenize_with_special_tokens() {
+        let mut vocabulary = sentencepiece_vocabulary(&[
+            ("\u{2581}", -1.0),
+            ("h", -2.0),
+            ("i", -2.0),
+            ("\u{2581}h", -1.5),
+            ("\u{2581}hi", -1.0),
+        ]);
+        // GGML files store LLaMA's control tokens as empty strings.
+        vocabulary.id_to_token[2] = vec![];
+        vocabulary.special_tokens.eos = Some(2);
+        vocabulary.special_tokens.insert("</s>", 2);
+        vocabulary.special_tokens.insert("</s></s>", 0);
+
+        let hi = vocabulary.token_to_id.get("\u{2581}hi".as_bytes()).unwrap();
+        let ids = |text: &str, bos: bool| -> Vec<TokenId> {
+            vocabulary
+                .tokenize_with_special_tokens(text, bos)
+                .unwrap()
+                .into_iter()
+                .map(|(_, id)| id)
+                .collect()
+        };
+
+        assert_eq!(ids("hi</s> hi", true), [1, hi, 2, hi]);
+        assert_eq!(ids("</s>hi", true), [1, 2, 260, 261]);
+        assert_eq!(ids("hi</s></s>", false), [260, 261, 0]);
+        // Without opting in, the text is tokenized as text.
+        assert!(!vocabulary
+            .tokenize("hi</s>", false)
+            .unwrap()
+            .iter()
+            .any(|&(_, id)| id == 2));
+    }
+
+    #[test]
+    fn test_round_trip_large_text() {
+        let sentence = "The naïve café — 日本語 🦀 isn't   spaced\tevenly.\n";
+        let text: String = (0..40_000).map(|i| format!("{i} {sentence}")).collect();
+        assert!(text.len() > 2_000_000);
+
+        // BPE can only reach a token through its prefixes, so they are all included.
+        let mut words: Vec<String> = vec![];
+        for word in ["the", "The", "café", "spaced", "evenly", "isn't", "123"] {
+            for word in [word.to_owned(), format!(" {word}")] {
+                let chars: Vec<char> = word.chars().collect();
+                words.extend((2..=chars.len()).map(|end| chars[..end].iter().collect()));
+            }
+        }
+
+        let pieces: Vec<(String, TokenScore)> = words
+            .iter()
+            .enumerate()
+            .map(|(index, word)| (word.replace(' ', SPACE_PIECE), -(index as TokenScore)))
+            .chain([(SPACE_PIECE.to_owned(), 0.0)])
+            .collect();
+        let pieces: Vec<_> = pieces.iter().map(|(p, s)| (p.as_str(), *s)).collect();
+        let sentencepiece = sentencepiece_vocabulary(&pieces);
+
+        let mut byte_level = Vocabulary {
+            tokenizer: TokenizerKind::ByteLevelBpe,
+            ..Default::default()
+        };
+        for byte in 0..=255u8 {
+            byte_level.push_token(byte as TokenId, vec![byte], 0.0);
+        }
+        for word in &words {
+            let id = byte_level.id_to_token.len() as TokenId;
+            byte_level.push_token(id, word.as_bytes().to_vec(), 0.0);
+        }
+
+        for vocabulary in [sentencepiece, byte_level] {
+            let tokens = ids(&vocabulary, &text, false);
+            assert!(tokens.len() < text.len());
+            assert_eq!(vocabulary.decode(&tokens, InvalidUtf8::Skip), text);
+        }
+    }
+
+    #[test]
+    fn test_padding_tokens_decode_to_nothing() {
+        let mut vocabulary = sentencepiece_vocabulary(&[("a", -1.0)]);
+        let n_tokens = vocabulary.id_to_token.len();
+        vocabulary.pad_to(n_tokens + 2);
+
+        assert_eq!(vocabulary.id_to_token.len(), n_tokens + 2);
+        assert_eq!(vocabulary.id_to_token_score.len(), n_tokens + 2);
+        assert!(vocabulary.decode_token(n_tokens as TokenId + 1).is_empty());
+    }
+}
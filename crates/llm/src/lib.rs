@@ -7,6 +7,9 @@
 //! - [LLaMA](llm_llama)
 //! - [GPT-NeoX](llm_neox)
 //!
+//! With the `async` feature enabled, inference can also be consumed as a
+//! `Stream` of tokens through `InferenceStream`.
+//!
 //! At present, the only supported backend is [GGML](https://github.com/ggerganov/ggml), but this is expected to
 //! change in the future.
 //!
@@ -61,6 +64,8 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    fs::File,
+    io::BufReader,
     path::Path,
     str::FromStr,
 };
@@ -68,14 +73,28 @@ use std::{
 // Try not to expose too many GGML details here.
 // This is the "user-facing" API, and GGML may not always be our backend.
 pub use llm_base::{
-    ggml::format as ggml_format, load, load_progress_callback_stdout, quantize, ElementType,
-    FileType, InferenceError, InferenceParameters, InferenceRequest, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InvalidTokenBias, KnownModel, LoadError,
-    LoadProgress, Loader, Model, ModelKVMemoryType, ModelParameters, OutputRequest, QuantizeError,
-    QuantizeProgress, SnapshotError, TokenBias, TokenId, TokenUtf8Buffer, Vocabulary,
+    embed, estimate_memory_requirements,
+    ggml::{format as ggml_format, FORMAT_VERSION as GGJT_VERSION, GGKV_VERSION},
+    load, load_hugging_face, load_hugging_face_config, load_progress_callback_stdout,
+    load_vocabulary, perplexity, quantize, CancellationToken, ChatTemplate, ChatTemplateError,
+    ContainerType, ContextStrategy, Conversation, ElementType, EmbeddingParameters, FileType,
+    FinishReason, HuggingFaceLayout, HuggingFaceTensor, InferenceError, InferenceIter,
+    InferenceParameters, InferenceRequest, InferenceSession, InferenceSessionConfig,
+    InferenceSnapshot, InferenceStats, InvalidTokenBias, InvalidUtf8, KnownModel, LayerTensor,
+    LoadError, LoadProgress, Loader, MemoryRequirements, Message, Metadata, MetadataError,
+    MetadataValue, Model, ModelKVMemoryType, ModelParameters, OutputRequest, Perplexity,
+    PerplexityWindow, Pooling, QuantizeError, QuantizeProgress, Role, Score, SnapshotError,
+    SpecialTokens, SplitAxis, TokenBias, TokenDecoder, TokenId, TokenTrie, TokenUtf8Buffer,
+    TokenizerKind, Vocabulary, ARCHITECTURE_KEY, CONTEXT_LENGTH_KEY, MESSAGE_PLACEHOLDER,
+    SOURCE_KEY,
 };
 use serde::Serialize;
 
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::{InferenceEvent, InferenceStream, StreamRequest, DEFAULT_STREAM_BUFFER_SIZE};
+
 /// All available models.
 pub mod models {
     #[cfg(feature = "bloom")]
@@ -209,6 +228,246 @@ pub fn load_dynamic(
     Ok(model)
 }
 
+/// A helper function that loads the specified model from a Hugging Face checkpoint
+/// directory using an architecture specified at runtime.
+///
+/// A wrapper around [load_hugging_face] that dispatches to the correct model.
+pub fn load_hugging_face_dynamic(
+    architecture: ModelArchitecture,
+    directory: &Path,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    use ModelArchitecture::*;
+
+    let model: Box<dyn Model> = match architecture {
+        #[cfg(feature = "bloom")]
+        Bloom => Box::new(load_hugging_face::<models::Bloom>(
+            directory,
+            params,
+            load_progress_callback,
+        )?),
+        #[cfg(feature = "gpt2")]
+        Gpt2 => Box::new(load_hugging_face::<models::Gpt2>(
+            directory,
+            params,
+            load_progress_callback,
+        )?),
+        #[cfg(feature = "gptj")]
+        GptJ => Box::new(load_hugging_face::<models::GptJ>(
+            directory,
+            params,
+            load_progress_callback,
+        )?),
+        #[cfg(feature = "llama")]
+        Llama => Box::new(load_hugging_face::<models::Llama>(
+            directory,
+            params,
+            load_progress_callback,
+        )?),
+        #[cfg(feature = "neox")]
+        NeoX => Box::new(load_hugging_face::<models::NeoX>(
+            directory,
+            params,
+            load_progress_callback,
+        )?),
+    };
+
+    Ok(model)
+}
+
+/// Detects the architecture of the model at `path`.
+///
+/// GGKV files record their architecture in their metadata. Other GGML files do not,
+/// so each known hyperparameter layout is tried in turn until the tensor table can
+/// be read. The architecture is then identified from the names of the tensors.
+pub fn detect_architecture(path: &Path) -> Result<ModelArchitecture, LoadError> {
+    use ModelArchitecture::*;
+
+    // Hugging Face checkpoints name their architecture in their configuration.
+    if path.is_dir() {
+        let config = load_hugging_face_config(path)?;
+        return config
+            .get_str("model_type")
+            .ok()
+            .and_then(architecture_from_model_type)
+            .ok_or_else(|| LoadError::UnknownArchitecture {
+                path: path.to_owned(),
+            });
+    }
+
+    // GGKV files written before the architecture was recorded fall through to probing.
+    if let Some(metadata) = ggkv_metadata(path)? {
+        if let Ok(model_type) = metadata.get_str(ARCHITECTURE_KEY) {
+            return architecture_from_model_type(model_type).ok_or_else(|| {
+                LoadError::UnknownArchitecture {
+                    path: path.to_owned(),
+                }
+            });
+        }
+    }
+
+    for architecture in ModelArchitecture::ALL {
+        let names = match architecture {
+            #[cfg(feature = "bloom")]
+            Bloom => tensor_names::<models::Bloom>(path),
+            #[cfg(feature = "gpt2")]
+            Gpt2 => tensor_names::<models::Gpt2>(path),
+            #[cfg(feature = "gptj")]
+            GptJ => tensor_names::<models::GptJ>(path),
+            #[cfg(feature = "llama")]
+            Llama => tensor_names::<models::Llama>(path),
+            #[cfg(feature = "neox")]
+            NeoX => tensor_names::<models::NeoX>(path),
+        };
+
+        match names {
+            Ok(names) => {
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                if let Some(architecture) = architecture_from_tensor_names(&names) {
+                    return Ok(architecture);
+                }
+            }
+            // The file itself is unreadable, so no other layout will fare better.
+            Err(err @ LoadError::OpenFileFailed { .. }) => return Err(err),
+            // The hyperparameters did not match this layout; try the next one.
+            Err(_) => {}
+        }
+    }
+
+    Err(LoadError::UnknownArchitecture {
+        path: path.to_owned(),
+    })
+}
+
+/// A helper function that loads the specified model from disk, detecting its
+/// architecture with [detect_architecture]. If `path` is a directory, it is loaded
+/// as a Hugging Face checkpoint.
+///
+/// A wrapper around [load_dynamic] and [load_hugging_face_dynamic].
+pub fn load_auto(
+    path: &Path,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    let architecture = detect_architecture(path)?;
+    if path.is_dir() {
+        load_hugging_face_dynamic(architecture, path, params, load_progress_callback)
+    } else {
+        load_dynamic(architecture, path, params, load_progress_callback)
+    }
+}
+
+/// Reads the names of the tensors in the model at `path`, assuming the hyperparameter
+/// layout of `M`.
+fn tensor_names<M: KnownModel>(path: &Path) -> Result<Vec<String>, LoadError> {
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(|_| {});
+    ggml_format::load(&mut BufReader::new(&file), &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    Ok(loader.tensors.into_keys().collect())
+}
+
+/// Reads the metadata of the model at `path`, or `None` if it is not a GGKV file.
+fn ggkv_metadata(path: &Path) -> Result<Option<Metadata>, LoadError> {
+    /// Keeps the metadata of a GGKV file, and skips its vocabulary.
+    struct MetadataReader(Option<Metadata>);
+    impl ggml_format::LoadHandler<LoadError> for MetadataReader {
+        fn container_type(&mut self, _container_type: ContainerType) -> Result<(), LoadError> {
+            Ok(())
+        }
+
+        fn vocabulary_token(
+            &mut self,
+            _i: usize,
+            _token: Vec<u8>,
+            _score: f32,
+        ) -> Result<(), LoadError> {
+            Ok(())
+        }
+
+        fn read_hyperparameters(
+            &mut self,
+            _reader: &mut dyn std::io::BufRead,
+        ) -> Result<ggml_format::PartialHyperparameters, LoadError> {
+            Ok(ggml_format::PartialHyperparameters { n_vocab: 0 })
+        }
+
+        fn read_metadata(
+            &mut self,
+            metadata: Metadata,
+        ) -> Result<ggml_format::PartialHyperparameters, LoadError> {
+            self.0 = Some(metadata);
+            Ok(ggml_format::PartialHyperparameters { n_vocab: 0 })
+        }
+
+        fn tensor_buffer(&mut self, _info: ggml_format::TensorLoadInfo) -> Result<(), LoadError> {
+            Ok(())
+        }
+    }
+
+    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+        source: e,
+        path: path.to_owned(),
+    })?;
+    let mut reader = MetadataReader(None);
+    ggml_format::load_vocabulary(&mut BufReader::new(&file), &mut reader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+    Ok(reader.0)
+}
+
+/// Identifies a model architecture from the `model_type` of a Hugging Face configuration.
+fn architecture_from_model_type(model_type: &str) -> Option<ModelArchitecture> {
+    match model_type {
+        #[cfg(feature = "bloom")]
+        "bloom" => Some(ModelArchitecture::Bloom),
+        #[cfg(feature = "gpt2")]
+        "gpt2" => Some(ModelArchitecture::Gpt2),
+        #[cfg(feature = "gptj")]
+        "gptj" => Some(ModelArchitecture::GptJ),
+        #[cfg(feature = "llama")]
+        "llama" => Some(ModelArchitecture::Llama),
+        #[cfg(feature = "neox")]
+        "gpt_neox" => Some(ModelArchitecture::NeoX),
+        _ => None,
+    }
+}
+
+/// Identifies a model architecture from the names of its tensors.
+fn architecture_from_tensor_names(names: &[&str]) -> Option<ModelArchitecture> {
+    let has = |pattern: &str| names.iter().any(|name| name.contains(pattern));
+
+    #[cfg(feature = "neox")]
+    if has("gpt_neox.") {
+        return Some(ModelArchitecture::NeoX);
+    }
+    #[cfg(feature = "gptj")]
+    if has("transformer.h.") || has("transformer.wte") {
+        return Some(ModelArchitecture::GptJ);
+    }
+    #[cfg(feature = "gpt2")]
+    if has("model/wte") {
+        return Some(ModelArchitecture::Gpt2);
+    }
+    // BLOOM and LLaMA share their embedding name, but only BLOOM fuses the
+    // attention projections.
+    if has("tok_embeddings.weight") {
+        #[cfg(feature = "bloom")]
+        if has("query_key_value") {
+            return Some(ModelArchitecture::Bloom);
+        }
+        #[cfg(feature = "llama")]
+        return Some(ModelArchitecture::Llama);
+    }
+
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,4 +481,43 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_architecture_from_tensor_names() {
+        let cases: [(&[&str], Option<ModelArchitecture>); 6] = [
+            (
+                &["tok_embeddings.weight", "layers.0.attention.wq.weight"],
+                Some(ModelArchitecture::Llama),
+            ),
+            (
+                &[
+                    "tok_embeddings.weight",
+                    "layers.0.attention.query_key_value.weight",
+                ],
+                Some(ModelArchitecture::Bloom),
+            ),
+            (
+                &["model/wte", "model/h0/attn/c_attn/w"],
+                Some(ModelArchitecture::Gpt2),
+            ),
+            (
+                &[
+                    "transformer.wte.weight",
+                    "transformer.h.0.attn.q_proj.weight",
+                ],
+                Some(ModelArchitecture::GptJ),
+            ),
+            (
+                &[
+                    "gpt_neox.embed_in.weight",
+                    "gpt_neox.layers.0.attention.dense.weight",
+                ],
+                Some(ModelArchitecture::NeoX),
+            ),
+            (&["embeddings.weight"], None),
+        ];
+        for (names, expected) in cases {
+            assert_eq!(architecture_from_tensor_names(names), expected);
+        }
+    }
 }
@@ -0,0 +1,180 @@
+//! An asynchronous interface to inference, available with the `async` feature.
+//!
+//! Inference is CPU-bound and blocking, so it cannot be run directly on an
+//! asynchronous executor. [InferenceStream] instead runs inference on a
+//! dedicated thread and exposes the generated tokens as a [Stream].
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use futures::{
+    channel::mpsc::{self, Receiver, SendError, Sender},
+    executor::block_on,
+    SinkExt, Stream,
+};
+
+use crate::{
+    CancellationToken, InferenceError, InferenceParameters, InferenceRequest, InferenceSession,
+    InferenceStats, Model, OutputRequest,
+};
+
+/// The number of events that can be buffered by an [InferenceStream] before
+/// inference is paused to wait for the consumer.
+pub const DEFAULT_STREAM_BUFFER_SIZE: usize = 16;
+
+/// An owned version of [InferenceRequest], which can be sent to the inference thread.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct StreamRequest {
+    /// The prompt to feed to the model.
+    pub prompt: String,
+    /// The parameters to use during this inference attempt.
+    /// If not specified, this will default to the parameters
+    /// specified in the model.
+    pub parameters: Option<InferenceParameters>,
+    /// Whether or not to emit the previous tokens that were encountered in
+    /// this session.
+    ///
+    /// You likely want to turn this on if you're using a session
+    /// that has been rehydrated from a snapshot.
+    pub play_back_previous_tokens: bool,
+    /// The maximum number of tokens to generate.
+    pub maximum_token_count: Option<usize>,
+    /// The point in time after which inference will be stopped.
+    pub deadline: Option<Instant>,
+    /// The maximum number of tokens, including those of the prompt, that can be
+    /// evaluated before inference is stopped.
+    pub token_budget: Option<usize>,
+    /// Generation stops when any of these strings is generated.
+    pub stop_sequences: Vec<String>,
+    /// The number of tokens at the end of the prompt to heal. See
+    /// [InferenceRequest::token_healing].
+    pub token_healing: usize,
+    /// The number of events that can be buffered before inference is paused.
+    /// If not specified, [DEFAULT_STREAM_BUFFER_SIZE] is used.
+    pub buffer_size: Option<usize>,
+}
+
+/// An event produced by an [InferenceStream].
+#[derive(Debug, Clone)]
+pub enum InferenceEvent {
+    /// A token was processed or generated. The prompt is emitted before
+    /// any generated tokens.
+    Token(String),
+    /// Inference has completed; no more events will be produced.
+    Finished(InferenceStats),
+}
+
+/// A [Stream] of [InferenceEvent]s produced by running inference on a
+/// dedicated thread.
+///
+/// Only a bounded number of events are buffered; if the consumer does not keep
+/// up, inference will wait for it. Dropping the stream cancels inference at the
+/// next batch or token.
+///
+/// Once the stream has finished, [InferenceStream::into_session] returns the
+/// session, so that the conversation can be continued or snapshotted.
+pub struct InferenceStream {
+    receiver: Receiver<Result<InferenceEvent, InferenceError>>,
+    cancellation_token: CancellationToken,
+    thread: Option<JoinHandle<InferenceSession>>,
+}
+impl InferenceStream {
+    /// Starts inference for `request` on a new thread, using `session` and `model`.
+    ///
+    /// The model is shared through an [Arc], so a model loaded with
+    /// [load_dynamic](crate::load_dynamic) can be used by converting it with
+    /// `Arc::from(model)`.
+    pub fn new(
+        model: Arc<dyn Model>,
+        mut session: InferenceSession,
+        request: StreamRequest,
+        mut rng: impl rand::Rng + Send + 'static,
+    ) -> Self {
+        let buffer_size = request.buffer_size.unwrap_or(DEFAULT_STREAM_BUFFER_SIZE);
+        let (mut sender, receiver) = mpsc::channel(buffer_size);
+        let cancellation_token = CancellationToken::new();
+
+        let thread_cancellation_token = cancellation_token.clone();
+        let thread = std::thread::spawn(move || {
+            let result = session.infer::<SendError>(
+                model.as_ref(),
+                &mut rng,
+                &InferenceRequest {
+                    prompt: &request.prompt,
+                    parameters: request.parameters.as_ref(),
+                    play_back_previous_tokens: request.play_back_previous_tokens,
+                    maximum_token_count: request.maximum_token_count,
+                    cancellation_token: Some(&thread_cancellation_token),
+                    deadline: request.deadline,
+                    token_budget: request.token_budget,
+                    stop_sequences: &request.stop_sequences,
+                    token_healing: request.token_healing,
+                },
+                &mut OutputRequest::default(),
+                |token| send(&mut sender, Ok(InferenceEvent::Token(token.to_owned()))),
+            );
+
+            let event = match result {
+                Ok(stats) => Ok(InferenceEvent::Finished(stats)),
+                // The stream was dropped; there is no one left to tell.
+                Err(InferenceError::UserCallback(_)) => return session,
+                Err(InferenceError::Cancelled) if thread_cancellation_token.is_cancelled() => {
+                    return session
+                }
+                Err(err) => Err(err),
+            };
+            let _ = send(&mut sender, event);
+            session
+        });
+
+        Self {
+            receiver,
+            cancellation_token,
+            thread: Some(thread),
+        }
+    }
+
+    /// Waits for the inference thread to exit, and returns the session it used.
+    ///
+    /// Call this once the stream has produced [InferenceEvent::Finished] or an
+    /// error. If it has not, inference is stopped as if the stream were dropped,
+    /// and the session holds whatever was processed up to that point.
+    ///
+    /// This blocks the current thread until inference has stopped.
+    pub fn into_session(mut self) -> InferenceSession {
+        self.cancellation_token.cancel();
+        // Wake the inference thread if it is waiting for room in the buffer.
+        self.receiver.close();
+        let thread = self.thread.take().expect("the thread is only taken here");
+        match thread.join() {
+            Ok(session) => session,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+impl Drop for InferenceStream {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
+impl Stream for InferenceStream {
+    type Item = Result<InferenceEvent, InferenceError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Sends an event to the stream, blocking the inference thread until there is
+/// room in the buffer.
+fn send(
+    sender: &mut Sender<Result<InferenceEvent, InferenceError>>,
+    event: Result<InferenceEvent, InferenceError>,
+) -> Result<(), SendError> {
+    block_on(sender.send(event))
+}
@@ -4,9 +4,9 @@
 
 use llm_base::{
     ggml,
-    model::{common, HyperparametersWriteError},
-    util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, Mmap, ModelParameters, OutputRequest, TokenId, Vocabulary,
+    model::{common, HyperparametersWriteError, LayerTensor},
+    FileType, HuggingFaceTensor, InferenceParameters, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, Metadata, Mmap, ModelParameters, OutputRequest, TokenId, Vocabulary,
 };
 
 /// The BLOOM model. Ref: [Introducing BLOOM](https://bigscience.huggingface.co/blog/bloom)
@@ -37,6 +37,7 @@ unsafe impl Sync for Bloom {}
 
 impl KnownModel for Bloom {
     type Hyperparameters = Hyperparameters;
+    const ARCHITECTURE: &'static str = "bloom";
 
     fn new<E: std::error::Error>(
         hyperparameters: Self::Hyperparameters,
@@ -107,6 +108,52 @@ impl KnownModel for Bloom {
         })
     }
 
+    fn hugging_face_hyperparameters(config: &Metadata) -> Result<Hyperparameters, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_embd: config.get_usize("hidden_size")?,
+            // BLOOM's feed-forward layers are always four times the embedding size.
+            n_mult: 1,
+            n_head: config.get_usize("n_head")?,
+            n_layer: config.get_usize("n_layer")?,
+            file_type: config
+                .get_str("torch_dtype")
+                .map_or(FileType::F32, FileType::from_torch_dtype),
+        })
+    }
+
+    fn hugging_face_tensor(hyperparameters: &Hyperparameters, name: &str) -> HuggingFaceTensor {
+        let (layer_name, suffix) = name.rsplit_once('.').unwrap_or((name, "weight"));
+        let layer_name = match layer_name.strip_prefix("layers.") {
+            Some(layer_name) => layer_name,
+            None => {
+                return HuggingFaceTensor::new(match layer_name {
+                    // BLOOM ties its output weights to its token embeddings.
+                    "tok_embeddings" | "output" => "transformer.word_embeddings.weight".to_owned(),
+                    "norm" => format!("transformer.word_embeddings_layernorm.{suffix}"),
+                    "output_norm" => format!("transformer.ln_f.{suffix}"),
+                    _ => name.to_owned(),
+                });
+            }
+        };
+        let (layer, tensor) = layer_name.split_once('.').unwrap_or((layer_name, ""));
+        let prefix = format!("transformer.h.{layer}");
+        match tensor {
+            "attention.query_key_value" => HuggingFaceTensor::deinterleaved(
+                format!("{prefix}.self_attention.query_key_value.{suffix}"),
+                hyperparameters.n_head,
+            ),
+            _ => HuggingFaceTensor::new(match tensor {
+                "attention_norm" => format!("{prefix}.input_layernorm.{suffix}"),
+                "attention.wo" => format!("{prefix}.self_attention.dense.{suffix}"),
+                "ffn_norm" => format!("{prefix}.post_attention_layernorm.{suffix}"),
+                "feed_forward.w1" => format!("{prefix}.mlp.dense_h_to_4h.{suffix}"),
+                "feed_forward.w2" => format!("{prefix}.mlp.dense_4h_to_h.{suffix}"),
+                _ => name.to_owned(),
+            }),
+        }
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
@@ -150,6 +197,7 @@ impl KnownModel for Bloom {
         }
 
         let mut gf = ggml::ComputationGraph::new(n_threads);
+        let mut intermediate_tensors = common::IntermediateTensors::new(output_request);
 
         for il in 0..n_layer {
             let input_self_attention = input_layer.share();
@@ -268,6 +316,13 @@ impl KnownModel for Bloom {
 
                 // KQ = soft_max(KQ_masked)
                 let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
+                intermediate_tensors.capture(
+                    &ctx0,
+                    &mut gf,
+                    il,
+                    LayerTensor::AttentionProbabilities,
+                    &k_q_soft_max,
+                );
 
                 let memv_elsize = session.memory_v.element_size();
 
@@ -310,6 +365,13 @@ impl KnownModel for Bloom {
                 // projection
                 current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].wo_b, &current), &current);
+                intermediate_tensors.capture(
+                    &ctx0,
+                    &mut gf,
+                    il,
+                    LayerTensor::AttentionOutput,
+                    &current,
+                );
             }
 
             let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
@@ -339,6 +401,13 @@ impl KnownModel for Bloom {
                 // SILU activation
 
                 current = ctx0.op_gelu(&current);
+                intermediate_tensors.capture(
+                    &ctx0,
+                    &mut gf,
+                    il,
+                    LayerTensor::MlpActivations,
+                    &current,
+                );
 
                 current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
 
@@ -349,6 +418,7 @@ impl KnownModel for Bloom {
 
             // input for next layer
             input_layer = current;
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::Residual, &input_layer);
         }
 
         // norm
@@ -367,6 +437,9 @@ impl KnownModel for Bloom {
             );
         }
 
+        // used at the end to optionally extract the embeddings
+        let embeddings_tensor = input_layer.share();
+
         // lm_head
         {
             input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
@@ -379,7 +452,8 @@ impl KnownModel for Bloom {
         // finish evaluation
         common::read_last_token(session, &input_layer, n_vocab, n);
         common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
+        intermediate_tensors.extract(output_request);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -393,15 +467,11 @@ impl KnownModel for Bloom {
     }
 
     fn bot_token_id(&self) -> Option<TokenId> {
-        self.vocabulary.token_to_id.get("<s>".as_bytes()).copied()
+        self.vocabulary.special_tokens.bos
     }
 
     fn eot_token_id(&self) -> TokenId {
-        self.vocabulary
-            .token_to_id
-            .get("</s>".as_bytes())
-            .copied()
-            .unwrap()
+        self.vocabulary.special_tokens.eos.unwrap()
     }
 
     fn inference_parameters(&self) -> &InferenceParameters {
@@ -426,35 +496,50 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 impl llm_base::Hyperparameters for Hyperparameters {
-    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, llm_base::LoadError> {
-        // NOTE: Field order matters! Data is laid out in the file exactly
-        // in this order.
+    const GGML_KEYS: &'static [&'static str] = &[
+        "n_vocab",
+        "n_embd",
+        "n_mult",
+        "n_head",
+        "n_layer",
+        "file_type",
+    ];
+
+    fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError> {
         Ok(Hyperparameters {
-            n_vocab: util::read_i32(reader)?.try_into()?,
-            n_embd: util::read_i32(reader)?.try_into()?,
-            n_mult: util::read_i32(reader)?.try_into()?,
-            n_head: util::read_i32(reader)?.try_into()?,
-            n_layer: util::read_i32(reader)?.try_into()?,
+            n_vocab: metadata.get_usize("n_vocab")?,
+            n_embd: metadata.get_usize("n_embd")?,
+            n_mult: metadata.get_usize("n_mult")?,
+            n_head: metadata.get_usize("n_head")?,
+            n_layer: metadata.get_usize("n_layer")?,
             file_type: {
-                let ftype = util::read_i32(reader)?;
+                let ftype = metadata.get_i32("file_type")?;
                 FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
             },
         })
     }
 
-    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
-        util::write_i32(writer, self.n_vocab.try_into()?)?;
-        util::write_i32(writer, self.n_embd.try_into()?)?;
-        util::write_i32(writer, self.n_mult.try_into()?)?;
-        util::write_i32(writer, self.n_head.try_into()?)?;
-        util::write_i32(writer, self.n_layer.try_into()?)?;
-        util::write_i32(writer, self.file_type.into())?;
+    fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        metadata.insert("n_vocab", i32::try_from(self.n_vocab)?);
+        metadata.insert("n_embd", i32::try_from(self.n_embd)?);
+        metadata.insert("n_mult", i32::try_from(self.n_mult)?);
+        metadata.insert("n_head", i32::try_from(self.n_head)?);
+        metadata.insert("n_layer", i32::try_from(self.n_layer)?);
+        metadata.insert("file_type", i32::from(self.file_type));
         Ok(())
     }
 
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
+
+    fn n_layer(&self) -> usize {
+        self.n_layer
+    }
+
+    fn n_embd(&self) -> usize {
+        self.n_embd
+    }
 }
 
 struct Layer {
@@ -473,3 +558,37 @@ struct Layer {
     pub w2: ggml::Tensor,
     pub w2_b: ggml::Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_map_hugging_face_tensors() {
+        let hyperparameters = Hyperparameters {
+            n_head: 16,
+            ..Default::default()
+        };
+        let tensor = |name| Bloom::hugging_face_tensor(&hyperparameters, name);
+
+        assert_eq!(
+            tensor("output.weight"),
+            HuggingFaceTensor::new("transformer.word_embeddings.weight")
+        );
+        assert_eq!(
+            tensor("norm.bias"),
+            HuggingFaceTensor::new("transformer.word_embeddings_layernorm.bias")
+        );
+        assert_eq!(
+            tensor("layers.5.attention.query_key_value.bias"),
+            HuggingFaceTensor::deinterleaved(
+                "transformer.h.5.self_attention.query_key_value.bias",
+                16
+            )
+        );
+        assert_eq!(
+            tensor("layers.23.feed_forward.w2.weight"),
+            HuggingFaceTensor::new("transformer.h.23.mlp.dense_4h_to_h.weight")
+        );
+    }
+}
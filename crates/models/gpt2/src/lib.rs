@@ -4,9 +4,10 @@
 use ggml::Tensor;
 use llm_base::{
     ggml,
-    model::{common, HyperparametersWriteError},
-    util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, ModelParameters, OutputRequest, TokenId, Vocabulary,
+    model::{common, HyperparametersWriteError, LayerTensor},
+    FileType, HuggingFaceTensor, InferenceParameters, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, Metadata, ModelParameters, OutputRequest, SpecialTokens, TokenId,
+    TokenizerKind, Vocabulary,
 };
 
 /// The GPT-2 model. Ref: [The Illustrated GPT-2](https://jalammar.github.io/illustrated-gpt2/)
@@ -32,6 +33,7 @@ unsafe impl Sync for Gpt2 {}
 
 impl KnownModel for Gpt2 {
     type Hyperparameters = Hyperparameters;
+    const ARCHITECTURE: &'static str = "gpt2";
 
     fn new<E: std::error::Error>(
         hyperparameters: Self::Hyperparameters,
@@ -91,6 +93,59 @@ impl KnownModel for Gpt2 {
         })
     }
 
+    fn tokenizer_kind() -> TokenizerKind {
+        TokenizerKind::ByteLevelBpe
+    }
+
+    fn special_tokens(vocabulary: &Vocabulary) -> SpecialTokens {
+        // `<|endoftext|>` both separates and ends texts, but is not inserted
+        // before them.
+        let mut special_tokens = vocabulary.special_tokens.clone();
+        special_tokens.eos = special_tokens.find(vocabulary, "<|endoftext|>");
+        special_tokens
+    }
+
+    fn hugging_face_hyperparameters(config: &Metadata) -> Result<Hyperparameters, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_ctx: config.get_usize("n_positions")?,
+            n_embd: config.get_usize("n_embd")?,
+            n_head: config.get_usize("n_head")?,
+            n_layer: config.get_usize("n_layer")?,
+            file_type: config
+                .get_str("torch_dtype")
+                .map_or(FileType::F32, FileType::from_torch_dtype),
+        })
+    }
+
+    fn hugging_face_tensor(_hyperparameters: &Hyperparameters, name: &str) -> HuggingFaceTensor {
+        // GPT-2 ties its output weights to its token embeddings.
+        let name = match name {
+            "model/lm_head" => "model/wte",
+            _ => name,
+        };
+        let path = name.strip_prefix("model/").unwrap_or(name);
+        let (path, suffix) = match path.rsplit_once('/') {
+            Some((path, "b")) => (path, "bias"),
+            Some((path, _)) => (path, "weight"),
+            None => (path, "weight"),
+        };
+        // The layers are numbered as `h.{i}` rather than `h{i}`.
+        let path = path.replace('/', ".");
+        let path = match path.strip_prefix('h') {
+            Some(layer) if layer.starts_with(|c: char| c.is_ascii_digit()) => format!("h.{layer}"),
+            _ => path,
+        };
+
+        let source = format!("transformer.{path}.{suffix}");
+        // The weights of the `Conv1D` layers are stored as input by output.
+        if name.ends_with("/w") {
+            HuggingFaceTensor::transposed(source)
+        } else {
+            HuggingFaceTensor::new(source)
+        }
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
@@ -144,6 +199,7 @@ impl KnownModel for Gpt2 {
         let memory_v_size = memory_v.element_size();
 
         let mut gf = ggml::ComputationGraph::new(n_threads);
+        let mut intermediate_tensors = common::IntermediateTensors::new(output_request);
 
         for il in 0..n_layer {
             // norm
@@ -219,6 +275,13 @@ impl KnownModel for Gpt2 {
 
             let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled, n_past);
             let kq_softmax = ctx0.op_soft_max(&kq_masked);
+            intermediate_tensors.capture(
+                &ctx0,
+                &mut gf,
+                il,
+                LayerTensor::AttentionProbabilities,
+                &kq_softmax,
+            );
 
             let v_trans = ctx0.op_cpy(
                 &ctx0.op_permute(
@@ -252,6 +315,14 @@ impl KnownModel for Gpt2 {
                 &current,
             );
 
+            intermediate_tensors.capture(
+                &ctx0,
+                &mut gf,
+                il,
+                LayerTensor::AttentionOutput,
+                &current,
+            );
+
             // add input
             current = ctx0.op_add(&current, &input_layer);
 
@@ -274,6 +345,7 @@ impl KnownModel for Gpt2 {
 
             // feed-forward activation
             current = ctx0.op_gelu(&current);
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::MlpActivations, &current);
 
             // feed-forward projection
             current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
@@ -284,6 +356,7 @@ impl KnownModel for Gpt2 {
 
             // input for next layer
             input_layer = ctx0.op_add(&current, &ff_in);
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::Residual, &input_layer);
         }
 
         // normalization
@@ -293,6 +366,9 @@ impl KnownModel for Gpt2 {
             &ctx0.op_repeat(&self.ln_f_b, &input_layer),
         );
 
+        // used at the end to optionally extract the embeddings
+        let embeddings_tensor = input_layer.share();
+
         input_layer = ctx0.op_mul_mat(&self.lm_head, &input_layer);
 
         // run the computation
@@ -302,7 +378,8 @@ impl KnownModel for Gpt2 {
         // finish evaluation
         common::read_last_token(session, &input_layer, n_vocab, n);
         common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
+        intermediate_tensors.extract(output_request);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -319,11 +396,7 @@ impl KnownModel for Gpt2 {
     }
 
     fn eot_token_id(&self) -> TokenId {
-        self.vocabulary
-            .token_to_id
-            .get("<|endoftext|>".as_bytes())
-            .copied()
-            .unwrap()
+        self.vocabulary.special_tokens.eos.unwrap()
     }
 
     fn inference_parameters(&self) -> &InferenceParameters {
@@ -348,48 +421,56 @@ pub struct Hyperparameters {
     file_type: FileType,
 }
 impl llm_base::Hyperparameters for Hyperparameters {
-    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
-        let hyperparameters = Hyperparameters {
-            n_vocab: util::read_i32(reader)?.try_into()?,
-            n_ctx: util::read_i32(reader)?.try_into()?,
-            n_embd: util::read_i32(reader)?.try_into()?,
-            n_head: util::read_i32(reader)?.try_into()?,
-            n_layer: util::read_i32(reader)?.try_into()?,
+    // The vocabulary size is repeated before the vocabulary.
+    const GGML_KEYS: &'static [&'static str] = &[
+        "n_vocab",
+        "n_ctx",
+        "n_embd",
+        "n_head",
+        "n_layer",
+        "file_type",
+        "n_vocab",
+    ];
+
+    fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.get_usize("n_vocab")?,
+            n_ctx: metadata.get_usize("n_ctx")?,
+            n_embd: metadata.get_usize("n_embd")?,
+            n_head: metadata.get_usize("n_head")?,
+            n_layer: metadata.get_usize("n_layer")?,
             file_type: {
-                let ftype = util::read_i32(reader)?;
+                let ftype = metadata.get_i32("file_type")?;
                 FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
             },
-        };
-
-        let n_vocab = util::read_i32(reader)? as usize;
-        if hyperparameters.n_vocab != n_vocab {
-            return Err(LoadError::InvariantBroken {
-                path: None,
-                invariant: format!(
-                    "GPT2 model expected n_vocab {} found {}",
-                    hyperparameters.n_vocab, n_vocab
-                ),
-            });
-        }
-
-        Ok(hyperparameters)
+        })
     }
 
-    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
-        util::write_i32(writer, self.n_vocab.try_into()?)?;
-        util::write_i32(writer, self.n_ctx.try_into()?)?;
-        util::write_i32(writer, self.n_embd.try_into()?)?;
-        util::write_i32(writer, self.n_head.try_into()?)?;
-        util::write_i32(writer, self.n_layer.try_into()?)?;
-        util::write_i32(writer, self.file_type.into())?;
-        util::write_i32(writer, self.n_vocab.try_into()?)?;
-
+    fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        metadata.insert("n_vocab", i32::try_from(self.n_vocab)?);
+        metadata.insert("n_ctx", i32::try_from(self.n_ctx)?);
+        metadata.insert("n_embd", i32::try_from(self.n_embd)?);
+        metadata.insert("n_head", i32::try_from(self.n_head)?);
+        metadata.insert("n_layer", i32::try_from(self.n_layer)?);
+        metadata.insert("file_type", i32::from(self.file_type));
         Ok(())
     }
 
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
+
+    fn n_layer(&self) -> usize {
+        self.n_layer
+    }
+
+    fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    fn n_context_tokens(&self) -> Option<usize> {
+        Some(self.n_ctx)
+    }
 }
 
 struct Layer {
@@ -414,3 +495,31 @@ struct Layer {
     c_mlp_proj_w: Tensor,
     c_mlp_proj_b: Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_map_hugging_face_tensors() {
+        let hyperparameters = Hyperparameters::default();
+        let tensor = |name| Gpt2::hugging_face_tensor(&hyperparameters, name);
+
+        assert_eq!(
+            tensor("model/lm_head"),
+            HuggingFaceTensor::new("transformer.wte.weight")
+        );
+        assert_eq!(
+            tensor("model/ln_f/g"),
+            HuggingFaceTensor::new("transformer.ln_f.weight")
+        );
+        assert_eq!(
+            tensor("model/h11/attn/c_attn/w"),
+            HuggingFaceTensor::transposed("transformer.h.11.attn.c_attn.weight")
+        );
+        assert_eq!(
+            tensor("model/h0/mlp/c_proj/b"),
+            HuggingFaceTensor::new("transformer.h.0.mlp.c_proj.bias")
+        );
+    }
+}
@@ -6,9 +6,10 @@ use std::error::Error;
 use ggml::Tensor;
 use llm_base::{
     ggml,
-    model::{common, HyperparametersWriteError},
-    util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, Mmap, ModelParameters, OutputRequest, TensorLoader, TokenId, Vocabulary,
+    model::{common, HyperparametersWriteError, LayerTensor},
+    FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
+    Metadata, Mmap, ModelParameters, OutputRequest, SpecialTokens, TensorLoader, TokenId,
+    TokenizerKind, Vocabulary,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -47,6 +48,7 @@ unsafe impl Sync for GptJ {}
 
 impl KnownModel for GptJ {
     type Hyperparameters = Hyperparameters;
+    const ARCHITECTURE: &'static str = "gptj";
 
     fn new<E: Error>(
         hyperparameters: Self::Hyperparameters,
@@ -108,6 +110,33 @@ impl KnownModel for GptJ {
         })
     }
 
+    fn tokenizer_kind() -> TokenizerKind {
+        TokenizerKind::ByteLevelBpe
+    }
+
+    fn special_tokens(vocabulary: &Vocabulary) -> SpecialTokens {
+        // `<|endoftext|>` both separates and ends texts, but is not inserted
+        // before them.
+        let mut special_tokens = vocabulary.special_tokens.clone();
+        special_tokens.eos = special_tokens.find(vocabulary, "<|endoftext|>");
+        special_tokens
+    }
+
+    // The tensors are named after those of `transformers`.
+    fn hugging_face_hyperparameters(config: &Metadata) -> Result<Hyperparameters, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_ctx: config.get_usize("n_positions")?,
+            n_embd: config.get_usize("n_embd")?,
+            n_head: config.get_usize("n_head")?,
+            n_layer: config.get_usize("n_layer")?,
+            n_rot: config.get_usize("rotary_dim")?,
+            file_type: config
+                .get_str("torch_dtype")
+                .map_or(FileType::F32, FileType::from_torch_dtype),
+        })
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
@@ -152,6 +181,7 @@ impl KnownModel for GptJ {
         let memory_v_size = memory_v.element_size();
 
         let mut gf = ggml::ComputationGraph::new(n_threads);
+        let mut intermediate_tensors = common::IntermediateTensors::new(output_request);
 
         for il in 0..n_layer {
             // norm
@@ -232,6 +262,13 @@ impl KnownModel for GptJ {
 
             let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled, n_past);
             let kq_softmax = ctx0.op_soft_max(&kq_masked);
+            intermediate_tensors.capture(
+                &ctx0,
+                &mut gf,
+                il,
+                LayerTensor::AttentionProbabilities,
+                &kq_softmax,
+            );
 
             let big_v = ctx0.op_view_3d(
                 memory_v,
@@ -250,6 +287,13 @@ impl KnownModel for GptJ {
 
             // self-attention projection
             current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+            intermediate_tensors.capture(
+                &ctx0,
+                &mut gf,
+                il,
+                LayerTensor::AttentionOutput,
+                &current,
+            );
 
             // feed-forward
             let ff_in = current.share();
@@ -261,6 +305,7 @@ impl KnownModel for GptJ {
             );
 
             current = ctx0.op_gelu(&current);
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::MlpActivations, &current);
 
             // feed-forward projection
             current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
@@ -273,6 +318,7 @@ impl KnownModel for GptJ {
 
             // input for next layer
             input_layer = ctx0.op_add(&current, &input_layer);
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::Residual, &input_layer);
         }
 
         // norm
@@ -282,6 +328,9 @@ impl KnownModel for GptJ {
             &ctx0.op_repeat(&self.ln_f_b, &input_layer),
         );
 
+        // used at the end to optionally extract the embeddings
+        let embeddings_tensor = input_layer.share();
+
         // lm_head
         input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
         input_layer = ctx0.op_add(&ctx0.op_repeat(&self.lmh_b, &input_layer), &input_layer);
@@ -293,7 +342,8 @@ impl KnownModel for GptJ {
         // finish evaluation
         common::read_last_token(session, &input_layer, n_vocab, n);
         common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
+        intermediate_tensors.extract(output_request);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -310,11 +360,7 @@ impl KnownModel for GptJ {
     }
 
     fn eot_token_id(&self) -> TokenId {
-        self.vocabulary
-            .token_to_id
-            .get("<|endoftext|>".as_bytes())
-            .copied()
-            .unwrap()
+        self.vocabulary.special_tokens.eos.unwrap()
     }
 
     fn inference_parameters(&self) -> &InferenceParameters {
@@ -341,48 +387,59 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 impl llm_base::Hyperparameters for Hyperparameters {
-    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
-        let hyperparameters = Hyperparameters {
-            n_vocab: util::read_i32(reader)?.try_into()?,
-            n_ctx: util::read_i32(reader)?.try_into()?,
-            n_embd: util::read_i32(reader)?.try_into()?,
-            n_head: util::read_i32(reader)?.try_into()?,
-            n_layer: util::read_i32(reader)?.try_into()?,
-            n_rot: util::read_i32(reader)?.try_into()?,
+    // The vocabulary size is repeated before the vocabulary.
+    const GGML_KEYS: &'static [&'static str] = &[
+        "n_vocab",
+        "n_ctx",
+        "n_embd",
+        "n_head",
+        "n_layer",
+        "n_rot",
+        "file_type",
+        "n_vocab",
+    ];
+
+    fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.get_usize("n_vocab")?,
+            n_ctx: metadata.get_usize("n_ctx")?,
+            n_embd: metadata.get_usize("n_embd")?,
+            n_head: metadata.get_usize("n_head")?,
+            n_layer: metadata.get_usize("n_layer")?,
+            n_rot: metadata.get_usize("n_rot")?,
             file_type: {
-                let ftype = util::read_i32(reader)?;
+                let ftype = metadata.get_i32("file_type")?;
                 FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
             },
-        };
-
-        let n_vocab = util::read_i32(reader)? as usize;
-        if hyperparameters.n_vocab != n_vocab {
-            return Err(LoadError::InvariantBroken {
-                path: None,
-                invariant: format!(
-                    "GPT2 model expected n_vocab {} found {}",
-                    hyperparameters.n_vocab, n_vocab
-                ),
-            });
-        }
-
-        Ok(hyperparameters)
+        })
     }
 
-    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
-        util::write_i32(writer, self.n_vocab.try_into()?)?;
-        util::write_i32(writer, self.n_ctx.try_into()?)?;
-        util::write_i32(writer, self.n_embd.try_into()?)?;
-        util::write_i32(writer, self.n_head.try_into()?)?;
-        util::write_i32(writer, self.n_layer.try_into()?)?;
-        util::write_i32(writer, self.n_rot.try_into()?)?;
-        util::write_i32(writer, self.file_type.into())?;
+    fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        metadata.insert("n_vocab", i32::try_from(self.n_vocab)?);
+        metadata.insert("n_ctx", i32::try_from(self.n_ctx)?);
+        metadata.insert("n_embd", i32::try_from(self.n_embd)?);
+        metadata.insert("n_head", i32::try_from(self.n_head)?);
+        metadata.insert("n_layer", i32::try_from(self.n_layer)?);
+        metadata.insert("n_rot", i32::try_from(self.n_rot)?);
+        metadata.insert("file_type", i32::from(self.file_type));
         Ok(())
     }
 
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
+
+    fn n_layer(&self) -> usize {
+        self.n_layer
+    }
+
+    fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    fn n_context_tokens(&self) -> Option<usize> {
+        Some(self.n_ctx)
+    }
 }
 
 struct Layer {
@@ -10,7 +10,6 @@ use rust_tokenizers::preprocessing::vocab::sentencepiece_proto::sentencepiece_mo
 use serde::Deserialize;
 use std::{
     borrow::BorrowMut,
-    collections::HashMap,
     fs::{read_to_string, File},
     io::{Read, Write},
     path::Path,
@@ -45,25 +44,14 @@ fn load_vocabulary(path: &Path) -> Vocabulary {
     f.read_to_end(&mut contents).unwrap();
 
     let proto = protobuf::parse_from_bytes::<ModelProto>(contents.as_slice()).unwrap();
-    let mut id_to_token = vec![];
-    let mut id_to_token_score = vec![];
-    let mut token_to_id = HashMap::new();
-    let mut max_token_length = 0;
+    let mut vocabulary = Vocabulary::default();
 
     // TODO: Does the original model use valid UTF-8 for its tokens? This seems a little suspect to me.
     for (idx, piece) in proto.get_pieces().iter().enumerate() {
         let word = piece.get_piece().as_bytes();
-        max_token_length = max_token_length.max(word.len());
-        id_to_token.push(word.to_owned());
-        token_to_id.insert(word.to_owned(), idx as i32);
-        id_to_token_score.push(piece.get_score());
-    }
-    Vocabulary {
-        id_to_token,
-        id_to_token_score,
-        token_to_id,
-        max_token_length,
+        vocabulary.push_token(idx as i32, word.to_owned(), piece.get_score());
     }
+    vocabulary
 }
 
 fn load_hyperparameters(path: &Path, file_type: FileType, vocab: &Vocabulary) -> Hyperparameters {
@@ -5,17 +5,15 @@ use std::error::Error;
 
 use llm_base::{
     ggml,
-    model::{common, HyperparametersWriteError},
-    util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, LoadProgress, Mmap, ModelParameters, OutputRequest, TensorLoader, TokenId,
-    Vocabulary,
+    model::{common, HyperparametersWriteError, LayerTensor},
+    FileType, HuggingFaceTensor, InferenceParameters, InferenceSession, InferenceSessionConfig,
+    KnownModel, LoadError, Metadata, Mmap, ModelParameters, OutputRequest, SpecialTokens,
+    SplitAxis, TensorLoader, TokenId, Vocabulary,
 };
 
 #[cfg(feature = "convert")]
 pub mod convert;
 
-mod old_loader;
-
 /// The LLaMA model. Ref: [Introducing LLaMA](https://ai.facebook.com/blog/large-language-model-llama-meta-ai/)
 ///
 /// # Safety
@@ -46,6 +44,7 @@ unsafe impl Sync for Llama {}
 
 impl KnownModel for Llama {
     type Hyperparameters = Hyperparameters;
+    const ARCHITECTURE: &'static str = "llama";
 
     fn new<E: Error>(
         hyperparameters: Self::Hyperparameters,
@@ -98,6 +97,95 @@ impl KnownModel for Llama {
         })
     }
 
+    fn special_tokens(vocabulary: &Vocabulary) -> SpecialTokens {
+        // GGML files store LLaMA's control tokens as empty strings, so unless an
+        // external tokenizer provides them, they are identified by the IDs its
+        // SentencePiece model gives them.
+        let mut special_tokens = vocabulary.special_tokens.clone();
+        special_tokens.unk = special_tokens
+            .unk
+            .or_else(|| Some(find_or_insert(&mut special_tokens, vocabulary, "<unk>", 0)));
+        special_tokens.bos = special_tokens
+            .bos
+            .or_else(|| Some(find_or_insert(&mut special_tokens, vocabulary, "<s>", 1)));
+        special_tokens.eos = special_tokens
+            .eos
+            .or_else(|| Some(find_or_insert(&mut special_tokens, vocabulary, "</s>", 2)));
+        special_tokens
+    }
+
+    fn tensor_split_axis(name: &str) -> Option<SplitAxis> {
+        // The original checkpoints are sharded for model parallelism: the
+        // embeddings and the projections back into the residual stream are split
+        // along their input, and the other weights along their output.
+        if name.starts_with("tok_embeddings")
+            || name.ends_with("attention.wo.weight")
+            || name.ends_with("feed_forward.w2.weight")
+        {
+            Some(SplitAxis::Columns)
+        } else if name.starts_with("layers.") || name.starts_with("output") {
+            Some(SplitAxis::Rows)
+        } else {
+            None
+        }
+    }
+
+    fn hugging_face_hyperparameters(config: &Metadata) -> Result<Hyperparameters, LoadError> {
+        let n_embd = config.get_usize("hidden_size")?;
+        let n_head = config.get_usize("num_attention_heads")?;
+        let n_ff = config.get_usize("intermediate_size")?;
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_embd,
+            n_mult: find_n_mult(n_ff, n_embd).ok_or_else(|| LoadError::InvariantBroken {
+                path: None,
+                invariant: format!("no n_mult gives an intermediate size of {n_ff}"),
+            })?,
+            n_head,
+            n_layer: config.get_usize("num_hidden_layers")?,
+            n_rot: n_embd / n_head,
+            file_type: config
+                .get_str("torch_dtype")
+                .map_or(FileType::F32, FileType::from_torch_dtype),
+        })
+    }
+
+    fn hugging_face_tensor(hyperparameters: &Hyperparameters, name: &str) -> HuggingFaceTensor {
+        let layer_name = match name.strip_prefix("layers.") {
+            Some(layer_name) => layer_name,
+            None => {
+                return HuggingFaceTensor::new(match name {
+                    "tok_embeddings.weight" => "model.embed_tokens.weight",
+                    "norm.weight" => "model.norm.weight",
+                    "output.weight" => "lm_head.weight",
+                    _ => name,
+                })
+            }
+        };
+        let (layer, tensor) = layer_name.split_once('.').unwrap_or((layer_name, ""));
+        let prefix = format!("model.layers.{layer}");
+        match tensor {
+            "attention.wq.weight" => HuggingFaceTensor::unpermuted(
+                format!("{prefix}.self_attn.q_proj.weight"),
+                hyperparameters.n_head,
+            ),
+            "attention.wk.weight" => HuggingFaceTensor::unpermuted(
+                format!("{prefix}.self_attn.k_proj.weight"),
+                hyperparameters.n_head,
+            ),
+            _ => HuggingFaceTensor::new(match tensor {
+                "attention.wv.weight" => format!("{prefix}.self_attn.v_proj.weight"),
+                "attention.wo.weight" => format!("{prefix}.self_attn.o_proj.weight"),
+                "attention_norm.weight" => format!("{prefix}.input_layernorm.weight"),
+                "ffn_norm.weight" => format!("{prefix}.post_attention_layernorm.weight"),
+                "feed_forward.w1.weight" => format!("{prefix}.mlp.gate_proj.weight"),
+                "feed_forward.w2.weight" => format!("{prefix}.mlp.down_proj.weight"),
+                "feed_forward.w3.weight" => format!("{prefix}.mlp.up_proj.weight"),
+                _ => name.to_owned(),
+            }),
+        }
+    }
+
     /// Starts a new `InferenceSession` for this model.
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
@@ -139,6 +227,7 @@ impl KnownModel for Llama {
         let mut input_layer = ctx0.op_get_rows(&self.tok_embeddings, &embd);
 
         let mut gf = ggml::ComputationGraph::new(n_threads);
+        let mut intermediate_tensors = common::IntermediateTensors::new(output_request);
 
         for il in 0..n_layer {
             let input_self_attention = input_layer.share();
@@ -243,6 +332,13 @@ impl KnownModel for Llama {
 
                 // KQ = soft_max(KQ_masked)
                 let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
+                intermediate_tensors.capture(
+                    &ctx0,
+                    &mut gf,
+                    il,
+                    LayerTensor::AttentionProbabilities,
+                    &k_q_soft_max,
+                );
 
                 // split cached V into n_head heads
                 let v = ctx0.op_view_3d(
@@ -265,6 +361,13 @@ impl KnownModel for Llama {
 
                 // projection (no bias)
                 current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
+                intermediate_tensors.capture(
+                    &ctx0,
+                    &mut gf,
+                    il,
+                    LayerTensor::AttentionOutput,
+                    &current,
+                );
             }
 
             ctx0.use_scratch(Some(&mut session.scratch[1]));
@@ -292,6 +395,13 @@ impl KnownModel for Llama {
                 current = ctx0.op_silu(&current);
 
                 current = ctx0.op_mul(&current, &tmp);
+                intermediate_tensors.capture(
+                    &ctx0,
+                    &mut gf,
+                    il,
+                    LayerTensor::MlpActivations,
+                    &current,
+                );
 
                 current = ctx0.op_mul_mat(&self.layers[il].w2, &current);
             }
@@ -300,12 +410,11 @@ impl KnownModel for Llama {
 
             // input for next layer
             input_layer = current;
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::Residual, &input_layer);
         }
 
         ctx0.use_scratch(Some(&mut session.scratch[0]));
 
-        // Used at the end to optionally extract the embeddings.
-
         // norm
         {
             input_layer = ctx0.op_rms_norm(&input_layer);
@@ -314,6 +423,9 @@ impl KnownModel for Llama {
             input_layer = ctx0.op_mul(&ctx0.op_repeat(&self.norm, &input_layer), &input_layer);
         }
 
+        // used at the end to optionally extract the embeddings
+        let embeddings_tensor = input_layer.share();
+
         // lm_head
         {
             input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
@@ -328,7 +440,8 @@ impl KnownModel for Llama {
         // finish evaluation
         common::read_last_token(session, &input_layer, n_vocab, n);
         common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
+        intermediate_tensors.extract(output_request);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -342,11 +455,11 @@ impl KnownModel for Llama {
     }
 
     fn bot_token_id(&self) -> Option<TokenId> {
-        None
+        self.vocabulary.special_tokens.bos
     }
 
     fn eot_token_id(&self) -> TokenId {
-        2
+        self.vocabulary.special_tokens.eos.unwrap_or(2)
     }
 
     fn inference_parameters(&self) -> &InferenceParameters {
@@ -397,35 +510,74 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 impl llm_base::Hyperparameters for Hyperparameters {
-    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+    const GGML_KEYS: &'static [&'static str] = &[
+        "n_vocab",
+        "n_embd",
+        "n_mult",
+        "n_head",
+        "n_layer",
+        "n_rot",
+        "file_type",
+    ];
+
+    fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError> {
         Ok(Hyperparameters {
-            n_vocab: util::read_i32(reader)?.try_into()?,
-            n_embd: util::read_i32(reader)?.try_into()?,
-            n_mult: util::read_i32(reader)?.try_into()?,
-            n_head: util::read_i32(reader)?.try_into()?,
-            n_layer: util::read_i32(reader)?.try_into()?,
-            n_rot: util::read_i32(reader)?.try_into()?,
+            n_vocab: metadata.get_usize("n_vocab")?,
+            n_embd: metadata.get_usize("n_embd")?,
+            n_mult: metadata.get_usize("n_mult")?,
+            n_head: metadata.get_usize("n_head")?,
+            n_layer: metadata.get_usize("n_layer")?,
+            n_rot: metadata.get_usize("n_rot")?,
             file_type: {
-                let ftype = util::read_i32(reader)?;
+                let ftype = metadata.get_i32("file_type")?;
                 FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
             },
         })
     }
 
-    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
-        util::write_i32(writer, self.n_vocab.try_into()?)?;
-        util::write_i32(writer, self.n_embd.try_into()?)?;
-        util::write_i32(writer, self.n_mult.try_into()?)?;
-        util::write_i32(writer, self.n_head.try_into()?)?;
-        util::write_i32(writer, self.n_layer.try_into()?)?;
-        util::write_i32(writer, self.n_rot.try_into()?)?;
-        util::write_i32(writer, self.file_type.into())?;
+    fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        metadata.insert("n_vocab", i32::try_from(self.n_vocab)?);
+        metadata.insert("n_embd", i32::try_from(self.n_embd)?);
+        metadata.insert("n_mult", i32::try_from(self.n_mult)?);
+        metadata.insert("n_head", i32::try_from(self.n_head)?);
+        metadata.insert("n_layer", i32::try_from(self.n_layer)?);
+        metadata.insert("n_rot", i32::try_from(self.n_rot)?);
+        metadata.insert("file_type", i32::from(self.file_type));
         Ok(())
     }
 
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
+
+    fn n_layer(&self) -> usize {
+        self.n_layer
+    }
+
+    fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+}
+
+/// Finds the special token `text` in `vocabulary`, or registers it as `id`.
+fn find_or_insert(
+    special_tokens: &mut SpecialTokens,
+    vocabulary: &Vocabulary,
+    text: &str,
+    id: TokenId,
+) -> TokenId {
+    special_tokens.find(vocabulary, text).unwrap_or_else(|| {
+        special_tokens.insert(text, id);
+        id
+    })
+}
+
+/// Finds the `n_mult` for which LLaMA's feed-forward layers have `n_ff` rows, as
+/// Hugging Face checkpoints store the intermediate size instead.
+fn find_n_mult(n_ff: usize, n_embd: usize) -> Option<usize> {
+    (2..=8192)
+        .rev()
+        .find(|n_mult| (8 * n_embd / 3 + n_mult - 1) / n_mult * n_mult == n_ff)
 }
 
 struct Layer {
@@ -466,4 +618,58 @@ mod tests {
             let _session = session;
         });
     }
+
+    #[test]
+    fn keeps_special_tokens_of_external_tokenizers() {
+        // A GGML vocabulary, with empty control tokens.
+        let mut vocabulary = Vocabulary::default();
+        for id in 0..3 {
+            vocabulary.push_token(id, vec![], 0.0);
+        }
+        let special_tokens = Llama::special_tokens(&vocabulary);
+        assert_eq!(
+            (special_tokens.unk, special_tokens.bos, special_tokens.eos),
+            (Some(0), Some(1), Some(2))
+        );
+
+        // A tokenizer that registered its own end-of-text token.
+        vocabulary.push_token(3, b"<|end|>".to_vec(), 0.0);
+        vocabulary.special_tokens.insert("<|end|>", 3);
+        vocabulary.special_tokens.eos = Some(3);
+        let special_tokens = Llama::special_tokens(&vocabulary);
+        assert_eq!(special_tokens.eos, Some(3));
+        assert_eq!(special_tokens.bos, Some(1));
+    }
+
+    #[test]
+    fn can_find_n_mult() {
+        // LLaMA 7B and 13B. Several values of `n_mult` give the same size.
+        for (n_ff, n_embd) in [(11008, 4096), (13824, 5120)] {
+            let n_mult = find_n_mult(n_ff, n_embd).unwrap();
+            assert_eq!((8 * n_embd / 3 + n_mult - 1) / n_mult * n_mult, n_ff);
+        }
+        assert_eq!(find_n_mult(1, 4096), None);
+    }
+
+    #[test]
+    fn can_map_hugging_face_tensors() {
+        let hyperparameters = Hyperparameters {
+            n_head: 32,
+            ..Default::default()
+        };
+        let tensor = |name| Llama::hugging_face_tensor(&hyperparameters, name);
+
+        assert_eq!(
+            tensor("output.weight"),
+            HuggingFaceTensor::new("lm_head.weight")
+        );
+        assert_eq!(
+            tensor("layers.3.attention.wk.weight"),
+            HuggingFaceTensor::unpermuted("model.layers.3.self_attn.k_proj.weight", 32)
+        );
+        assert_eq!(
+            tensor("layers.12.feed_forward.w2.weight"),
+            HuggingFaceTensor::new("model.layers.12.mlp.down_proj.weight")
+        );
+    }
 }
@@ -6,9 +6,10 @@ use std::error::Error;
 use ggml::Tensor;
 use llm_base::{
     ggml,
-    model::{common, HyperparametersWriteError},
-    util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, Mmap, ModelParameters, OutputRequest, TensorLoader, TokenId, Vocabulary,
+    model::{common, HyperparametersWriteError, LayerTensor},
+    FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
+    Metadata, MetadataError, MetadataValue, Mmap, ModelParameters, OutputRequest, SpecialTokens,
+    TensorLoader, TokenId, TokenizerKind, Vocabulary,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -46,6 +47,7 @@ unsafe impl Sync for NeoX {}
 
 impl KnownModel for NeoX {
     type Hyperparameters = Hyperparameters;
+    const ARCHITECTURE: &'static str = "gpt_neox";
 
     fn new<E: Error>(
         hyperparameters: Self::Hyperparameters,
@@ -120,6 +122,52 @@ impl KnownModel for NeoX {
         })
     }
 
+    fn tokenizer_kind() -> TokenizerKind {
+        TokenizerKind::ByteLevelBpe
+    }
+
+    fn special_tokens(vocabulary: &Vocabulary) -> SpecialTokens {
+        // `<|endoftext|>` both separates and ends texts, but is not inserted
+        // before them.
+        let mut special_tokens = vocabulary.special_tokens.clone();
+        special_tokens.eos = special_tokens.find(vocabulary, "<|endoftext|>");
+        special_tokens.pad = special_tokens.find(vocabulary, "<|padding|>");
+        special_tokens
+    }
+
+    // The tensors are named after those of `transformers`.
+    fn hugging_face_hyperparameters(config: &Metadata) -> Result<Hyperparameters, LoadError> {
+        let n_embd = config.get_usize("hidden_size")?;
+        let n_head = config.get_usize("num_attention_heads")?;
+        // `transformers` rotates a quarter of each head by default, and a whole
+        // fraction may be written as an integer.
+        let rotary_pct = match config.get("rotary_pct") {
+            None => 0.25,
+            Some(MetadataValue::F32(value)) => *value,
+            Some(MetadataValue::I32(value)) => *value as f32,
+            Some(MetadataValue::U32(value)) => *value as f32,
+            Some(other) => {
+                return Err(MetadataError::WrongType {
+                    key: "rotary_pct".to_owned(),
+                    expected: ggml::format::MetadataValueType::F32,
+                    actual: other.value_type(),
+                }
+                .into())
+            }
+        };
+        Ok(Hyperparameters {
+            n_vocab: config.get_usize("vocab_size")?,
+            n_ctx: config.get_usize("max_position_embeddings")?,
+            n_embd,
+            n_head,
+            n_layer: config.get_usize("num_hidden_layers")?,
+            n_rot: (rotary_pct * (n_embd / n_head) as f32) as usize,
+            file_type: config
+                .get_str("torch_dtype")
+                .map_or(FileType::F32, FileType::from_torch_dtype),
+        })
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
@@ -164,6 +212,7 @@ impl KnownModel for NeoX {
         let memory_v_size = memory_v.element_size();
 
         let mut gf = ggml::ComputationGraph::new(n_threads);
+        let mut intermediate_tensors = common::IntermediateTensors::new(output_request);
 
         for il in 0..n_layer {
             // self-attention
@@ -249,6 +298,13 @@ impl KnownModel for NeoX {
 
             let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled, n_past);
             let kq_softmax = ctx0.op_soft_max(&kq_masked);
+            intermediate_tensors.capture(
+                &ctx0,
+                &mut gf,
+                il,
+                LayerTensor::AttentionProbabilities,
+                &kq_softmax,
+            );
 
             let big_v = ctx0.op_view_3d(
                 memory_v,
@@ -272,6 +328,14 @@ impl KnownModel for NeoX {
                 &current,
             );
 
+            intermediate_tensors.capture(
+                &ctx0,
+                &mut gf,
+                il,
+                LayerTensor::AttentionOutput,
+                &current,
+            );
+
             // feed-forward
             let ff_in = current.share();
 
@@ -289,6 +353,7 @@ impl KnownModel for NeoX {
             );
 
             current = ctx0.op_gelu(&current);
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::MlpActivations, &current);
 
             // feed-forward projection
             current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
@@ -301,6 +366,7 @@ impl KnownModel for NeoX {
 
             // input for next layer
             input_layer = ctx0.op_add(&current, &input_layer);
+            intermediate_tensors.capture(&ctx0, &mut gf, il, LayerTensor::Residual, &input_layer);
         }
 
         input_layer = ctx0.op_norm(&input_layer);
@@ -309,6 +375,9 @@ impl KnownModel for NeoX {
             &ctx0.op_repeat(&self.ln_f_b, &input_layer),
         );
 
+        // used at the end to optionally extract the embeddings
+        let embeddings_tensor = input_layer.share();
+
         input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
 
         // run the computation
@@ -318,7 +387,8 @@ impl KnownModel for NeoX {
         // finish evaluation
         common::read_last_token(session, &input_layer, n_vocab, n);
         common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
+        intermediate_tensors.extract(output_request);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -335,11 +405,7 @@ impl KnownModel for NeoX {
     }
 
     fn eot_token_id(&self) -> TokenId {
-        self.vocabulary
-            .token_to_id
-            .get("<|endoftext|>".as_bytes())
-            .copied()
-            .unwrap()
+        self.vocabulary.special_tokens.eos.unwrap()
     }
 
     fn inference_parameters(&self) -> &InferenceParameters {
@@ -366,35 +432,57 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 impl llm_base::Hyperparameters for Hyperparameters {
-    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+    const GGML_KEYS: &'static [&'static str] = &[
+        "n_vocab",
+        "n_ctx",
+        "n_embd",
+        "n_head",
+        "n_layer",
+        "n_rot",
+        "file_type",
+    ];
+
+    fn read_metadata(metadata: &Metadata) -> Result<Self, LoadError> {
         Ok(Hyperparameters {
-            n_vocab: util::read_i32(reader)?.try_into()?,
-            n_ctx: util::read_i32(reader)?.try_into()?,
-            n_embd: util::read_i32(reader)?.try_into()?,
-            n_head: util::read_i32(reader)?.try_into()?,
-            n_layer: util::read_i32(reader)?.try_into()?,
-            n_rot: util::read_i32(reader)?.try_into()?,
+            n_vocab: metadata.get_usize("n_vocab")?,
+            n_ctx: metadata.get_usize("n_ctx")?,
+            n_embd: metadata.get_usize("n_embd")?,
+            n_head: metadata.get_usize("n_head")?,
+            n_layer: metadata.get_usize("n_layer")?,
+            n_rot: metadata.get_usize("n_rot")?,
             file_type: {
-                let ftype = util::read_i32(reader)?;
+                let ftype = metadata.get_i32("file_type")?;
                 FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))?
             },
         })
     }
 
-    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
-        util::write_i32(writer, self.n_vocab.try_into()?)?;
-        util::write_i32(writer, self.n_ctx.try_into()?)?;
-        util::write_i32(writer, self.n_embd.try_into()?)?;
-        util::write_i32(writer, self.n_head.try_into()?)?;
-        util::write_i32(writer, self.n_layer.try_into()?)?;
-        util::write_i32(writer, self.n_rot.try_into()?)?;
-        util::write_i32(writer, self.file_type.into())?;
+    fn write_metadata(&self, metadata: &mut Metadata) -> Result<(), HyperparametersWriteError> {
+        metadata.insert("n_vocab", i32::try_from(self.n_vocab)?);
+        metadata.insert("n_ctx", i32::try_from(self.n_ctx)?);
+        metadata.insert("n_embd", i32::try_from(self.n_embd)?);
+        metadata.insert("n_head", i32::try_from(self.n_head)?);
+        metadata.insert("n_layer", i32::try_from(self.n_layer)?);
+        metadata.insert("n_rot", i32::try_from(self.n_rot)?);
+        metadata.insert("file_type", i32::from(self.file_type));
         Ok(())
     }
 
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
+
+    fn n_layer(&self) -> usize {
+        self.n_layer
+    }
+
+    fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    fn n_context_tokens(&self) -> Option<usize> {
+        Some(self.n_ctx)
+    }
 }
 
 struct Layer {
@@ -449,6 +537,29 @@ mod tests {
     use super::*;
     use std::sync::Arc;
 
+    #[test]
+    fn can_read_rotary_pct() {
+        let mut config = Metadata::default();
+        for (key, value) in [
+            ("vocab_size", 50432),
+            ("max_position_embeddings", 2048),
+            ("hidden_size", 512),
+            ("num_attention_heads", 8),
+            ("num_hidden_layers", 6),
+        ] {
+            config.insert(key, value);
+        }
+        let n_rot = |config: &Metadata| NeoX::hugging_face_hyperparameters(config).unwrap().n_rot;
+
+        assert_eq!(n_rot(&config), 16);
+        config.insert("rotary_pct", 1);
+        assert_eq!(n_rot(&config), 64);
+        config.insert("rotary_pct", 0.5f32);
+        assert_eq!(n_rot(&config), 32);
+        config.insert("rotary_pct", "all");
+        assert!(NeoX::hugging_face_hyperparameters(&config).is_err());
+    }
+
     #[test]
     fn can_share_model_between_threads() {
         let model = Arc::new(NeoX::new_empty());